@@ -1,6 +1,6 @@
 use anyhow::{Result, ensure};
 use clap::Parser;
-use koharu_ml::comic_text_detector::ComicTextDetector;
+use koharu_ml::comic_text_detector::{ComicTextDetector, DetectOptions};
 use tracing_subscriber::fmt::format::FmtSpan;
 
 #[derive(Parser)]
@@ -13,6 +13,46 @@ struct Cli {
 
     #[arg(long, default_value_t = false)]
     cpu: bool,
+
+    /// Minimum box confidence (default 0.4)
+    #[arg(long)]
+    confidence_threshold: Option<f32>,
+
+    /// IoU above which overlapping boxes are merged (default 0.35)
+    #[arg(long)]
+    nms_threshold: Option<f32>,
+
+    /// Mask threshold out of 255 (default 60)
+    #[arg(long)]
+    binary_threshold: Option<u8>,
+
+    /// Mask dilation in pixels (default 3)
+    #[arg(long)]
+    dilation_radius: Option<u32>,
+
+    /// Radius of the mask holes to fill (default 10)
+    #[arg(long)]
+    hole_close_radius: Option<u32>,
+
+    /// Box padding in pixels (default 1)
+    #[arg(long)]
+    bbox_dilation: Option<f32>,
+
+    /// Model input size, a multiple of 32 (default 640 on CPU, 1024 otherwise)
+    #[arg(long)]
+    input_size: Option<u32>,
+
+    /// Detect the whole page at once, however long it is
+    #[arg(long, default_value_t = false)]
+    no_tiling: bool,
+
+    /// Share of a tile overlapping its neighbours (default 0.25)
+    #[arg(long)]
+    tile_overlap: Option<f32>,
+
+    /// Group boxes by speech bubble, bridging mask gaps of this many pixels
+    #[arg(long)]
+    group_bubbles: Option<u32>,
 }
 
 #[tokio::main]
@@ -26,7 +66,23 @@ async fn main() -> Result<()> {
     let model = ComicTextDetector::load(cli.cpu).await?;
     let image = image::open(&cli.input)?;
 
-    let (bboxes, mask) = model.inference(&image)?;
+    let defaults = DetectOptions::default();
+    let opts = DetectOptions {
+        confidence_threshold: cli
+            .confidence_threshold
+            .unwrap_or(defaults.confidence_threshold),
+        nms_threshold: cli.nms_threshold.unwrap_or(defaults.nms_threshold),
+        binary_threshold: cli.binary_threshold.unwrap_or(defaults.binary_threshold),
+        dilation_radius: cli.dilation_radius.unwrap_or(defaults.dilation_radius),
+        hole_close_radius: cli.hole_close_radius.unwrap_or(defaults.hole_close_radius),
+        bbox_dilation: cli.bbox_dilation.unwrap_or(defaults.bbox_dilation),
+        input_size: cli.input_size,
+        tile_aspect_ratio: defaults.tile_aspect_ratio.filter(|_| !cli.no_tiling),
+        tile_overlap: cli.tile_overlap.unwrap_or(defaults.tile_overlap),
+        group_bubbles: cli.group_bubbles,
+    };
+
+    let (bboxes, mask) = model.inference(&image, &opts)?;
 
     ensure!(!bboxes.is_empty(), "No text detected in the image.");
     ensure!(!mask.iter().all(|m| *m < 255), "No text mask generated.");
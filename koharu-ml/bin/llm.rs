@@ -1,7 +1,9 @@
+use std::io::Write;
+use std::path::PathBuf;
+
 use clap::Parser;
-use koharu_ml::{
-    llm::{GenerateOptions, Llm, ModelId},
-    set_default_locale,
+use koharu_ml::llm::{
+    CancellationToken, GenerateOptions, Llm, ModelId, PromptContext, PromptStyle,
 };
 use tracing_subscriber::fmt::format::FmtSpan;
 
@@ -26,6 +28,14 @@ struct Args {
     #[arg(long, default_value = "sakura-galtransl-7b-v3.7")]
     model: ModelId,
 
+    /// Local GGUF file to use instead of `--model`
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// System prompt for `--file` (defaults to a generic translation prompt)
+    #[arg(long)]
+    system_prompt: Option<String>,
+
     /// Max new tokens
     #[arg(long, default_value_t = 1000)]
     max_tokens: usize,
@@ -58,12 +68,16 @@ struct Args {
     #[arg(long, default_value_t = 64)]
     repeat_last_n: usize,
 
+    /// Always pick the most likely token, for reproducible output
+    #[arg(long, default_value_t = false)]
+    greedy: bool,
+
     #[arg(long, default_value_t = false)]
     cpu: bool,
 
-    /// override locale for translation models
-    #[arg(long, default_value = "zh-CN")]
-    locale: String,
+    /// target language for translation models, by name or tag like `zh-CN`
+    #[arg(long)]
+    locale: Option<String>,
 }
 
 #[tokio::main]
@@ -74,24 +88,51 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    set_default_locale(args.locale.clone());
-
-    let mut llm = Llm::load(args.model, args.cpu).await?;
+    let mut llm = match &args.file {
+        Some(path) => Llm::load_file(
+            path,
+            PromptStyle::Generic {
+                system: args.system_prompt.clone(),
+            },
+            args.cpu,
+        )?,
+        None => Llm::load(args.model, args.cpu).await?,
+    };
 
     let opts = GenerateOptions {
-        max_tokens: args.max_tokens,
+        max_tokens: Some(args.max_tokens),
         temperature: args.temperature,
         top_k: args.top_k,
         top_p: args.top_p,
-        seed: args.seed,
+        seed: Some(args.seed),
         split_prompt: args.split_prompt,
         repeat_penalty: args.repeat_penalty,
         repeat_last_n: args.repeat_last_n,
+        greedy: args.greedy,
     };
 
-    let out = llm.generate(&args.prompt, &opts)?;
+    // Ctrl-C stops the generation instead of the process
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        }
+    });
+
+    let context = PromptContext {
+        language: args.locale.as_deref(),
+        ..Default::default()
+    };
+    let mut stdout = std::io::stdout();
+    let out = llm.generate_stream(&args.prompt, &context, &opts, &cancel, |text| {
+        let _ = write!(stdout, "{text}");
+        let _ = stdout.flush();
+    })?;
 
-    println!("{}", out);
+    println!();
     println!(
         "** Out has {} lines",
         out.lines().filter(|l| !l.trim().is_empty()).count()
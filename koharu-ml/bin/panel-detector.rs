@@ -0,0 +1,71 @@
+use anyhow::{Result, ensure};
+use clap::Parser;
+use imageproc::point::Point;
+use koharu_ml::panel_detector::{self, PanelOptions};
+
+#[derive(Parser)]
+struct Cli {
+    #[arg(short, long, value_name = "FILE")]
+    input: String,
+
+    #[arg(short, long, value_name = "FILE")]
+    output: String,
+
+    /// Luma distance from the page margin still counted as gutter (default 40)
+    #[arg(long)]
+    gutter_tolerance: Option<u8>,
+
+    /// Border gaps to bridge in pixels (default 2)
+    #[arg(long)]
+    border_gap: Option<u8>,
+
+    /// Share of a row or column that has to be gutter to cut there (default 0.25)
+    #[arg(long)]
+    gutter_coverage: Option<f32>,
+
+    /// Smallest panel as a share of the page area (default 0.01)
+    #[arg(long)]
+    min_area: Option<f32>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let image = image::open(&cli.input)?;
+
+    let defaults = PanelOptions::default();
+    let opts = PanelOptions {
+        gutter_tolerance: cli.gutter_tolerance.unwrap_or(defaults.gutter_tolerance),
+        border_gap: cli.border_gap.unwrap_or(defaults.border_gap),
+        gutter_coverage: cli.gutter_coverage.unwrap_or(defaults.gutter_coverage),
+        min_area: cli.min_area.unwrap_or(defaults.min_area),
+        ..defaults
+    };
+
+    let gutters = panel_detector::gutters(&image, &opts);
+    let panels = panel_detector::panels(&gutters, &opts);
+
+    ensure!(!panels.is_empty(), "No panels detected in the image.");
+
+    // draw the outlines on the image
+    let mut image = image.to_rgba8();
+    for panel in &panels {
+        let polygon: Vec<Point<f32>> = panel
+            .polygon
+            .iter()
+            .map(|&[x, y]| Point::new(x as f32, y as f32))
+            .collect();
+        imageproc::drawing::draw_hollow_polygon_mut(
+            &mut image,
+            &polygon,
+            image::Rgba([255, 0, 0, 255]),
+        );
+    }
+    println!("{} panels", panels.len());
+
+    image.save(&cli.output)?;
+
+    gutters.save(format!("{}_gutters.png", cli.output))?;
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use candle_transformers::object_detection::Bbox;
+use image::{GrayImage, Luma};
+use imageproc::{
+    distance_transform::Norm,
+    morphology::dilate,
+    region_labelling::{Connectivity, connected_components},
+};
+
+/// Share of the text pixels of a box a bubble needs to get its own box when the box is split.
+const MIN_SPLIT_SHARE: f32 = 0.1;
+
+/// Regroup boxes into one per speech bubble, by the connectivity of the text `mask` once gaps of
+/// up to `gap` pixels are bridged. Boxes whose text connects are merged, and a box whose text
+/// falls apart is split into one box per bubble. Boxes without text pixels are kept as they are.
+pub(crate) fn group_bubbles(
+    bboxes: Vec<Bbox<usize>>,
+    mask: &GrayImage,
+    gap: u32,
+) -> Vec<Bbox<usize>> {
+    let bridged = dilate(mask, Norm::LInf, gap.min(u8::MAX as u32) as u8);
+    let labels = connected_components(&bridged, Connectivity::Eight, Luma([0u8]));
+    let (width, height) = mask.dimensions();
+
+    let mut kept = Vec::new();
+    let mut bubbles: Vec<(u32, Bbox<usize>)> = Vec::new();
+    for bbox in bboxes {
+        let x0 = (bbox.xmin.max(0.0) as u32).min(width);
+        let y0 = (bbox.ymin.max(0.0) as u32).min(height);
+        let x1 = (bbox.xmax.ceil().max(0.0) as u32).min(width);
+        let y1 = (bbox.ymax.ceil().max(0.0) as u32).min(height);
+
+        // pixel count and extent of the text of every bubble inside the box
+        let mut parts: HashMap<u32, (u32, [u32; 4])> = HashMap::new();
+        for y in y0..y1 {
+            for x in x0..x1 {
+                if mask.get_pixel(x, y).0[0] == 0 {
+                    continue;
+                }
+                let label = labels.get_pixel(x, y).0[0];
+                let (count, extent) = parts.entry(label).or_insert((0, [x, y, x, y]));
+                *count += 1;
+                *extent = [
+                    extent[0].min(x),
+                    extent[1].min(y),
+                    extent[2].max(x),
+                    extent[3].max(y),
+                ];
+            }
+        }
+        let total: u32 = parts.values().map(|(count, _)| count).sum();
+        let mut parts: Vec<_> = parts
+            .into_iter()
+            .filter(|(_, (count, _))| *count as f32 >= total as f32 * MIN_SPLIT_SHARE)
+            .collect();
+        parts.sort_by_key(|(label, _)| *label);
+
+        match parts.as_slice() {
+            [] => kept.push(bbox),
+            [(label, _)] => bubbles.push((*label, bbox)),
+            _ => {
+                for (label, (_, [xmin, ymin, xmax, ymax])) in parts {
+                    bubbles.push((
+                        label,
+                        Bbox {
+                            xmin: xmin as f32,
+                            ymin: ymin as f32,
+                            xmax: (xmax + 1) as f32,
+                            ymax: (ymax + 1) as f32,
+                            confidence: bbox.confidence,
+                            data: bbox.data,
+                        },
+                    ));
+                }
+            }
+        }
+    }
+
+    // one box per bubble, in the order the bubbles were first met
+    let mut merged: Vec<Bbox<usize>> = Vec::new();
+    let mut index: HashMap<u32, usize> = HashMap::new();
+    for (label, bbox) in bubbles {
+        let Some(&i) = index.get(&label) else {
+            index.insert(label, merged.len());
+            merged.push(bbox);
+            continue;
+        };
+        let bubble = &mut merged[i];
+        bubble.xmin = bubble.xmin.min(bbox.xmin);
+        bubble.ymin = bubble.ymin.min(bbox.ymin);
+        bubble.xmax = bubble.xmax.max(bbox.xmax);
+        bubble.ymax = bubble.ymax.max(bbox.ymax);
+        // the bubble takes the class of its most confident box
+        if bbox.confidence > bubble.confidence {
+            bubble.confidence = bbox.confidence;
+            bubble.data = bbox.data;
+        }
+    }
+    merged.extend(kept);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImage;
+
+    use super::*;
+
+    fn bbox(xmin: f32, ymin: f32, xmax: f32, ymax: f32, data: usize) -> Bbox<usize> {
+        Bbox {
+            xmin,
+            ymin,
+            xmax,
+            ymax,
+            confidence: 0.5 + data as f32 / 10.0,
+            data,
+        }
+    }
+
+    fn fill(mask: &mut GrayImage, x: u32, y: u32, width: u32, height: u32) {
+        let mut region = mask.sub_image(x, y, width, height);
+        for y in 0..height {
+            for x in 0..width {
+                region.put_pixel(x, y, Luma([255]));
+            }
+        }
+    }
+
+    #[test]
+    fn lines_of_a_bubble_are_merged() {
+        let mut mask = GrayImage::new(100, 100);
+        // two lines of one bubble, and a line of another bubble far below
+        fill(&mut mask, 10, 10, 40, 8);
+        fill(&mut mask, 10, 22, 40, 8);
+        fill(&mut mask, 10, 70, 40, 8);
+        let bboxes = vec![
+            bbox(10.0, 10.0, 50.0, 18.0, 0),
+            bbox(10.0, 22.0, 50.0, 30.0, 1),
+            bbox(10.0, 70.0, 50.0, 78.0, 0),
+            bbox(80.0, 80.0, 90.0, 90.0, 0),
+        ];
+
+        let grouped = group_bubbles(bboxes, &mask, 3);
+
+        let extents: Vec<_> = grouped
+            .iter()
+            .map(|b| (b.xmin, b.ymin, b.xmax, b.ymax, b.data))
+            .collect();
+        assert_eq!(
+            extents,
+            vec![
+                (10.0, 10.0, 50.0, 30.0, 1),
+                (10.0, 70.0, 50.0, 78.0, 0),
+                (80.0, 80.0, 90.0, 90.0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn boxes_over_several_bubbles_are_split() {
+        let mut mask = GrayImage::new(100, 100);
+        fill(&mut mask, 10, 10, 30, 10);
+        fill(&mut mask, 60, 50, 30, 10);
+
+        let grouped = group_bubbles(vec![bbox(0.0, 0.0, 100.0, 100.0, 0)], &mask, 3);
+
+        let extents: Vec<_> = grouped
+            .iter()
+            .map(|b| (b.xmin, b.ymin, b.xmax, b.ymax))
+            .collect();
+        assert_eq!(
+            extents,
+            vec![(10.0, 10.0, 40.0, 20.0), (60.0, 50.0, 90.0, 60.0)]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+mod bubbles;
 mod dbnet;
 mod unet;
 mod yolo_v5;
@@ -9,17 +10,62 @@ use candle_core::{DType, Device, IndexOp, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::object_detection::{Bbox, non_maximum_suppression};
 use image::{DynamicImage, GenericImageView, GrayImage};
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::{define_models, device};
+use crate::{
+    define_models, device,
+    tiling::{self, Tile},
+};
 
-const CONFIDENCE_THRESHOLD: f32 = 0.4;
-const NMS_THRESHOLD: f32 = 0.35;
 const DBNET_BINARIZE_K: f64 = 50.0;
-const BINARY_THRESHOLD: u8 = 60;
-const DILATION_RADIUS: u32 = 3;
-const HOLE_CLOSE_RADIUS: u32 = 10;
-const BBOX_DILATION: f32 = 1.0;
+
+/// Tuning of a detection. Missing fields take their default when deserialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DetectOptions {
+    /// Minimum objectness times class score of a kept box.
+    pub confidence_threshold: f32,
+    /// Boxes of a class overlapping a better one by more than this IoU are dropped.
+    pub nms_threshold: f32,
+    /// Mask probability, out of 255, above which a pixel counts as text.
+    pub binary_threshold: u8,
+    /// Pixels the text mask grows by after closing its holes.
+    pub dilation_radius: u32,
+    /// Gaps in the text mask up to about this radius are filled.
+    pub hole_close_radius: u32,
+    /// Pixels added to every side of a box.
+    pub bbox_dilation: f32,
+    /// Side of the square the page is resized into, a multiple of 32. When `None`, 640 on
+    /// CPU and 1024 on GPUs.
+    pub input_size: Option<u32>,
+    /// Pages whose long side is more than this many times their short side, like webtoon
+    /// strips, are detected in square tiles along their length. `None` never tiles.
+    pub tile_aspect_ratio: Option<f32>,
+    /// Share of a tile that overlaps each of its neighbours.
+    pub tile_overlap: f32,
+    /// Merge the boxes of a speech bubble into one, and split boxes covering several bubbles,
+    /// by which text of the mask connects once gaps of up to this many pixels are bridged.
+    /// `None` keeps the boxes of the detector.
+    pub group_bubbles: Option<u32>,
+}
+
+impl Default for DetectOptions {
+    fn default() -> Self {
+        Self {
+            confidence_threshold: 0.4,
+            nms_threshold: 0.35,
+            binary_threshold: 60,
+            dilation_radius: 3,
+            hole_close_radius: 10,
+            bbox_dilation: 1.0,
+            input_size: None,
+            tile_aspect_ratio: Some(2.0),
+            tile_overlap: 0.25,
+            group_bubbles: None,
+        }
+    }
+}
 
 define_models! {
     Yolov5 => ("mayocream/comic-text-detector", "yolo-v5.safetensors"),
@@ -65,16 +111,103 @@ impl ComicTextDetector {
     }
 
     #[instrument(level = "debug", skip_all)]
-    pub fn inference(&self, image: &DynamicImage) -> anyhow::Result<(Vec<Bbox<usize>>, GrayImage)> {
+    pub fn inference(
+        &self,
+        image: &DynamicImage,
+        opts: &DetectOptions,
+    ) -> anyhow::Result<(Vec<Bbox<usize>>, GrayImage)> {
+        let (width, height) = image.dimensions();
+        let (long, short) = (width.max(height), width.min(height));
+        let (bboxes, mask) = match opts.tile_aspect_ratio {
+            Some(ratio) if short > 0 && long as f32 > short as f32 * ratio => {
+                self.inference_tiled(image, short, opts)?
+            }
+            _ => self.inference_page(image, opts)?,
+        };
+        let bboxes = match opts.group_bubbles {
+            Some(gap) => bubbles::group_bubbles(bboxes, &mask, gap),
+            None => bboxes,
+        };
+        Ok((bboxes, mask))
+    }
+
+    /// Detect in overlapping `size` squares, merging their boxes with NMS and their masks by
+    /// keeping the strongest pixel.
+    #[instrument(level = "debug", skip(self, image, opts))]
+    fn inference_tiled(
+        &self,
+        image: &DynamicImage,
+        size: u32,
+        opts: &DetectOptions,
+    ) -> anyhow::Result<(Vec<Bbox<usize>>, GrayImage)> {
+        let (width, height) = image.dimensions();
+        let overlap = (size as f32 * opts.tile_overlap.clamp(0.0, 0.9)) as u32;
+        let mut mask = GrayImage::new(width, height);
+        let mut bboxes: Vec<Vec<Bbox<usize>>> = Vec::new();
+        for tile in tiling::tiles(width, height, size, overlap) {
+            let crop = image.crop_imm(tile.x.start, tile.y.start, tile.width(), tile.height());
+            let (tile_bboxes, tile_mask) = self.inference_page(&crop, opts)?;
+            for bbox in tile_bboxes {
+                if cut_by_tile(
+                    &bbox,
+                    &tile,
+                    (width, height),
+                    overlap,
+                    opts.bbox_dilation + 1.0,
+                ) {
+                    continue;
+                }
+                let class_index = bbox.data;
+                if bboxes.len() <= class_index {
+                    bboxes.resize_with(class_index + 1, Vec::new);
+                }
+                let (dx, dy) = (tile.x.start as f32, tile.y.start as f32);
+                bboxes[class_index].push(Bbox {
+                    xmin: bbox.xmin + dx,
+                    xmax: bbox.xmax + dx,
+                    ymin: bbox.ymin + dy,
+                    ymax: bbox.ymax + dy,
+                    ..bbox
+                });
+            }
+            for (x, y, pixel) in tile_mask.enumerate_pixels() {
+                let stitched = mask.get_pixel_mut(x + tile.x.start, y + tile.y.start);
+                stitched.0[0] = stitched.0[0].max(pixel.0[0]);
+            }
+        }
+
+        non_maximum_suppression(&mut bboxes, opts.nms_threshold);
+
+        Ok((bboxes.into_iter().flatten().collect(), mask))
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    fn inference_page(
+        &self,
+        image: &DynamicImage,
+        opts: &DetectOptions,
+    ) -> anyhow::Result<(Vec<Bbox<usize>>, GrayImage)> {
         let original_dimensions = image.dimensions();
-        let (image_tensor, resized_dimensions) = preprocess(image, &self.device)?;
+        let image_size = match opts.input_size {
+            Some(size) if size == 0 || size % 32 != 0 => {
+                bail!("input size must be a positive multiple of 32, got {size}")
+            }
+            Some(size) => size,
+            // The model was trained at 640x640; larger inputs collapse confidence on CPU.
+            None => match self.device {
+                Device::Cpu => 640,
+                _ => 1024,
+            },
+        };
+        let (image_tensor, resized_dimensions) = preprocess(image, image_size, &self.device)?;
         let (predictions, mask, shrink_threshold) = self.forward(&image_tensor)?;
-        let bboxes = postprocess_yolo(&predictions, original_dimensions, resized_dimensions)?;
+        let bboxes = postprocess_yolo(&predictions, original_dimensions, resized_dimensions, opts)?;
         let mask = postprocess_mask(
             &mask,
             &shrink_threshold,
             original_dimensions,
             resized_dimensions,
+            opts,
         )?;
 
         Ok((bboxes, mask))
@@ -98,14 +231,30 @@ impl ComicTextDetector {
     }
 }
 
+/// Whether `bbox`, in the coordinates of `tile`, touches an edge the tile shares with a
+/// neighbour while being small enough to lie whole in that neighbour, which then keeps it.
+fn cut_by_tile(
+    bbox: &Bbox<usize>,
+    tile: &Tile,
+    (width, height): (u32, u32),
+    overlap: u32,
+    margin: f32,
+) -> bool {
+    let fits = |len: f32| len < overlap as f32;
+    let cut_x = (tile.x.start > 0 && bbox.xmin <= margin)
+        || (tile.x.end < width && bbox.xmax >= tile.width() as f32 - margin);
+    let cut_y = (tile.y.start > 0 && bbox.ymin <= margin)
+        || (tile.y.end < height && bbox.ymax >= tile.height() as f32 - margin);
+    (cut_x && fits(bbox.xmax - bbox.xmin)) || (cut_y && fits(bbox.ymax - bbox.ymin))
+}
+
 #[instrument(level = "debug", skip_all)]
-fn preprocess(image: &DynamicImage, device: &Device) -> anyhow::Result<(Tensor, (u32, u32))> {
+fn preprocess(
+    image: &DynamicImage,
+    image_size: u32,
+    device: &Device,
+) -> anyhow::Result<(Tensor, (u32, u32))> {
     let (orig_w, orig_h) = image.dimensions();
-    // The model was trained at 640x640; larger inputs collapse confidence on CPU.
-    let image_size = match device {
-        Device::Cpu => 640,
-        _ => 1024,
-    };
     let (width, height) = if orig_w >= orig_h {
         (image_size, image_size * orig_h / orig_w)
     } else {
@@ -127,11 +276,12 @@ fn preprocess(image: &DynamicImage, device: &Device) -> anyhow::Result<(Tensor,
     Ok((tensor, (width, height)))
 }
 
-#[instrument(level = "debug", skip(predictions))]
+#[instrument(level = "debug", skip(predictions, opts))]
 fn postprocess_yolo(
     predictions: &Tensor,
     original_dimensions: (u32, u32),
     resized_dimensions: (u32, u32),
+    opts: &DetectOptions,
 ) -> anyhow::Result<Vec<Bbox<usize>>> {
     // predictions shape: (1, num_boxes, num_outputs)
     // this removes the batch dimension
@@ -160,14 +310,18 @@ fn postprocess_yolo(
                 .unwrap_or((0, 0.0));
             (cls_idx, pred[4] * cls_score)
         };
-        if confidence < CONFIDENCE_THRESHOLD {
+        if confidence < opts.confidence_threshold {
             continue;
         }
 
-        let xmin = ((pred[0] - pred[2] / 2.) * w_ratio - BBOX_DILATION).clamp(0., orig_w as f32);
-        let xmax = ((pred[0] + pred[2] / 2.) * w_ratio + BBOX_DILATION).clamp(0., orig_w as f32);
-        let ymin = ((pred[1] - pred[3] / 2.) * h_ratio - BBOX_DILATION).clamp(0., orig_h as f32);
-        let ymax = ((pred[1] + pred[3] / 2.) * h_ratio + BBOX_DILATION).clamp(0., orig_h as f32);
+        let xmin =
+            ((pred[0] - pred[2] / 2.) * w_ratio - opts.bbox_dilation).clamp(0., orig_w as f32);
+        let xmax =
+            ((pred[0] + pred[2] / 2.) * w_ratio + opts.bbox_dilation).clamp(0., orig_w as f32);
+        let ymin =
+            ((pred[1] - pred[3] / 2.) * h_ratio - opts.bbox_dilation).clamp(0., orig_h as f32);
+        let ymax =
+            ((pred[1] + pred[3] / 2.) * h_ratio + opts.bbox_dilation).clamp(0., orig_h as f32);
 
         let bbox = Bbox {
             xmin,
@@ -180,17 +334,18 @@ fn postprocess_yolo(
         bboxes[class_index].push(bbox);
     }
 
-    non_maximum_suppression(&mut bboxes, NMS_THRESHOLD);
+    non_maximum_suppression(&mut bboxes, opts.nms_threshold);
 
     Ok(bboxes.into_iter().flatten().collect())
 }
 
-#[instrument(level = "debug", skip(mask, shrink_thresh))]
+#[instrument(level = "debug", skip(mask, shrink_thresh, opts))]
 fn postprocess_mask(
     mask: &Tensor,
     shrink_thresh: &Tensor,
     original_dimensions: (u32, u32),
     resized_dimensions: (u32, u32),
+    opts: &DetectOptions,
 ) -> anyhow::Result<GrayImage> {
     let shrink_and_thresh = shrink_thresh.squeeze(0)?; // (2, H, W)
     let shrink = shrink_and_thresh.i(0)?; // (H, W)
@@ -225,11 +380,11 @@ fn postprocess_mask(
         original_dimensions.1 as usize,
         original_dimensions.0 as usize,
     )?;
-    let threshold = BINARY_THRESHOLD as f32 / 255.0;
+    let threshold = opts.binary_threshold as f32 / 255.0;
     let binary = resized.ge(threshold)?.to_dtype(DType::F32)?;
 
-    let closed = morph_close(&binary, HOLE_CLOSE_RADIUS as usize)?;
-    let dilated = dilate(&closed, DILATION_RADIUS as usize)?;
+    let closed = morph_close(&binary, opts.hole_close_radius as usize)?;
+    let dilated = dilate(&closed, opts.dilation_radius as usize)?;
     let mask = dilated.squeeze(0)?.squeeze(0)?;
 
     let mask = (mask * 255.)?.to_dtype(DType::U8)?;
@@ -7,12 +7,17 @@ use candle_nn::VarBuilder;
 use image::{DynamicImage, GenericImageView, RgbImage};
 use tracing::instrument;
 
-use crate::{define_models, device};
+use crate::{define_models, device, tiling};
 
 define_models! {
     Lama => ("mayocream/lama-manga", "lama-manga.safetensors"),
 }
 
+/// Images with a side longer than this are inpainted in tiles, which bounds memory use.
+pub const TILE_SIZE: u32 = 2048;
+/// Context a tile shares with each of its neighbours.
+const TILE_OVERLAP: u32 = 256;
+
 pub struct Lama {
     model: model::Lama,
     device: Device,
@@ -36,9 +41,55 @@ impl Lama {
 
     #[instrument(level = "debug", skip_all)]
     pub fn inference(&self, image: &DynamicImage, mask: &DynamicImage) -> Result<DynamicImage> {
-        let (image_tensor, mask_tensor) = self.preprocess(image, mask)?;
-        let output = self.forward(&image_tensor, &mask_tensor)?;
-        self.postprocess(&output)
+        self.inference_tiled(image, mask, TILE_SIZE)
+    }
+
+    /// Inpaint in overlapping `tile_size` squares, skipping those without masked pixels. Each
+    /// tile only writes the part of it nearer to its middle than to its neighbours'.
+    #[instrument(level = "debug", skip(self, image, mask))]
+    pub fn inference_tiled(
+        &self,
+        image: &DynamicImage,
+        mask: &DynamicImage,
+        tile_size: u32,
+    ) -> Result<DynamicImage> {
+        let (width, height) = image.dimensions();
+        let tiles = tiling::tiles(width, height, tile_size, TILE_OVERLAP.min(tile_size / 4));
+        if tiles.len() == 1 {
+            let (image_tensor, mask_tensor) = self.preprocess(image, mask)?;
+            let output = self.forward(&image_tensor, &mask_tensor)?;
+            return self.postprocess(&output);
+        }
+        if image.dimensions() != mask.dimensions() {
+            bail!(
+                "image and mask dimensions dismatch: image is {:?}, mask is {:?}",
+                image.dimensions(),
+                mask.dimensions()
+            );
+        }
+
+        let mask = mask.to_luma8();
+        let mut output = image.to_rgb8();
+        for tile in tiles {
+            let (x, y) = (tile.x.start, tile.y.start);
+            let tile_mask =
+                image::imageops::crop_imm(&mask, x, y, tile.width(), tile.height()).to_image();
+            if !tile_mask.pixels().any(|pixel| pixel.0[0] > 1) {
+                continue;
+            }
+            let tile_mask = DynamicImage::ImageLuma8(tile_mask);
+            let tile_image = image.crop_imm(x, y, tile.width(), tile.height());
+            let (image_tensor, mask_tensor) = self.preprocess(&tile_image, &tile_mask)?;
+            let inpainted = self
+                .postprocess(&self.forward(&image_tensor, &mask_tensor)?)?
+                .to_rgb8();
+            for ty in tile.y.own_start..tile.y.own_end {
+                for tx in tile.x.own_start..tile.x.own_end {
+                    output.put_pixel(tx, ty, *inpainted.get_pixel(tx - x, ty - y));
+                }
+            }
+        }
+        Ok(DynamicImage::ImageRgb8(output))
     }
 
     #[instrument(level = "debug", skip_all)]
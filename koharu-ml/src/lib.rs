@@ -1,16 +1,18 @@
 mod hf_hub;
+mod tiling;
 
 pub mod comic_text_detector;
 pub mod font_detector;
 pub mod lama;
 pub mod llm;
 pub mod manga_ocr;
+pub mod panel_detector;
 
 use anyhow::Result;
 use candle_core::{Device, utils::metal_is_available};
 
 pub use hf_hub::set_cache_dir;
-pub use llm::{language_from_tag, set_default_locale, set_locale, supported_locales};
+pub use llm::{language_from_tag, supported_locales};
 
 pub fn device(cpu: bool) -> Result<Device> {
     if cpu {
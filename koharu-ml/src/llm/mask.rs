@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use candle_core::{Device, Result, Tensor};
+
+/// Causal masks by length, and by length and window for sliding-window layers.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CausalMasks(HashMap<(usize, Option<usize>), Tensor>);
+
+impl CausalMasks {
+    /// Mask of shape `(t, index_pos + t)` for `t` new tokens that follow `index_pos` cached
+    /// ones, hiding later positions and, with a `window`, those at least `window` behind.
+    pub(crate) fn get(
+        &mut self,
+        t: usize,
+        index_pos: usize,
+        window: Option<usize>,
+        device: &Device,
+    ) -> Result<Tensor> {
+        if index_pos == 0
+            && let Some(mask) = self.0.get(&(t, window))
+        {
+            return Ok(mask.clone());
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| {
+                let q = i + index_pos;
+                (0..index_pos + t)
+                    .map(move |j| u8::from(j > q || window.is_some_and(|w| q - j >= w)))
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+        // masks after a cached prefix vary with its length, so only the common ones are kept
+        if index_pos == 0 {
+            self.0.insert((t, window), mask.clone());
+        }
+        Ok(mask)
+    }
+}
+
+pub(crate) fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+    let shape = mask.shape();
+    let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
+    Ok(m)
+}
+
+/// Attention mask of shape `(b, 1, t, index_pos + t)` for a batch of left-padded sequences,
+/// where row `i` starts with `padding[i]` pad tokens. Besides the causal mask, the tokens of a
+/// sequence don't attend to its padding. Pad positions still see each other, so no row of the
+/// softmax is fully masked.
+pub(crate) fn padding_mask(
+    t: usize,
+    index_pos: usize,
+    padding: &[usize],
+    device: &Device,
+) -> Result<Tensor> {
+    let kv = index_pos + t;
+    let mask: Vec<u8> = padding
+        .iter()
+        .flat_map(|&pad| {
+            (0..t).flat_map(move |i| {
+                let q = i + index_pos;
+                (0..kv).map(move |k| u8::from(k > q || (k < pad && q >= pad)))
+            })
+        })
+        .collect();
+    Tensor::from_vec(mask, (padding.len(), 1, t, kv), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn causal_masks_follow_the_cached_prefix() -> anyhow::Result<()> {
+        let mut masks = CausalMasks::default();
+        assert_eq!(
+            masks.get(2, 1, None, &Device::Cpu)?.to_vec2::<u8>()?,
+            vec![vec![0, 0, 1], vec![0, 0, 0]]
+        );
+        assert_eq!(
+            masks.get(3, 0, Some(2), &Device::Cpu)?.to_vec2::<u8>()?,
+            vec![vec![0, 1, 1], vec![0, 0, 1], vec![1, 0, 0]]
+        );
+        // only masks without a prefix are kept
+        assert_eq!(masks.0.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn padded_rows_skip_their_padding() -> anyhow::Result<()> {
+        let mask = padding_mask(3, 0, &[0, 2], &Device::Cpu)?.squeeze(1)?;
+        assert_eq!(
+            mask.to_vec3::<u8>()?,
+            vec![
+                vec![vec![0, 1, 1], vec![0, 0, 1], vec![0, 0, 0]],
+                vec![vec![0, 1, 1], vec![0, 0, 1], vec![1, 1, 0]],
+            ]
+        );
+
+        // decoding one token after the prompt
+        let mask = padding_mask(1, 3, &[0, 2], &Device::Cpu)?.squeeze(1)?;
+        assert_eq!(
+            mask.to_vec3::<u8>()?,
+            vec![vec![vec![0, 0, 0, 0]], vec![vec![1, 1, 0, 0]]]
+        );
+        Ok(())
+    }
+}
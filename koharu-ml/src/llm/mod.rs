@@ -1,11 +1,21 @@
+mod mask;
 mod model;
+mod openai;
 pub mod prompt;
+mod quantized_gemma;
 mod quantized_hunyuan_dense;
 mod quantized_lfm2;
+mod quantized_llama;
+mod quantized_qwen2;
+mod quantized_qwen3;
 mod tokenizer;
 
-pub use model::{GenerateOptions, Llm};
-pub use prompt::{ChatMessage, ChatRole, set_default_locale, set_locale};
+pub use model::{CancellationToken, GenerateOptions, Llm};
+pub use openai::{OpenAiClient, OpenAiConfig};
+pub use prompt::{
+    ChatMessage, ChatRole, ContextTurn, GlossaryEntry, PromptContext, PromptProfile, PromptStyle,
+    UnsupportedLanguage, default_profiles, resolve_language,
+};
 
 macro_rules! define_languages {
     ( $( $code:literal => $name:literal ),* $(,)? ) => {
@@ -182,4 +192,40 @@ define_llms! {
             "mn", "ug", "yue",
         ]
     },
+    Qwen3_4B => {
+        id = "qwen3-4b",
+        repo = "Qwen/Qwen3-4B-GGUF",
+        filename = "Qwen3-4B-Q4_K_M.gguf",
+        languages = [
+            "zh", "en", "fr", "pt", "es", "ja", "tr", "ru", "ar",
+            "ko", "th", "it", "de", "vi", "ms", "id", "tl", "hi",
+            "zh-Hant", "pl", "cs", "nl", "km", "my", "fa", "gu",
+            "ur", "te", "mr", "he", "bn", "ta", "uk", "bo", "kk",
+            "mn", "ug", "yue",
+        ]
+    },
+    Gemma3_4B => {
+        id = "gemma-3-4b-it",
+        repo = "unsloth/gemma-3-4b-it-GGUF",
+        filename = "gemma-3-4b-it-Q4_K_M.gguf",
+        languages = [
+            "zh", "en", "fr", "pt", "es", "ja", "tr", "ru", "ar",
+            "ko", "th", "it", "de", "vi", "ms", "id", "tl", "hi",
+            "zh-Hant", "pl", "cs", "nl", "km", "my", "fa", "gu",
+            "ur", "te", "mr", "he", "bn", "ta", "uk", "bo", "kk",
+            "mn", "ug", "yue",
+        ]
+    },
+    Gemma2_2B => {
+        id = "gemma-2-2b-it",
+        repo = "bartowski/gemma-2-2b-it-GGUF",
+        filename = "gemma-2-2b-it-Q4_K_M.gguf",
+        languages = ["en", "zh", "ja", "ko", "fr", "de", "es", "it", "pt"]
+    },
+    MistralNemo12B => {
+        id = "mistral-nemo-instruct-2407",
+        repo = "bartowski/Mistral-Nemo-Instruct-2407-GGUF",
+        filename = "Mistral-Nemo-Instruct-2407-Q4_K_M.gguf",
+        languages = ["en", "fr", "de", "es", "it", "pt", "zh", "ja", "ko", "ar", "hi"]
+    },
 }
@@ -1,20 +1,29 @@
 use std::io::Seek;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::Result;
 use candle_core::quantized::gguf_file;
 use candle_core::{Device, Tensor};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
-use candle_transformers::models::{quantized_llama, quantized_qwen2};
+use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
 use crate::device;
-use crate::llm::prompt::PromptRenderer;
-use crate::llm::tokenizer::TokenizerFromGguf;
-use crate::llm::{ModelId, quantized_hunyuan_dense, quantized_lfm2};
+use crate::llm::prompt::{PromptContext, PromptRenderer, PromptStyle, UnsupportedLanguage};
+use crate::llm::tokenizer::{TokenizerFromGguf, encode_chat};
+use crate::llm::{
+    ModelId, quantized_gemma, quantized_hunyuan_dense, quantized_lfm2, quantized_llama,
+    quantized_qwen2, quantized_qwen3,
+};
 
+#[derive(Clone)]
 pub enum Model {
     Llama(quantized_llama::ModelWeights),
     Qwen2(quantized_qwen2::ModelWeights),
+    Qwen3(quantized_qwen3::ModelWeights),
+    Gemma(quantized_gemma::ModelWeights),
     Lfm2(quantized_lfm2::ModelWeights),
     HunyuanDense(quantized_hunyuan_dense::ModelWeights),
 }
@@ -24,10 +33,44 @@ impl Model {
         match self {
             Model::Llama(m) => m.forward(input, pos),
             Model::Qwen2(m) => m.forward(input, pos),
+            Model::Qwen3(m) => m.forward(input, pos),
+            Model::Gemma(m) => m.forward(input, pos),
             Model::Lfm2(m) => m.forward(input, pos),
             Model::HunyuanDense(m) => m.forward(input, pos),
         }
     }
+
+    /// Forward a batch of left-padded sequences, for the architectures that can mask the
+    /// padding out.
+    fn forward_padded(&mut self, input: &Tensor, pos: usize, padding: &[usize]) -> Result<Tensor> {
+        Ok(match self {
+            Model::Llama(m) => m.forward_padded(input, pos, padding)?,
+            Model::Qwen2(m) => m.forward_padded(input, pos, padding)?,
+            Model::Qwen3(m) => m.forward_padded(input, pos, padding)?,
+            Model::Gemma(m) => m.forward_padded(input, pos, padding)?,
+            Model::HunyuanDense(m) => m.forward_padded(input, pos, padding)?,
+            // the short convolutions would mix the padding into the first tokens
+            Model::Lfm2(_) => anyhow::bail!("LFM2 models don't support batched generation"),
+        })
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            Model::Llama(m) => m.clear_kv_cache(),
+            Model::Qwen2(m) => m.clear_kv_cache(),
+            Model::Qwen3(m) => m.clear_kv_cache(),
+            Model::Gemma(m) => m.clear_kv_cache(),
+            Model::Lfm2(m) => m.clear_kv_cache(),
+            Model::HunyuanDense(m) => m.clear_kv_cache(),
+        }
+    }
+}
+
+/// The model as it was right after processing `tokens`. Tensors are shared, so keeping a copy
+/// only costs the cache of those positions.
+struct PrefixCache {
+    tokens: Vec<u32>,
+    model: Model,
 }
 
 /// Minimal quantized LLM wrapper
@@ -37,32 +80,156 @@ pub struct Llm {
     tokenizer: Tokenizer,
     prompt_renderer: PromptRenderer,
     eos_token_id: u32,
+    context_length: usize,
+    /// Start of the previous prompts, such as the system prompt, kept to skip processing it again.
+    prefix_cache: Option<PrefixCache>,
+    /// Tokens of the previous prompt, to find the prefix the next one shares with it.
+    last_prompt: Vec<u32>,
 }
 
-#[derive(Debug, Clone)]
+/// Used when the GGUF metadata doesn't say how long the context window is.
+const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+/// Tokens generated at most when [`GenerateOptions::max_tokens`] is not set.
+const DEFAULT_MAX_TOKENS: usize = 1000;
+/// Sampling seed when [`GenerateOptions::seed`] is not set.
+const DEFAULT_SEED: u64 = 299792458;
+
+/// Sampling settings of a generation. Missing fields take their default when deserialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
 pub struct GenerateOptions {
-    pub max_tokens: usize,
+    /// Tokens generated at most, 1000 for local models and the server's default for remote ones.
+    pub max_tokens: Option<usize>,
     pub temperature: f64,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
-    pub seed: u64,
+    /// Sampling seed, fixed for local models and left to the server for remote ones.
+    pub seed: Option<u64>,
     pub split_prompt: bool,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
+    /// Always pick the most likely token, ignoring temperature, top-k and top-p, so the same
+    /// prompt gives the same output.
+    pub greedy: bool,
+}
+
+/// Stops a running generation before its next decoding step. Clones share the same flag.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            anyhow::bail!("Generation was cancelled");
+        }
+        Ok(())
+    }
+}
+
+/// Decodes generated tokens incrementally, holding back text until it forms whole characters.
+/// Like candle's `TokenOutputStream`, only the tokens since the last returned text are decoded
+/// again, starting one piece earlier so the spacing between pieces comes out right.
+struct TokenStream<'a> {
+    tokenizer: &'a Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl<'a> TokenStream<'a> {
+    fn new(tokenizer: &'a Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    /// Add a token and return the text it completes, if any.
+    fn push(&mut self, token: u32) -> Result<Option<String>> {
+        let prev_text = self.decode_tokens(&self.tokens[self.prev_index..self.current_index])?;
+        self.tokens.push(token);
+        let text = self.decode_tokens(&self.tokens[self.prev_index..])?;
+        // a multi-byte character split across tokens decodes to a replacement character
+        if text.len() <= prev_text.len()
+            || text.ends_with('\u{FFFD}')
+            || !text.is_char_boundary(prev_text.len())
+        {
+            return Ok(None);
+        }
+        let delta = text[prev_text.len()..].to_string();
+        self.prev_index = self.current_index;
+        self.current_index = self.tokens.len();
+        Ok(Some(delta))
+    }
+
+    /// The whole text of the tokens so far.
+    fn decode(&self) -> Result<String> {
+        self.decode_tokens(&self.tokens)
+    }
+
+    fn decode_tokens(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(anyhow::Error::msg)
+    }
+}
+
+/// Build the sampler `opts` describe.
+fn sampler(opts: &GenerateOptions) -> LogitsProcessor {
+    let temperature = opts.temperature;
+    let sampling = if opts.greedy || temperature <= 0.0 {
+        Sampling::ArgMax
+    } else {
+        match (opts.top_k, opts.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    };
+    LogitsProcessor::from_sampling(opts.seed.unwrap_or(DEFAULT_SEED), sampling)
+}
+
+/// Penalize the last `repeat_last_n` of `tokens` in `logits`, if a penalty is set.
+fn penalize(logits: Tensor, tokens: &[u32], opts: &GenerateOptions) -> Result<Tensor> {
+    if (opts.repeat_penalty - 1.0).abs() < f32::EPSILON {
+        return Ok(logits);
+    }
+    let start_at = tokens.len().saturating_sub(opts.repeat_last_n);
+    Ok(candle_transformers::utils::apply_repeat_penalty(
+        &logits,
+        opts.repeat_penalty,
+        &tokens[start_at..],
+    )?)
 }
 
 // refer: https://github.com/huggingface/candle/blob/d4545ebbbfb37d3cf0e228642ffaaa75b5d6bce9/candle-examples/examples/quantized/main.rs#L235
 impl Default for GenerateOptions {
     fn default() -> Self {
         Self {
-            max_tokens: 1000,
+            max_tokens: None,
             temperature: 0.8,
             top_k: None,
             top_p: None,
-            seed: 299792458,
+            seed: None,
             split_prompt: false,
             repeat_penalty: 1.1,
             repeat_last_n: 64,
+            greedy: false,
         }
     }
 }
@@ -71,10 +238,15 @@ impl Llm {
     /// Constructs a new LLM instance from a quantized GGUF model (including tokenizer metadata).
     pub async fn load(id: ModelId, use_cpu: bool) -> Result<Self> {
         let model_path = id.get().await?;
+        Self::load_file(&model_path, PromptStyle::Builtin(id), use_cpu)
+    }
 
+    /// Load any GGUF file of a supported architecture, prompted with `style`.
+    /// The chat template comes from the file's metadata.
+    pub fn load_file(model_path: &Path, style: PromptStyle, use_cpu: bool) -> Result<Self> {
         // Peek GGUF metadata to choose device/loader
-        let mut file = std::fs::File::open(&model_path)?;
-        let ct = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&model_path))?;
+        let mut file = std::fs::File::open(model_path)?;
+        let ct = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
         let tokenizer = Tokenizer::from_gguf(&ct)?;
         let metadata = ct.metadata.clone();
         let md_get = |s: &str| {
@@ -88,10 +260,20 @@ impl Llm {
             .to_string()?;
         let bos_token_id = md_get("tokenizer.ggml.bos_token_id")?.to_u32()?;
         let eos_token_id = md_get("tokenizer.ggml.eos_token_id")?.to_u32()?;
+        let context_length = md_get(&format!("{arch}.context_length"))
+            .and_then(|value| Ok(value.to_u32()? as usize))
+            .unwrap_or(DEFAULT_CONTEXT_LENGTH);
 
         // The gguf metadata for Sakura1.5bQwen2.5v1.0 has wrong eos_token_id, override it here
-        let eos_token_id = match id {
-            ModelId::Sakura1_5bQwen2_5v1_0 => 151645,
+        let eos_token_id = match style {
+            PromptStyle::Builtin(ModelId::Sakura1_5bQwen2_5v1_0) => 151645,
+            _ => eos_token_id,
+        };
+        // Gemma ends its turns with <end_of_turn> rather than the <eos> the metadata names
+        let eos_token_id = match arch.as_str() {
+            "gemma2" | "gemma3" => tokenizer
+                .token_to_id("<end_of_turn>")
+                .unwrap_or(eos_token_id),
             _ => eos_token_id,
         };
 
@@ -103,7 +285,8 @@ impl Llm {
         let eos_token = tokenizer
             .id_to_token(eos_token_id)
             .unwrap_or_else(|| eos_token_id.to_string());
-        let prompt_renderer = PromptRenderer::new(id, chat_template.clone(), bos_token, eos_token);
+        let prompt_renderer =
+            PromptRenderer::new(style, chat_template.clone(), bos_token, eos_token);
 
         // Rewind reader before loading tensors
         file.rewind()?;
@@ -119,6 +302,12 @@ impl Llm {
             "qwen2" => Model::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
                 ct, &mut file, &device,
             )?),
+            "qwen3" => Model::Qwen3(quantized_qwen3::ModelWeights::from_gguf(
+                ct, &mut file, &device,
+            )?),
+            "gemma2" | "gemma3" => Model::Gemma(quantized_gemma::ModelWeights::from_gguf(
+                ct, &mut file, &device,
+            )?),
             "lfm2" => Model::Lfm2(quantized_lfm2::ModelWeights::from_gguf(
                 ct, &mut file, &device,
             )?),
@@ -131,61 +320,165 @@ impl Llm {
             tokenizer,
             prompt_renderer,
             eos_token_id,
+            context_length,
+            prefix_cache: None,
+            last_prompt: Vec::new(),
         })
     }
 
-    /// Generate up to `max_tokens` following `prompt` using temperature/top-k/p settings.
-    /// Logs simple performance metrics via `tracing`.
-    pub fn generate(&mut self, prompt: &str, opts: &GenerateOptions) -> Result<String> {
-        let prompt = self
-            .prompt_renderer
-            .format_chat_prompt(prompt.to_string())?;
-        tracing::info!("Generating with prompt:\n{}", prompt);
+    /// Drop the cached prompt prefix, so the next prompt is processed from the start.
+    pub fn clear_prompt_cache(&mut self) {
+        self.prefix_cache = None;
+        self.last_prompt.clear();
+        self.model.clear_kv_cache();
+    }
 
-        // Encode prompt
-        let enc = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?;
-        let prompt_tokens: Vec<u32> = enc.get_ids().to_vec();
-        let mut all_tokens: Vec<u32> = Vec::new();
+    /// Feed `tokens` to the model and return the logits of the last one, with how many tokens
+    /// were restored from the prefix cache. The part shared with the previous prompt is kept
+    /// as the new prefix, and the cache is cleared once the prompt no longer starts with it.
+    fn process_prompt(
+        &mut self,
+        tokens: &[u32],
+        split_prompt: bool,
+        cancel: &CancellationToken,
+    ) -> Result<(Tensor, usize)> {
+        anyhow::ensure!(!tokens.is_empty(), "prompt is empty");
+        let mut start = match &self.prefix_cache {
+            // at least one token has to be processed to get logits
+            Some(prefix)
+                if prefix.tokens.len() < tokens.len() && tokens.starts_with(&prefix.tokens) =>
+            {
+                self.model = prefix.model.clone();
+                prefix.tokens.len()
+            }
+            _ => {
+                self.prefix_cache = None;
+                self.model.clear_kv_cache();
+                0
+            }
+        };
+        let reused = start;
 
-        // Build sampler
-        let mut logits_processor = {
-            let temperature = opts.temperature;
-            let sampling = if temperature <= 0.0 {
-                Sampling::ArgMax
-            } else {
-                match (opts.top_k, opts.top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-                }
+        let shared = self
+            .last_prompt
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(tokens.len() - 1);
+        if shared > start {
+            self.forward_tokens(&tokens[start..shared], start, split_prompt, cancel)?;
+            self.prefix_cache = Some(PrefixCache {
+                tokens: tokens[..shared].to_vec(),
+                model: self.model.clone(),
+            });
+            start = shared;
+        }
+        let logits = self.forward_tokens(&tokens[start..], start, split_prompt, cancel)?;
+        self.last_prompt = tokens.to_vec();
+        Ok((logits, reused))
+    }
+
+    /// Run `tokens` at positions from `pos`, all at once or one by one, returning the logits
+    /// of the last one.
+    fn forward_tokens(
+        &mut self,
+        tokens: &[u32],
+        pos: usize,
+        split_prompt: bool,
+        cancel: &CancellationToken,
+    ) -> Result<Tensor> {
+        cancel.check()?;
+        if !split_prompt {
+            let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+            return Ok(self.model.forward(&input, pos)?.squeeze(0)?);
+        }
+        let mut logits = None;
+        for (index, token) in tokens.iter().enumerate() {
+            cancel.check()?;
+            let input = Tensor::new(&[*token], &self.device)?.unsqueeze(0)?;
+            logits = Some(self.model.forward(&input, pos + index)?.squeeze(0)?);
+        }
+        logits.ok_or_else(|| anyhow::anyhow!("no tokens to process"))
+    }
+
+    /// Render `prompt` with `context` and tokenize it. The oldest history turns are dropped
+    /// until the prompt and `max_tokens` fit the context window.
+    fn encode_prompt(
+        &self,
+        prompt: &str,
+        context: &PromptContext,
+        opts: &GenerateOptions,
+    ) -> Result<Vec<u32>> {
+        let max_tokens = opts.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        let budget = self.context_length.saturating_sub(max_tokens);
+        let mut history = context.history;
+        let (prompt, prompt_tokens) = loop {
+            let context = PromptContext {
+                history,
+                ..*context
             };
-            LogitsProcessor::from_sampling(opts.seed, sampling)
+            let prompt = self
+                .prompt_renderer
+                .format_chat_prompt(prompt.to_string(), &context)?;
+            let tokens = encode_chat(&self.tokenizer, &prompt, self.prompt_renderer.bos_token())?;
+            if history.is_empty() || tokens.len() <= budget {
+                break (prompt, tokens);
+            }
+            history = &history[1..];
         };
+        if history.len() < context.history.len() {
+            tracing::info!(
+                "Dropped {} context turns to fit {} tokens",
+                context.history.len() - history.len(),
+                self.context_length
+            );
+        }
+        tracing::info!("Generating with prompt:\n{}", prompt);
+        Ok(prompt_tokens)
+    }
+
+    /// The language `requested` resolves to, or why the model can't translate into it.
+    pub fn target_language(&self, requested: Option<&str>) -> Result<String, UnsupportedLanguage> {
+        self.prompt_renderer.style().target_language(requested)
+    }
 
-        // Process prompt (all at once or token by token)
+    /// Generate up to `max_tokens` following `prompt` using temperature/top-k/p settings.
+    /// The glossary in `context` is added for models trained to follow one, and the oldest
+    /// history turns are dropped until the prompt and `max_tokens` fit the context window.
+    /// Logs simple performance metrics via `tracing`.
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        context: &PromptContext,
+        opts: &GenerateOptions,
+    ) -> Result<String> {
+        self.generate_stream(prompt, context, opts, &CancellationToken::new(), |_| {})
+    }
+
+    /// Like [`Llm::generate`], passing each piece of decoded text to `on_token` as soon as it
+    /// is complete. Fails once `cancel` is cancelled, checked before every decoding step.
+    pub fn generate_stream(
+        &mut self,
+        prompt: &str,
+        context: &PromptContext,
+        opts: &GenerateOptions,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<String> {
+        let prompt_tokens = self.encode_prompt(prompt, context, opts)?;
+        let mut logits_processor = sampler(opts);
+
+        // Process prompt, reusing the cache of the prefix it shares with the previous one
         let start_prompt_processing = std::time::Instant::now();
-        let mut next_token = if !opts.split_prompt {
-            let input = Tensor::new(prompt_tokens.as_slice(), &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, 0)?.squeeze(0)?;
-            logits_processor.sample(&logits)?
-        } else {
-            let mut next_token = 0u32;
-            for (pos, token) in prompt_tokens.iter().enumerate() {
-                let input = Tensor::new(&[*token], &self.device)?.unsqueeze(0)?;
-                let logits = self.model.forward(&input, pos)?.squeeze(0)?;
-                next_token = logits_processor.sample(&logits)?;
-            }
-            next_token
-        };
+        let (logits, reused) = self.process_prompt(&prompt_tokens, opts.split_prompt, cancel)?;
+        let mut next_token = logits_processor.sample(&logits)?;
         let prompt_dt = start_prompt_processing.elapsed();
 
         tracing::info!(
-            "{:4} prompt tokens processed: {:.2} token/s",
+            "{:4} prompt tokens processed ({} from cache): {:.2} token/s",
             prompt_tokens.len(),
+            reused,
             if prompt_dt.as_secs_f64() > 0.0 {
                 prompt_tokens.len() as f64 / prompt_dt.as_secs_f64()
             } else {
@@ -198,32 +491,33 @@ impl Llm {
             return Ok("".to_string());
         }
 
+        let mut all_tokens: Vec<u32> = Vec::new();
+        let mut stream = TokenStream::new(&self.tokenizer);
         all_tokens.push(next_token);
+        if let Some(text) = stream.push(next_token)? {
+            on_token(&text);
+        }
         // Generate tokens autoregressively
         let start_post_prompt = std::time::Instant::now();
         let mut sampled = 0usize;
-        for index in 0..opts.max_tokens.saturating_sub(1) {
+        let max_tokens = opts.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        for index in 0..max_tokens.saturating_sub(1) {
+            cancel.check()?;
             let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
             let logits = self
                 .model
                 .forward(&input, prompt_tokens.len() + index)?
                 .squeeze(0)?;
-            let logits = if (opts.repeat_penalty - 1.0).abs() < f32::EPSILON {
-                logits
-            } else {
-                let start_at = all_tokens.len().saturating_sub(opts.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    opts.repeat_penalty,
-                    &all_tokens[start_at..],
-                )?
-            };
+            let logits = penalize(logits, &all_tokens, opts)?;
             next_token = logits_processor.sample(&logits)?;
             all_tokens.push(next_token);
             sampled += 1;
             if next_token == self.eos_token_id {
                 break;
             }
+            if let Some(text) = stream.push(next_token)? {
+                on_token(&text);
+            }
         }
         let gen_dt = start_post_prompt.elapsed();
 
@@ -237,8 +531,172 @@ impl Llm {
             }
         );
 
-        self.tokenizer
-            .decode(&all_tokens, true)
-            .map_err(anyhow::Error::msg)
+        stream.decode()
+    }
+
+    /// Whether [`Llm::generate_batch`] decodes its prompts together rather than one after
+    /// another.
+    pub fn supports_batch(&self) -> bool {
+        !matches!(self.model, Model::Lfm2(_))
+    }
+
+    /// Generate for several independent prompts sharing `context`, returning the outputs in
+    /// order. The prompts are left-padded to the same length and decoded together, each one
+    /// stopping at its own EOS token, and `on_token` gets the index of the prompt with each
+    /// piece of text. Models without batch support run them one after another.
+    pub fn generate_batch(
+        &mut self,
+        prompts: &[&str],
+        context: &PromptContext,
+        opts: &GenerateOptions,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(usize, &str),
+    ) -> Result<Vec<String>> {
+        if prompts.len() < 2 || !self.supports_batch() {
+            return prompts
+                .iter()
+                .enumerate()
+                .map(|(i, prompt)| {
+                    self.generate_stream(prompt, context, opts, cancel, |text| on_token(i, text))
+                })
+                .collect();
+        }
+
+        let encoded = prompts
+            .iter()
+            .map(|prompt| self.encode_prompt(prompt, context, opts))
+            .collect::<Result<Vec<_>>>()?;
+        let len = encoded.iter().map(Vec::len).max().unwrap_or_default();
+        let padding: Vec<usize> = encoded.iter().map(|tokens| len - tokens.len()).collect();
+        let mut input = Vec::with_capacity(prompts.len() * len);
+        for (tokens, &pad) in encoded.iter().zip(&padding) {
+            input.extend(std::iter::repeat_n(self.eos_token_id, pad));
+            input.extend(tokens);
+        }
+
+        // the prefix cache holds a single sequence, so the batch starts from scratch
+        self.model.clear_kv_cache();
+        self.last_prompt.clear();
+
+        let start_prompt_processing = std::time::Instant::now();
+        cancel.check()?;
+        let input = Tensor::from_vec(input, (prompts.len(), len), &self.device)?;
+        let mut logits = self.model.forward_padded(&input, 0, &padding)?;
+        let prompt_dt = start_prompt_processing.elapsed();
+        let prompt_count: usize = encoded.iter().map(Vec::len).sum();
+        tracing::info!(
+            "{:4} prompt tokens processed in a batch of {}: {:.2} token/s",
+            prompt_count,
+            prompts.len(),
+            if prompt_dt.as_secs_f64() > 0.0 {
+                prompt_count as f64 / prompt_dt.as_secs_f64()
+            } else {
+                0.0
+            }
+        );
+
+        let mut samplers: Vec<_> = prompts.iter().map(|_| sampler(opts)).collect();
+        let mut streams: Vec<_> = prompts
+            .iter()
+            .map(|_| TokenStream::new(&self.tokenizer))
+            .collect();
+        let mut all_tokens = vec![Vec::new(); prompts.len()];
+        let mut done = vec![false; prompts.len()];
+        let mut next_tokens = vec![self.eos_token_id; prompts.len()];
+
+        let start_post_prompt = std::time::Instant::now();
+        let mut sampled = 0usize;
+        let max_tokens = opts.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        for index in 0..max_tokens {
+            for (i, sampler) in samplers.iter_mut().enumerate() {
+                if done[i] {
+                    continue;
+                }
+                let logits = penalize(logits.get(i)?, &all_tokens[i], opts)?;
+                let next_token = sampler.sample(&logits)?;
+                next_tokens[i] = next_token;
+                all_tokens[i].push(next_token);
+                sampled += 1;
+                if next_token == self.eos_token_id {
+                    done[i] = true;
+                } else if let Some(text) = streams[i].push(next_token)? {
+                    on_token(i, &text);
+                }
+            }
+            if done.iter().all(|&done| done) || index + 1 == max_tokens {
+                break;
+            }
+            cancel.check()?;
+            // finished sequences keep decoding their EOS token, their output is ignored
+            let input = Tensor::new(next_tokens.as_slice(), &self.device)?.unsqueeze(1)?;
+            logits = self.model.forward_padded(&input, len + index, &padding)?;
+        }
+        let gen_dt = start_post_prompt.elapsed();
+
+        tracing::info!(
+            "{:<4} tokens generated in a batch of {}: {:.2} token/s",
+            sampled,
+            prompts.len(),
+            if gen_dt.as_secs_f64() > 0.0 {
+                sampled as f64 / gen_dt.as_secs_f64()
+            } else {
+                0.0
+            }
+        );
+
+        // the caches now hold the whole batch
+        self.model.clear_kv_cache();
+        streams.iter().map(TokenStream::decode).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::{
+        decoders::{byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence, strip::Strip},
+        models::wordlevel::WordLevel,
+        normalizers::Replace,
+    };
+
+    use super::*;
+
+    #[test]
+    fn token_stream_emits_whole_characters_with_their_spacing() -> Result<()> {
+        let tokens = ["<unk>", "▁Hello", "▁world", "<0xE3>", "<0x81>", "<0x82>"];
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        // the SentencePiece decoder, which strips the space of the first piece it decodes
+        tokenizer.with_decoder(Some(Sequence::new(vec![
+            Replace::new("▁", " ").map_err(anyhow::Error::msg)?.into(),
+            ByteFallback::new().into(),
+            Fuse::new().into(),
+            Strip::new(' ', 1, 0).into(),
+        ])));
+
+        let mut stream = TokenStream::new(&tokenizer);
+        let pieces = (1..tokens.len() as u32)
+            .map(|token| stream.push(token))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            pieces,
+            [
+                Some("Hello".to_string()),
+                Some(" world".to_string()),
+                None,
+                None,
+                Some("あ".to_string()),
+            ]
+        );
+        assert_eq!(stream.decode()?, "Hello worldあ");
+        Ok(())
     }
 }
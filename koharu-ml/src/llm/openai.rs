@@ -0,0 +1,378 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use koharu_core::http::http_client;
+use reqwest::Url;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+
+use crate::llm::prompt::{
+    ChatMessage, PromptContext, PromptProfile, UnsupportedLanguage, resolve_language,
+};
+use crate::llm::supported_locales;
+use crate::llm::{CancellationToken, GenerateOptions};
+
+const DEFAULT_ENDPOINT: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+/// Low enough to keep names and terms stable across pages, used unless options are given.
+const TEMPERATURE: f64 = 0.2;
+
+/// Where to reach an OpenAI-style `/v1/chat/completions` server, such as llama.cpp, vLLM or Ollama.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OpenAiConfig {
+    /// Base URL like `http://localhost:8080/v1`, or the full chat completions URL.
+    /// Defaults to the OpenAI API.
+    pub endpoint: String,
+    /// Defaults to `gpt-4o-mini`.
+    pub model: String,
+    pub api_key: Option<String>,
+    /// Replaces the default instructions, the target language and numbering rules still follow.
+    pub prompt: Option<String>,
+}
+
+/// Translates through a remote chat completions endpoint.
+#[derive(Debug, Clone)]
+pub struct OpenAiClient {
+    url: Url,
+    model: String,
+    api_key: Option<String>,
+    prompt: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    temperature: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct Completion {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: Option<Delta>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+impl OpenAiClient {
+    pub fn new(config: OpenAiConfig) -> Result<Self> {
+        let endpoint = match config.endpoint.trim() {
+            "" => DEFAULT_ENDPOINT,
+            endpoint => endpoint,
+        };
+        let mut url = Url::parse(endpoint)
+            .with_context(|| format!("invalid OpenAI compatible endpoint `{endpoint}`"))?;
+        if !url.path().ends_with("/chat/completions") {
+            let path = format!("{}/chat/completions", url.path().trim_end_matches('/'));
+            url.set_path(&path);
+        }
+
+        let api_key = config.api_key.filter(|key| !key.trim().is_empty());
+        anyhow::ensure!(
+            api_key.is_some() || url.host_str() != Some("api.openai.com"),
+            "an API key is required for the OpenAI API"
+        );
+
+        let model = match config.model.trim() {
+            "" => DEFAULT_MODEL.to_string(),
+            model => model.to_string(),
+        };
+
+        Ok(Self {
+            url,
+            model,
+            api_key,
+            prompt: config.prompt,
+        })
+    }
+
+    /// The language `requested` resolves to. Remote models are offered every supported language.
+    pub fn target_language(&self, requested: Option<&str>) -> Result<String, UnsupportedLanguage> {
+        resolve_language(requested, &supported_locales(), &self.model)
+    }
+
+    /// Send `prompt` and stream the answer to `on_token`. Servers that ignore `stream` and
+    /// answer with a single completion are accepted too. Of `opts`, the server is sent the
+    /// settings chat completions know about, the token limit and seed only when set; top-k and
+    /// the repeat penalty are local only.
+    pub async fn generate(
+        &self,
+        prompt: &str,
+        context: &PromptContext<'_>,
+        opts: Option<&GenerateOptions>,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<String> {
+        cancel.check()?;
+        let language = self.target_language(context.language)?;
+        let context = &PromptContext {
+            language: Some(&language),
+            ..*context
+        };
+        // earlier turns are replayed as chat history, like for the local models
+        let messages = match context.profile {
+            Some(profile) => profile.messages(prompt, context)?,
+            None => PromptProfile::generic(self.prompt.as_deref()).messages(prompt, context)?,
+        };
+        let greedy = opts.is_some_and(|opts| opts.greedy);
+        let body = serde_json::to_vec(&ChatRequest {
+            model: &self.model,
+            messages: &messages,
+            temperature: match opts {
+                _ if greedy => 0.0,
+                Some(opts) => opts.temperature,
+                None => TEMPERATURE,
+            },
+            top_p: opts.and_then(|opts| opts.top_p).filter(|_| !greedy),
+            max_tokens: opts.and_then(|opts| opts.max_tokens),
+            seed: opts.and_then(|opts| opts.seed),
+            stream: true,
+        })?;
+
+        let mut request = http_client()
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(api_key) = &self.api_key {
+            request = request.header(AUTHORIZATION, format!("Bearer {api_key}"));
+        }
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("cannot reach {}", self.url))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            anyhow::bail!("OpenAI compatible request failed ({status}): {message}");
+        }
+
+        let is_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if !is_stream {
+            let completion: Completion = serde_json::from_slice(&response.bytes().await?)?;
+            let choice = completion
+                .choices
+                .into_iter()
+                .next()
+                .context("OpenAI compatible response has no choices")?;
+            let content = choice
+                .message
+                .and_then(|message| message.content)
+                .or(choice.text)
+                .context("OpenAI compatible response missing content")?;
+            on_token(&content);
+            return Ok(content);
+        }
+
+        let mut output = String::new();
+        let mut buffer = Vec::new();
+        let mut body = response.bytes_stream();
+        'stream: while let Some(chunk) = body.next().await {
+            cancel.check()?;
+            buffer.extend_from_slice(&chunk?);
+            // server-sent events, one `data: {...}` line per chunk
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<_>>();
+                let Some(data) = std::str::from_utf8(&line)?.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+                let chunk: CompletionChunk = serde_json::from_str(data)
+                    .with_context(|| format!("invalid stream chunk `{data}`"))?;
+                if let Some(text) = chunk
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.delta.content)
+                    .filter(|text| !text.is_empty())
+                {
+                    output.push_str(&text);
+                    on_token(&text);
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::llm::{ContextTurn, GlossaryEntry};
+
+    /// Answer a single request with `content_type` and `body`, returning the raw request.
+    async fn serve_once(content_type: &'static str, body: String) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let read = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|value| value.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (endpoint, handle)
+    }
+
+    #[tokio::test]
+    async fn streams_chat_completions() -> Result<()> {
+        let events = [
+            r#"{"choices":[{"delta":{"role":"assistant"}}]}"#,
+            r#"{"choices":[{"delta":{"content":"1. Hello"}}]}"#,
+            r#"{"choices":[{"delta":{"content":"\n2. Bye"}}]}"#,
+        ]
+        .iter()
+        .map(|event| format!("data: {event}\n\n"))
+        .collect::<String>()
+            + "data: [DONE]\n\n";
+        let (endpoint, request) = serve_once("text/event-stream", events).await;
+
+        let client = OpenAiClient::new(OpenAiConfig {
+            endpoint,
+            model: "local".to_string(),
+            api_key: Some("secret".to_string()),
+            prompt: None,
+        })?;
+        let glossary = [GlossaryEntry {
+            source: "コハル".to_string(),
+            target: "Koharu".to_string(),
+            notes: None,
+        }];
+        let history = [ContextTurn {
+            source: "はい".to_string(),
+            translation: "Yes".to_string(),
+        }];
+        let context = PromptContext {
+            glossary: &glossary,
+            history: &history,
+            ..Default::default()
+        };
+
+        let mut tokens = Vec::new();
+        let output = client
+            .generate(
+                "1. こんにちは\n2. さようなら",
+                &context,
+                Some(&GenerateOptions {
+                    greedy: true,
+                    ..Default::default()
+                }),
+                &CancellationToken::new(),
+                |text| tokens.push(text.to_string()),
+            )
+            .await?;
+        assert_eq!(output, "1. Hello\n2. Bye");
+        assert_eq!(tokens, ["1. Hello", "\n2. Bye"]);
+
+        let request = request.await?;
+        assert!(request.starts_with("POST /v1/chat/completions "));
+        assert!(request.contains("authorization: Bearer secret"));
+        let body: serde_json::Value =
+            serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1)?;
+        assert_eq!(body["model"], "local");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["temperature"], 0.0);
+        // unset limits are left to the server
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("seed").is_none());
+        let roles = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["role"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert!(
+            body["messages"][0]["content"]
+                .as_str()
+                .unwrap()
+                .contains("コハル -> Koharu")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accepts_a_single_completion() -> Result<()> {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":"Hello"}}]}"#;
+        let (endpoint, _) = serve_once("application/json", body.to_string()).await;
+
+        let client = OpenAiClient::new(OpenAiConfig {
+            endpoint: format!("{endpoint}/chat/completions"),
+            ..Default::default()
+        })?;
+        let output = client
+            .generate(
+                "こんにちは",
+                &PromptContext::default(),
+                None,
+                &CancellationToken::new(),
+                |_| {},
+            )
+            .await?;
+        assert_eq!(output, "Hello");
+        Ok(())
+    }
+
+    #[test]
+    fn openai_api_needs_a_key() {
+        assert!(OpenAiClient::new(OpenAiConfig::default()).is_err());
+    }
+}
@@ -1,34 +1,56 @@
-use minijinja::{Environment, context};
-use once_cell::sync::Lazy;
-use serde::Serialize;
-use std::sync::RwLock;
+use minijinja::value::{Value, ValueKind};
+use minijinja::{Environment, Error, ErrorKind, State, context};
+use serde::{Deserialize, Serialize};
 use strum::{Display, EnumString};
 use sys_locale::get_locale;
 
-use crate::llm::{ModelId, language_from_tag};
+use crate::llm::{ModelId, SUPPORTED_LANGUAGES, supported_locales};
 
-static LOCALE: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(system_locale_name()));
-
-fn system_locale_name() -> String {
-    get_locale()
-        .map(|locale| language_from_tag(&locale).to_string())
-        .unwrap_or_else(|| "English".to_string())
-}
-
-fn get_default_locale() -> String {
-    LOCALE
-        .read()
-        .map(|locale| locale.clone())
-        .unwrap_or_else(|_| system_locale_name())
+/// Look up a language by name or tag, such as `日本語`, `ja` or `ja-JP`.
+fn find_language(language: &str) -> Option<&'static str> {
+    let language = language.trim();
+    let primary = language.split(['-', '_']).next().unwrap_or(language);
+    [language, primary].into_iter().find_map(|language| {
+        SUPPORTED_LANGUAGES
+            .iter()
+            .find(|(code, name)| code.eq_ignore_ascii_case(language) || *name == language)
+            .map(|(_, name)| *name)
+    })
 }
 
-pub fn set_default_locale(locale: String) {
-    set_locale(locale);
+/// A target language the model can't translate into.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{model} cannot translate into {language}, supported: {}", .supported.join(", "))]
+pub struct UnsupportedLanguage {
+    pub model: String,
+    pub language: String,
+    pub supported: Vec<String>,
 }
 
-pub fn set_locale(locale: String) {
-    if let Ok(mut guard) = LOCALE.write() {
-        *guard = locale;
+/// Resolve `requested` to one of the `supported` language names. Without a request, the system
+/// language is used if `model` supports it and its first language otherwise.
+pub fn resolve_language(
+    requested: Option<&str>,
+    supported: &[String],
+    model: &str,
+) -> Result<String, UnsupportedLanguage> {
+    let is_supported = |name: &&str| supported.iter().any(|language| language == name);
+    match requested {
+        Some(language) => find_language(language)
+            .filter(is_supported)
+            .map(str::to_string)
+            .ok_or_else(|| UnsupportedLanguage {
+                model: model.to_string(),
+                language: language.to_string(),
+                supported: supported.to_vec(),
+            }),
+        None => Ok(get_locale()
+            .as_deref()
+            .and_then(find_language)
+            .filter(is_supported)
+            .map(str::to_string)
+            .or_else(|| supported.first().cloned())
+            .unwrap_or_else(|| "English".to_string())),
     }
 }
 
@@ -52,6 +74,222 @@ impl Serialize for ChatRole {
     }
 }
 
+/// A project term that should always be translated the same way.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GlossaryEntry {
+    pub source: String,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+/// How prompts are worded for a model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptStyle {
+    /// The format a known model was trained with.
+    Builtin(ModelId),
+    /// Any chat model, instructed with `system` or a generic translation prompt.
+    Generic { system: Option<String> },
+}
+
+impl PromptStyle {
+    /// Languages the prompts can translate into, by name.
+    pub fn languages(&self) -> Vec<String> {
+        match self {
+            PromptStyle::Builtin(model_id) => model_id.languages(),
+            PromptStyle::Generic { .. } => supported_locales(),
+        }
+    }
+
+    /// The language to translate into, see [`resolve_language`].
+    pub fn target_language(&self, requested: Option<&str>) -> Result<String, UnsupportedLanguage> {
+        let model = match self {
+            PromptStyle::Builtin(model_id) => model_id.to_string(),
+            PromptStyle::Generic { .. } => "The model".to_string(),
+        };
+        resolve_language(requested, &self.languages(), &model)
+    }
+}
+
+impl From<ModelId> for PromptStyle {
+    fn from(id: ModelId) -> Self {
+        PromptStyle::Builtin(id)
+    }
+}
+
+/// An earlier source text and the translation that was kept for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContextTurn {
+    pub source: String,
+    pub translation: String,
+}
+
+/// Project knowledge added to a translation prompt.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PromptContext<'a> {
+    pub glossary: &'a [GlossaryEntry],
+    /// Replayed as chat history, oldest first.
+    pub history: &'a [ContextTurn],
+    /// Replaces the prompts of the model.
+    pub profile: Option<&'a PromptProfile>,
+    /// Language to translate into, by name or tag. Defaults to the system language.
+    pub language: Option<&'a str>,
+}
+
+const TEXT_USER: &str = "{{ text }}";
+
+const LFM2_SYSTEM: &str =
+    "Translate to English, do not add any explanations, do not add or delete line breaks.";
+
+// refer: https://huggingface.co/SakuraLLM/Sakura-GalTransl-7B-v3.7#prompt
+const SAKURA_SYSTEM: &str = "你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。";
+const SAKURA_USER: &str = "{% if glossary %}参考以下术语表（可为空，格式为src->dst #备注）：\n{% for entry in glossary %}{{ entry.source }}->{{ entry.target }}{% if entry.notes %} #{{ entry.notes }}{% endif %}\n{% endfor %}根据以上术语表的对应关系和备注，将下面的文本从日文翻译成简体中文：\n{{ text }}{% else %}{{ text }}{% endif %}";
+
+// refer: https://huggingface.co/tencent/Hunyuan-MT-7B#prompts
+const HUNYUAN_USER: &str = "{% if glossary %}参考下面的翻译：\n{% for entry in glossary %}{{ entry.source }} 翻译成 {{ entry.target }}\n{% endfor %}\n将以下文本翻译为{{ language }}，注意只需要输出翻译后的结果，不要额外解释：\n{{ text }}{% else %}Translate the following light novel dialog into {{ language }}, without additional explanation.\n\n{{ text }}{% endif %}";
+
+/// Role of chat models that weren't trained on a prompt format of their own.
+const GENERIC_SYSTEM: &str = "You are a manga translator.";
+/// Follows generic instructions, custom ones too, so answers can be matched to the numbered lines.
+const FORMAT_SUFFIX: &str = "Translate the user's text into {{ language }}. Keep the numbered lines and their numbers, one line per number, and do not add explanations.";
+/// Appended to generic instructions, so custom ones get the glossary too.
+const GLOSSARY_SUFFIX: &str = "{% if glossary %}\n\nAlways translate these terms as given:{% for entry in glossary %}\n{{ entry.source }} -> {{ entry.target }}{% if entry.notes %} ({{ entry.notes }}){% endif %}{% endfor %}{% endif %}";
+
+/// MiniJinja templates for the system prompt and the user message of a translation.
+///
+/// Both see `text`, `language`, `glossary` (`source`, `target` and `notes` of each term) and
+/// `context` (`source` and `translation` of the earlier turns). Earlier turns replayed as chat
+/// history are rendered with an empty glossary and context.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptProfile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub user: String,
+}
+
+impl PromptProfile {
+    pub fn new(system: Option<&str>, user: &str) -> Self {
+        Self {
+            system: system.map(str::to_string),
+            user: user.to_string(),
+        }
+    }
+
+    /// The prompts a model was tuned with.
+    pub fn builtin(style: &PromptStyle) -> Self {
+        let model_id = match style {
+            PromptStyle::Builtin(model_id) => model_id,
+            PromptStyle::Generic { system } => return Self::generic(system.as_deref()),
+        };
+        match model_id {
+            ModelId::VntlLlama3_8Bv2 => Self::new(None, TEXT_USER),
+            ModelId::Lfm2_350mEnjpMt => Self::new(Some(LFM2_SYSTEM), TEXT_USER),
+            ModelId::SakuraGalTransl7Bv3_7 | ModelId::Sakura1_5bQwen2_5v1_0 => {
+                Self::new(Some(SAKURA_SYSTEM), SAKURA_USER)
+            }
+            ModelId::HunyuanMT7B => Self::new(None, HUNYUAN_USER),
+            // general chat models, without a translation prompt of their own
+            ModelId::Qwen3_4B
+            | ModelId::Gemma3_4B
+            | ModelId::Gemma2_2B
+            | ModelId::MistralNemo12B => Self::generic(None),
+        }
+    }
+
+    /// Generic translation instructions, or `system` instead of them, followed by the target
+    /// language, the numbering rules and the glossary.
+    pub fn generic(system: Option<&str>) -> Self {
+        let system = match system.map(str::trim).filter(|system| !system.is_empty()) {
+            Some(system) => format!("{system}\n\n{FORMAT_SUFFIX}"),
+            None => format!("{GENERIC_SYSTEM} {FORMAT_SUFFIX}"),
+        };
+        Self {
+            system: Some(format!("{system}{GLOSSARY_SUFFIX}")),
+            user: TEXT_USER.to_string(),
+        }
+    }
+
+    /// Fail on templates that don't compile, before they are used for a translation.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let env = Environment::new();
+        if let Some(system) = &self.system {
+            env.template_from_str(system)
+                .map_err(|err| anyhow::anyhow!("invalid system template: {err}"))?;
+        }
+        env.template_from_str(&self.user)
+            .map_err(|err| anyhow::anyhow!("invalid user template: {err}"))?;
+        Ok(())
+    }
+
+    /// The system message, the replayed history and the message for `text`, with plain chat roles.
+    pub fn messages(
+        &self,
+        text: &str,
+        context: &PromptContext,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let env = Environment::new();
+        let language = match context.language {
+            Some(language) => find_language(language).unwrap_or(language).to_string(),
+            None => PromptStyle::Generic { system: None }.target_language(None)?,
+        };
+        let render =
+            |template: &str, text: &str, glossary: &[GlossaryEntry], history: &[ContextTurn]| {
+                env.render_str(
+                    template,
+                    context! {
+                        text => text,
+                        language => language,
+                        glossary => glossary,
+                        context => history,
+                    },
+                )
+                .map_err(anyhow::Error::msg)
+            };
+
+        let mut messages = Vec::new();
+        if let Some(system) = &self.system {
+            let system = render(system, text, context.glossary, context.history)?;
+            messages.push(ChatMessage::new(ChatRole::System, system));
+        }
+        for turn in context.history {
+            let source = render(&self.user, &turn.source, &[], &[])?;
+            messages.push(ChatMessage::new(ChatRole::User, source));
+            messages.push(ChatMessage::new(
+                ChatRole::Assistant,
+                turn.translation.as_str(),
+            ));
+        }
+        let user = render(&self.user, text, context.glossary, context.history)?;
+        messages.push(ChatMessage::new(ChatRole::User, user));
+        Ok(messages)
+    }
+}
+
+/// The built-in prompts, under the names they are offered as editable profiles.
+pub fn default_profiles() -> Vec<(&'static str, PromptProfile)> {
+    vec![
+        ("generic", PromptProfile::generic(None)),
+        (
+            "sakura",
+            PromptProfile::builtin(&ModelId::SakuraGalTransl7Bv3_7.into()),
+        ),
+        (
+            "hunyuan-mt",
+            PromptProfile::builtin(&ModelId::HunyuanMT7B.into()),
+        ),
+        (
+            "lfm2",
+            PromptProfile::builtin(&ModelId::Lfm2_350mEnjpMt.into()),
+        ),
+        (
+            "vntl",
+            PromptProfile::builtin(&ModelId::VntlLlama3_8Bv2.into()),
+        ),
+    ]
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct ChatMessage {
     pub role: ChatRole,
@@ -74,78 +312,165 @@ impl ChatMessage {
     }
 }
 
+/// The Python methods chat templates call on strings and dicts, which MiniJinja doesn't have.
+fn python_method(
+    state: &State,
+    value: &Value,
+    method: &str,
+    args: &[Value],
+) -> Result<Value, Error> {
+    if value.kind() == ValueKind::Map {
+        return match method {
+            "items" => state.apply_filter("items", std::slice::from_ref(value)),
+            "get" => {
+                let item = value.get_item(args.first().unwrap_or(&Value::UNDEFINED))?;
+                Ok(match item.is_undefined() {
+                    true => args.get(1).cloned().unwrap_or(Value::from(())),
+                    false => item,
+                })
+            }
+            _ => Err(Error::from(ErrorKind::UnknownMethod)),
+        };
+    }
+    let Some(text) = value.as_str() else {
+        return Err(Error::from(ErrorKind::UnknownMethod));
+    };
+    let arg = |index: usize| args.get(index).and_then(Value::as_str);
+    let trimmed = |chars: Option<&str>, c: char| match chars {
+        Some(chars) => chars.contains(c),
+        None => c.is_whitespace(),
+    };
+    Ok(match method {
+        "startswith" => Value::from(arg(0).is_some_and(|prefix| text.starts_with(prefix))),
+        "endswith" => Value::from(arg(0).is_some_and(|suffix| text.ends_with(suffix))),
+        "strip" => Value::from(text.trim_matches(|c| trimmed(arg(0), c))),
+        "lstrip" => Value::from(text.trim_start_matches(|c| trimmed(arg(0), c))),
+        "rstrip" => Value::from(text.trim_end_matches(|c| trimmed(arg(0), c))),
+        "split" => Value::from(match arg(0) {
+            Some(separator) => text.split(separator).map(Value::from).collect::<Vec<_>>(),
+            None => text.split_whitespace().map(Value::from).collect(),
+        }),
+        "upper" => Value::from(text.to_uppercase()),
+        "lower" => Value::from(text.to_lowercase()),
+        "replace" => match (arg(0), arg(1)) {
+            (Some(from), Some(to)) => Value::from(text.replace(from, to)),
+            _ => return Err(Error::from(ErrorKind::MissingArgument)),
+        },
+        _ => return Err(Error::from(ErrorKind::UnknownMethod)),
+    })
+}
+
 // Chat template renderer using MiniJinja
 pub struct PromptRenderer {
     env: Environment<'static>,
-    model_id: ModelId,
+    style: PromptStyle,
     template: String,
     bos_token: String,
     eos_token: String,
 }
 
 impl PromptRenderer {
-    pub fn new(model_id: ModelId, template: String, bos_token: String, eos_token: String) -> Self {
+    pub fn new(
+        style: impl Into<PromptStyle>,
+        template: String,
+        bos_token: String,
+        eos_token: String,
+    ) -> Self {
         let mut env = Environment::new();
 
         // Add custom filters that are commonly used in chat templates
         env.add_filter("trim", |s: String| s.trim().to_string());
+        env.add_function(
+            "raise_exception",
+            |message: String| -> Result<Value, Error> {
+                Err(Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+        env.set_unknown_method_callback(python_method);
 
         Self {
             env,
-            model_id,
+            style: style.into(),
             template,
             bos_token,
             eos_token,
         }
     }
 
-    fn messages(&self, text: impl Into<String>) -> Vec<ChatMessage> {
-        match self.model_id {
-            // refer: https://huggingface.co/lmg-anon/vntl-llama3-8b-v2-gguf#translation-prompt
-            ModelId::VntlLlama3_8Bv2 => vec![
-                ChatMessage::new(ChatRole::Name("Japanese".to_string()), text),
-                ChatMessage::new(ChatRole::Name("English".to_string()), String::new()),
-            ],
-            ModelId::Lfm2_350mEnjpMt => vec![
-                ChatMessage::new(
-                    ChatRole::System,
-                    "Translate to English, do not add any explanations, do not add or delete line breaks.",
-                ),
-                ChatMessage::new(ChatRole::User, text),
-            ],
-            ModelId::SakuraGalTransl7Bv3_7 | ModelId::Sakura1_5bQwen2_5v1_0 => vec![
-                ChatMessage::new(
-                    ChatRole::System,
-                    "你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。",
-                ),
-                ChatMessage::new(ChatRole::User, text),
-            ],
-            ModelId::HunyuanMT7B => vec![ChatMessage::new(
-                ChatRole::User,
-                format!(
-                    "Translate the following light novel dialog into {}, without additional explanation.\n\n{}",
-                    get_default_locale(),
-                    text.into(),
-                ),
-            )],
+    pub fn style(&self) -> &PromptStyle {
+        &self.style
+    }
+
+    pub fn bos_token(&self) -> &str {
+        &self.bos_token
+    }
+
+    fn is_vntl(&self) -> bool {
+        self.style == PromptStyle::Builtin(ModelId::VntlLlama3_8Bv2)
+    }
+
+    /// Earlier turns are replayed as if the model had answered them, so it picks up names and tone.
+    fn messages(&self, text: &str, context: &PromptContext) -> anyhow::Result<Vec<ChatMessage>> {
+        let language = self.style.target_language(context.language)?;
+        let context = &PromptContext {
+            language: Some(&language),
+            ..*context
+        };
+        let mut messages = match context.profile {
+            Some(profile) => profile.messages(text, context)?,
+            None => PromptProfile::builtin(&self.style).messages(text, context)?,
+        };
+
+        // refer: https://huggingface.co/lmg-anon/vntl-llama3-8b-v2-gguf#translation-prompt
+        if self.is_vntl() {
+            for message in &mut messages {
+                message.role = match &message.role {
+                    ChatRole::User => ChatRole::Name("Japanese".to_string()),
+                    ChatRole::Assistant => ChatRole::Name("English".to_string()),
+                    role => role.clone(),
+                };
+            }
+            // vntl continues an open `English` turn instead of using a generation prompt
+            messages.push(ChatMessage::new(ChatRole::Name("English".to_string()), ""));
         }
+        Ok(messages)
     }
 
-    pub fn format_chat_prompt(&self, prompt: String) -> anyhow::Result<String> {
-        let messages = self.messages(prompt);
+    pub fn format_chat_prompt(
+        &self,
+        prompt: String,
+        context: &PromptContext,
+    ) -> anyhow::Result<String> {
+        let mut messages = self.messages(&prompt, context)?;
         let tmpl = self.env.template_from_str(&self.template)?;
-
-        let prompt = tmpl
-            .render(context! {
+        let render = |messages: &[ChatMessage]| {
+            tmpl.render(context! {
                 messages => messages,
                 bos_token => self.bos_token,
                 eos_token => self.eos_token,
-                add_generation_prompt => !matches!(self.model_id, ModelId::VntlLlama3_8Bv2),
+                add_generation_prompt => !self.is_vntl(),
+                // qwen3 answers right away instead of reasoning first
+                enable_thinking => false,
             })
-            .map_err(anyhow::Error::msg);
+        };
+
+        let prompt = match render(&messages) {
+            // some templates, such as Mistral's, only accept alternating user and assistant turns
+            Err(err)
+                if err.kind() == ErrorKind::InvalidOperation
+                    && messages.len() > 1
+                    && messages[0].role == ChatRole::System =>
+            {
+                let system = messages.remove(0);
+                messages[0].content = format!("{}\n\n{}", system.content, messages[0].content);
+                render(&messages)
+            }
+            prompt => prompt,
+        }
+        .map_err(anyhow::Error::msg);
 
         // hotfix the vntl-llama3-8b-v2 extra eos_token issue
-        if self.model_id == ModelId::VntlLlama3_8Bv2 {
+        if self.is_vntl() {
             prompt.map(|s| s.trim_end_matches("<|eot_id|>").to_string())
         } else {
             prompt
@@ -166,7 +491,8 @@ mod tests {
             "<|begin_of_text|>".to_string(),
             "<|end_of_text|>".to_string(),
         );
-        let formatted = renderer.format_chat_prompt("こんにちは".to_string())?;
+        let formatted =
+            renderer.format_chat_prompt("こんにちは".to_string(), &PromptContext::default())?;
         let expected = "<|begin_of_text|><|start_header_id|>Metadata<|end_header_id|>\n\n<|eot_id|><|start_header_id|>Japanese<|end_header_id|>\n\nこんにちは<|eot_id|><|start_header_id|>English<|end_header_id|>\n\n";
         assert_eq!(formatted, expected);
 
@@ -182,7 +508,8 @@ mod tests {
             "<|begin_of_text|>".to_string(),
             "<|end_of_text|>".to_string(),
         );
-        let formatted = renderer.format_chat_prompt("こんにちは".to_string())?;
+        let formatted =
+            renderer.format_chat_prompt("こんにちは".to_string(), &PromptContext::default())?;
         let expected = "<|begin_of_text|><|im_start|>system Translate to English, do not add any explanations, do not add or delete line breaks.<|im_end|> <|im_start|>user こんにちは<|im_end|> <|im_start|>assistant ";
         assert_eq!(formatted, expected);
 
@@ -198,10 +525,186 @@ mod tests {
             "<s>".to_string(),
             "</s>".to_string(),
         );
-        let formatted = renderer.format_chat_prompt("こんにちは".to_string())?;
+        let formatted =
+            renderer.format_chat_prompt("こんにちは".to_string(), &PromptContext::default())?;
         let expected = "<|im_start|>system 你是一个视觉小说翻译模型，可以通顺地使用给定的术语表以指定的风格将日文翻译成简体中文，并联系上下文正确使用人称代词，注意不要混淆使役态和被动态的主语和宾语，不要擅自添加原文中没有的特殊符号，也不要擅自增加或减少换行。<|im_end|> <|im_start|>user こんにちは<|im_end|> <|im_start|>assistant ";
         assert_eq!(formatted, expected);
 
         Ok(())
     }
+
+    #[test]
+    fn sakura_glossary_prompt_format() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            ModelId::Sakura1_5bQwen2_5v1_0,
+            r#"{% for message in messages %}{{ message['role'] + ': ' + message['content'] + ' ' }}{% endfor %}"#.to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let glossary = [
+            GlossaryEntry {
+                source: "コハル".to_string(),
+                target: "小春".to_string(),
+                notes: Some("女性".to_string()),
+            },
+            GlossaryEntry {
+                source: "先生".to_string(),
+                target: "老师".to_string(),
+                notes: None,
+            },
+        ];
+        let context = PromptContext {
+            glossary: &glossary,
+            ..Default::default()
+        };
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string(), &context)?;
+        assert!(formatted.contains(
+            "user: 参考以下术语表（可为空，格式为src->dst #备注）：\nコハル->小春 #女性\n先生->老师\n根据以上术语表的对应关系和备注，将下面的文本从日文翻译成简体中文：\nこんにちは "
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_is_replayed_as_chat() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            ModelId::VntlLlama3_8Bv2,
+            r#"{% for message in messages %}[{{ message['role'] }}] {{ message['content'] }} {% endfor %}"#.to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let history = [ContextTurn {
+            source: "おはよう".to_string(),
+            translation: "Morning.".to_string(),
+        }];
+        let context = PromptContext {
+            history: &history,
+            ..Default::default()
+        };
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string(), &context)?;
+        assert_eq!(
+            formatted,
+            "[Japanese] おはよう [English] Morning. [Japanese] こんにちは [English]  "
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn generic_prompt_lists_glossary_after_system_prompt() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            PromptStyle::Generic {
+                system: Some("Translate into English.".to_string()),
+            },
+            "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}"
+                .to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let glossary = [GlossaryEntry {
+            source: "コハル".to_string(),
+            target: "Koharu".to_string(),
+            notes: Some("girl".to_string()),
+        }];
+        let context = PromptContext {
+            glossary: &glossary,
+            language: Some("English"),
+            ..Default::default()
+        };
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string(), &context)?;
+        assert_eq!(
+            formatted,
+            "system: Translate into English.\n\nTranslate the user's text into English. Keep the numbered lines and their numbers, one line per number, and do not add explanations.\n\nAlways translate these terms as given:\nコハル -> Koharu (girl)\nuser: こんにちは\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn target_language_is_checked_against_the_model() {
+        let hunyuan = PromptStyle::Builtin(ModelId::HunyuanMT7B);
+        assert_eq!(hunyuan.target_language(Some("ja")).unwrap(), "日本語");
+        assert_eq!(hunyuan.target_language(Some("pt-BR")).unwrap(), "Português");
+        assert_eq!(hunyuan.target_language(Some("Deutsch")).unwrap(), "Deutsch");
+
+        let sakura = PromptStyle::Builtin(ModelId::SakuraGalTransl7Bv3_7);
+        assert_eq!(sakura.target_language(None).unwrap(), "简体中文");
+        let err = sakura.target_language(Some("en")).unwrap_err();
+        assert_eq!(err.language, "en");
+        assert_eq!(err.supported, ["简体中文"]);
+
+        let generic = PromptStyle::Generic { system: None };
+        assert!(generic.target_language(Some("Klingon")).is_err());
+    }
+
+    #[test]
+    fn custom_profile_sees_every_variable() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            ModelId::HunyuanMT7B,
+            "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}"
+                .to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let profile = PromptProfile::new(
+            Some(
+                "Casual {{ language }}. {% for entry in glossary %}{{ entry.source }}={{ entry.target }}{% endfor %}",
+            ),
+            "{% if context %}after {{ context | length }} turns: {% endif %}{{ text }}",
+        );
+        profile.validate()?;
+        let glossary = [GlossaryEntry {
+            source: "先輩".to_string(),
+            target: "senpai".to_string(),
+            notes: None,
+        }];
+        let history = [ContextTurn {
+            source: "おはよう".to_string(),
+            translation: "Morning.".to_string(),
+        }];
+        let context = PromptContext {
+            glossary: &glossary,
+            history: &history,
+            profile: Some(&profile),
+            language: Some("en"),
+        };
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string(), &context)?;
+        assert_eq!(
+            formatted,
+            "system: Casual English. 先輩=senpai\nuser: おはよう\nassistant: Morning.\nuser: after 1 turns: こんにちは\n"
+        );
+
+        assert!(
+            PromptProfile::new(None, "{% if text %}")
+                .validate()
+                .is_err()
+        );
+        for (name, profile) in default_profiles() {
+            assert!(profile.validate().is_ok(), "{name} should compile");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mistral_template_gets_system_prompt_folded_into_user_turn() -> anyhow::Result<()> {
+        let renderer = PromptRenderer::new(
+            PromptStyle::Generic {
+                system: Some("Translate into English.".to_string()),
+            },
+            r#"{{ bos_token }}{% for message in messages %}{% if message['role'].startswith('sys') %}{{ raise_exception('Only user and assistant roles are supported!') }}{% elif message['role'] == 'user' %}[INST] {{ message['content'].split('\n') | join(' / ') }} [/INST]{% else %}{{ message['content'].strip() }}{{ eos_token }}{% endif %}{% endfor %}"#.to_string(),
+            "<s>".to_string(),
+            "</s>".to_string(),
+        );
+        let context = PromptContext {
+            language: Some("English"),
+            ..Default::default()
+        };
+        let formatted = renderer.format_chat_prompt("こんにちは".to_string(), &context)?;
+        assert_eq!(
+            formatted,
+            "<s>[INST] Translate into English. /  / Translate the user's text into English. Keep the numbered lines and their numbers, one line per number, and do not add explanations. /  / こんにちは [/INST]"
+        );
+
+        Ok(())
+    }
 }
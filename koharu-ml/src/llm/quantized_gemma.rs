@@ -0,0 +1,457 @@
+//! Gemma 2 and Gemma 3 model implementation with quantization support.
+//!
+//! Both alternate sliding window attention layers with global ones and normalize the output
+//! of attention and feed-forward blocks as well as their input.
+//!
+//! Key characteristics:
+//! - Group Query Attention (GQA)
+//! - RMSNorm before and after each block, GELU gated feed-forward
+//! - Gemma 2: attention and final logits soft-capped, every other layer sliding
+//! - Gemma 3: queries and keys normalized per head, five sliding layers per global one,
+//!   with their own rotary base
+//!
+//! References:
+//! - [Gemma 2](https://huggingface.co/google/gemma-2-2b-it)
+//! - [Gemma 3](https://huggingface.co/google/gemma-3-4b-it)
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use candle_core::{
+    DType, Device, IndexOp, Result, Tensor,
+    quantized::{QMatMul, gguf_file},
+};
+use candle_nn::{Embedding, Module};
+use candle_transformers::{quantized_nn::RmsNorm, utils::repeat_kv};
+
+use crate::llm::mask::{CausalMasks, masked_fill, padding_mask};
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    feed_forward_gate: QMatMul,
+    feed_forward_up: QMatMul,
+    feed_forward_down: QMatMul,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = self.feed_forward_gate.forward(xs)?.gelu()?;
+        let up = self.feed_forward_up.forward(xs)?;
+        self.feed_forward_down.forward(&(gate * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    /// Gemma 3 only.
+    attention_q_norm: Option<RmsNorm>,
+    attention_k_norm: Option<RmsNorm>,
+    attention_norm: RmsNorm,
+    post_attention_norm: RmsNorm,
+    ffn_norm: RmsNorm,
+    post_ffn_norm: RmsNorm,
+    mlp: Mlp,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    query_scale: f64,
+    attn_logit_softcapping: Option<f64>,
+    sliding_window: Option<usize>,
+    cos: Tensor,
+    sin: Tensor,
+    neg_inf: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+    span_attn: tracing::Span,
+    span_rot: tracing::Span,
+    span_mlp: tracing::Span,
+}
+
+fn softcap(xs: &Tensor, cap: f64) -> Result<Tensor> {
+    (xs / cap)?.tanh()? * cap
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let _enter = self.span_rot.enter();
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (b_sz, seq_len, _n_embd) = x.dims3()?;
+
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = match &self.attention_q_norm {
+            Some(norm) => norm.forward(&q)?,
+            None => q,
+        };
+        let k = match &self.attention_k_norm {
+            Some(norm) => norm.forward(&k)?,
+            None => k,
+        };
+
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((k_cache, v_cache)) => {
+                if index_pos == 0 {
+                    (k, v)
+                } else {
+                    let k = Tensor::cat(&[k_cache, &k], 2)?;
+                    let v = Tensor::cat(&[v_cache, &v], 2)?;
+                    (k, v)
+                }
+            }
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
+        let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+        let att = (q.matmul(&k.t()?)? * self.query_scale)?;
+        let att = match self.attn_logit_softcapping {
+            Some(cap) => softcap(&att, cap)?,
+            None => att,
+        };
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, &self.neg_inf)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y
+            .transpose(1, 2)?
+            .reshape(&[b_sz, seq_len, self.n_head * self.head_dim])?;
+        self.attention_wo.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    embedding_length: usize,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    final_logit_softcapping: Option<f64>,
+    masks: CausalMasks,
+    span: tracing::Span,
+    span_output: tracing::Span,
+}
+
+/// Rotary tables for positions scaled by `1 / scale`, as linear RoPE scaling does.
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    scale: f32,
+    context_length: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = (Tensor::arange(0, context_length as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((context_length, 1))?
+        / scale as f64)?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let arch = match ct.metadata.get("general.architecture") {
+            Some(v) => v.to_string()?.clone(),
+            None => candle_core::bail!("cannot find general.architecture in metadata"),
+        };
+        let gemma3 = match arch.as_str() {
+            "gemma2" => false,
+            "gemma3" => true,
+            _ => candle_core::bail!("unsupported gemma architecture {arch}"),
+        };
+        let md_get = |s: &str| {
+            let key = format!("{arch}.{s}");
+            match ct.metadata.get(&key) {
+                None => candle_core::bail!("cannot find {key} in metadata"),
+                Some(v) => Ok(v),
+            }
+        };
+
+        let head_count = md_get("attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("embedding_length")?.to_u32()? as usize;
+        let context_length = md_get("context_length")?.to_u32()? as usize;
+        let head_dim = md_get("attention.key_length")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let sliding_window = md_get("attention.sliding_window")?.to_u32()? as usize;
+        let rope_freq_base = md_get("rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(if gemma3 { 1_000_000f32 } else { 10_000f32 });
+        let rope_scale = md_get("rope.scaling.factor")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(1f32);
+        let attn_logit_softcapping = md_get("attn_logit_softcapping")
+            .and_then(|m| m.to_f32())
+            .ok()
+            .map(f64::from);
+        let final_logit_softcapping = md_get("final_logit_softcapping")
+            .and_then(|m| m.to_f32())
+            .ok()
+            .map(f64::from);
+
+        // like llama.cpp, the 27B models scale queries by the width per head rather than the head size
+        let query_pre_attn_scalar = match (gemma3, block_count) {
+            (false, 46) | (true, 62) => embedding_length / head_count,
+            _ => head_dim,
+        };
+        let query_scale = 1. / (query_pre_attn_scalar as f64).sqrt();
+
+        // global layers use the model's rotary base and scaling, sliding ones a fixed base
+        let (cos, sin) =
+            precomput_freqs_cis(head_dim, rope_freq_base, rope_scale, context_length, device)?;
+        let (cos_sliding, sin_sliding) = if gemma3 {
+            precomput_freqs_cis(head_dim, 10_000f32, 1f32, context_length, device)?
+        } else {
+            (cos.clone(), sin.clone())
+        };
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+        let tok_embeddings_q = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        let output = match ct.tensor(reader, "output.weight", device) {
+            Ok(tensor) => tensor,
+            Err(_) => tok_embeddings_q,
+        };
+
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let mut rms_norm = |name: &str| {
+                RmsNorm::from_qtensor(
+                    ct.tensor(reader, &format!("{prefix}.{name}.weight"), device)?,
+                    rms_norm_eps,
+                )
+            };
+            let attention_norm = rms_norm("attn_norm")?;
+            let post_attention_norm = rms_norm("post_attention_norm")?;
+            let ffn_norm = rms_norm("ffn_norm")?;
+            let post_ffn_norm = rms_norm("post_ffw_norm")?;
+            let (attention_q_norm, attention_k_norm) = if gemma3 {
+                (
+                    Some(rms_norm("attn_q_norm")?),
+                    Some(rms_norm("attn_k_norm")?),
+                )
+            } else {
+                (None, None)
+            };
+
+            let mut qmatmul = |name: &str| {
+                QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.{name}.weight"),
+                    device,
+                )?)
+            };
+            let attention_wq = qmatmul("attn_q")?;
+            let attention_wk = qmatmul("attn_k")?;
+            let attention_wv = qmatmul("attn_v")?;
+            let attention_wo = qmatmul("attn_output")?;
+            let mlp = Mlp {
+                feed_forward_gate: qmatmul("ffn_gate")?,
+                feed_forward_up: qmatmul("ffn_up")?,
+                feed_forward_down: qmatmul("ffn_down")?,
+            };
+
+            let is_sliding = if gemma3 {
+                (layer_idx + 1) % 6 != 0
+            } else {
+                layer_idx % 2 == 0
+            };
+            let (cos, sin) = if is_sliding {
+                (cos_sliding.clone(), sin_sliding.clone())
+            } else {
+                (cos.clone(), sin.clone())
+            };
+
+            let span_attn = tracing::span!(tracing::Level::TRACE, "attn");
+            let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
+            let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
+            layers.push(LayerWeights {
+                attention_wq,
+                attention_wk,
+                attention_wv,
+                attention_wo,
+                attention_q_norm,
+                attention_k_norm,
+                attention_norm,
+                post_attention_norm,
+                ffn_norm,
+                post_ffn_norm,
+                mlp,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                query_scale,
+                attn_logit_softcapping,
+                sliding_window: is_sliding.then_some(sliding_window),
+                cos,
+                sin,
+                neg_inf: neg_inf.clone(),
+                kv_cache: None,
+                span_attn,
+                span_rot,
+                span_mlp,
+            })
+        }
+
+        let span = tracing::span!(tracing::Level::TRACE, "model");
+        let span_output = tracing::span!(tracing::Level::TRACE, "output");
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            embedding_length,
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            final_logit_softcapping,
+            masks: CausalMasks::default(),
+            span,
+            span_output,
+        })
+    }
+
+    /// The mask of the layers attending to `window`, `None` when every position is visible.
+    fn layer_mask(
+        &mut self,
+        t: usize,
+        index_pos: usize,
+        padding: Option<&Tensor>,
+        window: Option<usize>,
+        device: &Device,
+    ) -> Result<Option<Tensor>> {
+        let windowed = window.is_some_and(|w| index_pos + t > w);
+        let causal = if t > 1 || windowed {
+            Some(self.masks.get(t, index_pos, window, device)?)
+        } else {
+            None
+        };
+        Ok(match (padding, causal) {
+            (Some(padding), Some(causal)) => Some(padding.broadcast_maximum(&causal)?),
+            (Some(padding), None) => Some(padding.clone()),
+            (None, causal) => causal,
+        })
+    }
+
+    /// Forget every cached position, so the next forward pass starts a new sequence.
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.kv_cache = None;
+        }
+    }
+
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward_padded(x, index_pos, &[])
+    }
+
+    /// Like [`ModelWeights::forward`] for a batch of sequences where row `i` is left-padded
+    /// with `padding[i]` tokens, which the rest of the row doesn't attend to.
+    pub fn forward_padded(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        padding: &[usize],
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let padding = if padding.iter().any(|&pad| pad > 0) {
+            Some(padding_mask(seq_len, index_pos, padding, x.device())?)
+        } else {
+            None
+        };
+        let mut masks = HashMap::new();
+        let windows: HashSet<_> = self
+            .layers
+            .iter()
+            .map(|layer| layer.sliding_window)
+            .collect();
+        for window in windows {
+            let mask = self.layer_mask(seq_len, index_pos, padding.as_ref(), window, x.device())?;
+            masks.insert(window, mask);
+        }
+
+        let _enter = self.span.enter();
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        layer_in = (layer_in * (self.embedding_length as f64).sqrt())?;
+        for layer in self.layers.iter_mut() {
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let mask = masks.get(&layer.sliding_window).and_then(Option::as_ref);
+            let x = layer.forward_attn(&x, mask, index_pos)?;
+            let x = layer.post_attention_norm.forward(&x)?;
+            let x = (x + residual)?;
+
+            // MLP
+            let _enter = layer.span_mlp.enter();
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = layer.post_ffn_norm.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let _enter = self.span_output.enter();
+        let logits = self.output.forward(&x)?;
+        match self.final_logit_softcapping {
+            Some(cap) => softcap(&logits, cap),
+            None => Ok(logits),
+        }
+    }
+}
@@ -16,14 +16,14 @@
 //! ![](https://raw.githubusercontent.com/huggingface/candle/main/candle-examples/examples/quantized/assets/aoc.gif)
 //!
 
-use std::collections::HashMap;
-
 use candle_core::quantized::QTensor;
 use candle_core::quantized::{ggml_file, gguf_file};
 use candle_core::{DType, Device, IndexOp, Result, Tensor, bail};
 use candle_nn::{Embedding, Module};
 use candle_transformers::quantized_nn::RmsNorm;
 
+use crate::llm::mask::{CausalMasks, masked_fill, padding_mask};
+
 pub const MAX_SEQ_LEN: usize = 4096;
 
 fn get_qtensor<R: std::io::Seek + std::io::Read>(
@@ -189,12 +189,6 @@ struct LayerWeights {
     span_mlp: tracing::Span,
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
-    let shape = mask.shape();
-    let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
-    Ok(m)
-}
-
 impl LayerWeights {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
@@ -251,7 +245,7 @@ impl LayerWeights {
         };
         self.kv_cache = Some((k.clone(), v.clone()));
 
-        let y = if q.device().is_metal() && seq_len == 1 {
+        let y = if q.device().is_metal() && seq_len == 1 && mask.is_none() {
             // SDPA will do MQA for us
             candle_nn::ops::sdpa(
                 &q,
@@ -292,7 +286,7 @@ pub struct ModelWeights {
     layers: Vec<LayerWeights>,
     norm: RmsNorm,
     output: QMatMul,
-    masks: HashMap<usize, Tensor>,
+    masks: CausalMasks,
     span: tracing::Span,
     span_output: tracing::Span,
 }
@@ -392,7 +386,7 @@ impl ModelWeights {
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
-            masks: HashMap::new(),
+            masks: CausalMasks::default(),
             span,
             span_output,
         })
@@ -566,31 +560,38 @@ impl ModelWeights {
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
-            masks: HashMap::new(),
+            masks: CausalMasks::default(),
             span,
             span_output,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
+    /// Forget every cached position, so the next forward pass starts a new sequence.
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.kv_cache = None;
         }
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward_padded(x, index_pos, &[])
+    }
+
+    /// Like [`ModelWeights::forward`] for a batch of sequences where row `i` is left-padded
+    /// with `padding[i]` tokens, which the rest of the row doesn't attend to.
+    pub fn forward_padded(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        padding: &[usize],
+    ) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
-        let mask = if seq_len == 1 {
+        let mask = if padding.iter().any(|&pad| pad > 0) {
+            Some(padding_mask(seq_len, index_pos, padding, x.device())?)
+        } else if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.masks.get(seq_len, index_pos, None, x.device())?)
         };
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use candle_core::quantized::QMatMul;
 use candle_core::quantized::gguf_file;
 use candle_core::{DType, Device, IndexOp, Result, Tensor, bail};
@@ -7,6 +5,8 @@ use candle_nn::{Conv1d, Conv1dConfig, Embedding, Module};
 use candle_transformers::quantized_nn::RmsNorm;
 use candle_transformers::utils::repeat_kv;
 
+use crate::llm::mask::{CausalMasks, masked_fill};
+
 fn get_qtensor<R: std::io::Seek + std::io::Read>(
     ct: &gguf_file::Content,
     reader: &mut R,
@@ -89,12 +89,6 @@ struct LayerWeights {
     span_mlp: tracing::Span,
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
-    let shape = mask.shape();
-    let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
-    Ok(m)
-}
-
 fn precomput_freqs_cis(
     head_dim: usize,
     freq_base: f32,
@@ -183,7 +177,7 @@ impl AttentionLayer {
 }
 
 impl ShortConvLayer {
-    fn forward(&mut self, xs: &Tensor, _index_pos: usize) -> Result<Tensor> {
+    fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
         let (b_sz, seq_len, hidden) = xs.dims3()?;
         let bcx = self.in_proj.forward(xs)?.transpose(1, 2)?;
         let b = bcx.narrow(1, 0, hidden)?;
@@ -200,13 +194,14 @@ impl ShortConvLayer {
         }
         let conv_weight = conv_weight.contiguous()?;
 
-        let mut conv_out = if seq_len == 1 {
-            let mut state = if let Some(cache) = &self.cache {
-                cache.clone()
-            } else {
-                Tensor::zeros((b_sz, hidden, self.l_cache), bx.dtype(), bx.device())?
-            };
+        // the cache holds the last `l_cache` inputs, a new sequence starts from zeros
+        let cache = match &self.cache {
+            Some(cache) if index_pos > 0 => cache.clone(),
+            _ => Tensor::zeros((b_sz, hidden, self.l_cache), bx.dtype(), bx.device())?,
+        };
 
+        let mut conv_out = if seq_len == 1 {
+            let mut state = cache;
             if self.l_cache > 1 {
                 let tail = state.narrow(2, 1, self.l_cache - 1)?;
                 state = Tensor::cat(&[tail, bx.clone()], 2)?;
@@ -225,26 +220,17 @@ impl ShortConvLayer {
                     .contiguous()?,
                 None,
                 Conv1dConfig {
-                    padding: self.l_cache.saturating_sub(1),
                     groups: hidden,
                     ..Default::default()
                 },
             );
-            let mut out = conv.forward(&bx.contiguous()?)?;
-            out = out.narrow(2, 0, seq_len)?;
-
-            if self.l_cache > 0 {
-                let (_, _, cur_len) = bx.dims3()?;
-                let start = cur_len.saturating_sub(self.l_cache);
-                let mut cache_src = bx.narrow(2, start, cur_len - start)?;
-                if cache_src.dims3()?.2 < self.l_cache {
-                    let pad = self.l_cache - cache_src.dims3()?.2;
-                    let zeros =
-                        Tensor::zeros((b_sz, hidden, pad), cache_src.dtype(), cache_src.device())?;
-                    cache_src = Tensor::cat(&[zeros, cache_src], 2)?;
-                }
-                self.cache = Some(cache_src);
-            }
+            // continue from the inputs before this chunk instead of padding
+            let history = cache.narrow(2, 1, self.l_cache.saturating_sub(1))?;
+            let input = Tensor::cat(&[&history, &bx], 2)?.contiguous()?;
+            let out = conv.forward(&input)?;
+
+            let len = input.dims3()?.2;
+            self.cache = Some(input.narrow(2, len - self.l_cache, self.l_cache)?);
 
             out
         };
@@ -255,12 +241,13 @@ impl ShortConvLayer {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct ModelWeights {
     tok_embeddings: Embedding,
     layers: Vec<LayerWeights>,
     norm: RmsNorm,
     output: QMatMul,
-    masks: HashMap<usize, Tensor>,
+    masks: CausalMasks,
     span: tracing::Span,
     span_output: tracing::Span,
 }
@@ -581,22 +568,19 @@ impl ModelWeights {
             layers,
             norm,
             output: QMatMul::from_qtensor(output_q)?,
-            masks: HashMap::new(),
+            masks: CausalMasks::default(),
             span: tracing::span!(tracing::Level::TRACE, "model"),
             span_output: tracing::span!(tracing::Level::TRACE, "output"),
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
+    /// Forget every cached position, so the next forward pass starts a new sequence.
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            match &mut layer.kind {
+                LayerKind::Attention(attn) => attn.kv_cache = None,
+                LayerKind::ShortConv(conv) => conv.cache = None,
+            }
         }
     }
 
@@ -605,7 +589,7 @@ impl ModelWeights {
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.masks.get(seq_len, index_pos, None, x.device())?)
         };
 
         let _enter = self.span.enter();
@@ -1,12 +1,17 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{Context, Result, bail};
 use candle_core::quantized::gguf_file;
 use tokenizers::{
     AddedToken, Tokenizer,
-    decoders::{DecoderWrapper, byte_level::ByteLevel as ByteLevelDecoder},
-    models::bpe::{BPE, Vocab},
-    normalizers::{NormalizerWrapper, unicode::NFC},
+    decoders::{
+        DecoderWrapper, byte_fallback::ByteFallback, byte_level::ByteLevel as ByteLevelDecoder,
+        fuse::Fuse, sequence::Sequence as DecoderSequence, strip::Strip,
+    },
+    models::bpe::{BPE, Merges, Vocab},
+    normalizers::{
+        NormalizerWrapper, Prepend, Replace, Sequence as NormalizerSequence, unicode::NFC,
+    },
     pre_tokenizers::{
         PreTokenizerWrapper,
         byte_level::ByteLevel as ByteLevelPre,
@@ -18,6 +23,16 @@ use tokenizers::{
     tokenizer::SplitDelimiterBehavior,
 };
 
+/// Tokenize a rendered chat prompt. The tokenizer adds its special tokens only when the
+/// template didn't already spell out `bos`, so the prompt never starts with it twice.
+pub fn encode_chat(tokenizer: &Tokenizer, prompt: &str, bos: &str) -> Result<Vec<u32>> {
+    let add_special_tokens = bos.is_empty() || !prompt.starts_with(bos);
+    let encoding = tokenizer
+        .encode(prompt, add_special_tokens)
+        .map_err(anyhow::Error::msg)?;
+    Ok(encoding.get_ids().to_vec())
+}
+
 pub trait TokenizerFromGguf: Sized {
     fn from_gguf(ct: &gguf_file::Content) -> Result<Self>;
 }
@@ -67,6 +82,36 @@ fn merges_from_value(v: &gguf_file::Value) -> Result<Vec<(String, String)>> {
         .collect()
 }
 
+/// SentencePiece vocabularies only ship scores, so rebuild the BPE merges from them the way
+/// `tokenizers` converts SentencePiece models: every split of a token into two known pieces,
+/// the merges of higher scoring tokens first.
+fn merges_from_scores(tokens: &[String], scores: &[f32]) -> Merges {
+    let vocab: HashMap<&str, usize> = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.as_str(), i))
+        .collect();
+    let mut merges = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if let (Some(&l), Some(&r)) = (vocab.get(left), vocab.get(right)) {
+                merges.push((id, l, r));
+            }
+        }
+    }
+    let score = |id: usize| scores.get(id).copied().unwrap_or(0.0);
+    merges.sort_by(|a, b| {
+        score(b.0)
+            .total_cmp(&score(a.0))
+            .then((a.1, a.2).cmp(&(b.1, b.2)))
+    });
+    merges
+        .into_iter()
+        .map(|(_, l, r)| (tokens[l].clone(), tokens[r].clone()))
+        .collect()
+}
+
 struct Pipeline {
     normalizer: Option<NormalizerWrapper>,
     pretokenizer: Option<PreTokenizerWrapper>,
@@ -104,6 +149,7 @@ fn pre_tokenizer_sequence(regex: &str, byte_level: ByteLevelPre) -> Result<PreTo
 fn pipeline_from_pre(pre: &str) -> Result<Pipeline> {
     const REGEX_QWEN2: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
     const REGEX_LLAMA3: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+    const REGEX_TEKKEN: &str = r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+";
 
     Ok(match pre {
         // Matches Qwen2 tokenizer.json settings
@@ -116,6 +162,16 @@ fn pipeline_from_pre(pre: &str) -> Result<Pipeline> {
             decoder: Some(ByteLevelDecoder::new(false, false, false).into()),
             post_processor: Some(ByteLevelProcessor::new(false, false, false).into()),
         },
+        // Mistral Nemo's tekken tokenizer splits like llama3, but keeps cased words apart
+        "tekken" => Pipeline {
+            normalizer: None,
+            pretokenizer: Some(pre_tokenizer_sequence(
+                REGEX_TEKKEN,
+                ByteLevelPre::new(false, true, false),
+            )?),
+            decoder: Some(ByteLevelDecoder::new(true, true, true).into()),
+            post_processor: Some(ByteLevelProcessor::new(true, false, true).into()),
+        },
         // Matches Smaug/Llama3 style byte-level BPE (used by vntl-llama3-8b-v2 and lfm2)
         "smaug-bpe" | "lfm2" | "llama3" => Pipeline {
             normalizer: None,
@@ -136,6 +192,32 @@ fn pipeline_from_pre(pre: &str) -> Result<Pipeline> {
     })
 }
 
+/// Matches the SentencePiece tokenizer.json of llama and gemma: spaces become `▁`, and unknown
+/// characters fall back to their bytes.
+fn sentencepiece_pipeline(add_space_prefix: bool) -> Result<Pipeline> {
+    let mut normalizers = Vec::new();
+    if add_space_prefix {
+        normalizers.push(Prepend::new("▁".to_string()).into());
+    }
+    normalizers.push(Replace::new(" ", "▁").map_err(anyhow::Error::msg)?.into());
+
+    let mut decoders = vec![
+        Replace::new("▁", " ").map_err(anyhow::Error::msg)?.into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+    ];
+    if add_space_prefix {
+        decoders.push(Strip::new(' ', 1, 0).into());
+    }
+
+    Ok(Pipeline {
+        normalizer: Some(NormalizerSequence::new(normalizers).into()),
+        pretokenizer: None,
+        decoder: Some(DecoderSequence::new(decoders).into()),
+        post_processor: None,
+    })
+}
+
 fn template_processor(
     tokens: &[String],
     bos_id: Option<u32>,
@@ -204,9 +286,6 @@ impl TokenizerFromGguf for Tokenizer {
         let model_kind = metadata_value(ct, "tokenizer.ggml.model")?
             .to_string()?
             .to_lowercase();
-        if model_kind != "gpt2" {
-            bail!("unsupported tokenizer model `{model_kind}`");
-        }
 
         let tokens = value_to_string_array(
             metadata_value(ct, "tokenizer.ggml.tokens")?,
@@ -217,9 +296,23 @@ impl TokenizerFromGguf for Tokenizer {
             .enumerate()
             .map(|(i, t)| (t.clone(), i as u32))
             .collect();
-        let merges = merges_from_value(metadata_value(ct, "tokenizer.ggml.merges")?)?;
+        let merges = match model_kind.as_str() {
+            "gpt2" => merges_from_value(metadata_value(ct, "tokenizer.ggml.merges")?)?,
+            // SentencePiece (llama, mistral, gemma)
+            "llama" => {
+                let scores = metadata_value(ct, "tokenizer.ggml.scores")?
+                    .to_vec()?
+                    .iter()
+                    .map(|v| v.to_f32().map_err(anyhow::Error::msg))
+                    .collect::<Result<Vec<_>>>()?;
+                merges_from_scores(&tokens, &scores)
+            }
+            _ => bail!("unsupported tokenizer model `{model_kind}`"),
+        };
 
-        let mut builder = BPE::builder().vocab_and_merges(vocab, merges);
+        let mut builder = BPE::builder()
+            .vocab_and_merges(vocab, merges)
+            .byte_fallback(model_kind == "llama");
 
         if let Ok(val) = metadata_value(ct, "tokenizer.ggml.unk_token_id")
             && let Some(token) = tokens.get(gguf_value_to_u32(val)? as usize)
@@ -242,7 +335,14 @@ impl TokenizerFromGguf for Tokenizer {
             .and_then(|v| v.to_string().map_err(anyhow::Error::msg))
             .map(|s| s.to_string())
             .unwrap_or_else(|_| "gpt2".to_string());
-        let pipeline = pipeline_from_pre(pre.as_str())?;
+        let pipeline = if model_kind == "llama" {
+            let add_space_prefix = metadata_value(ct, "tokenizer.ggml.add_space_prefix")
+                .and_then(|v| v.to_bool().map_err(anyhow::Error::msg))
+                .unwrap_or(true);
+            sentencepiece_pipeline(add_space_prefix)?
+        } else {
+            pipeline_from_pre(pre.as_str())?
+        };
         let post_processor_base = pipeline.post_processor.clone();
 
         let add_bos = metadata_value(ct, "tokenizer.ggml.add_bos_token")
@@ -320,3 +420,51 @@ impl TokenizerFromGguf for Tokenizer {
         Ok(tokenizer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace};
+
+    use super::*;
+
+    #[test]
+    fn chat_prompts_start_with_one_bos() -> Result<()> {
+        let tokens = ["<s>", "hi", "<unk>"].map(String::from);
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.add_special_tokens(&[AddedToken::from("<s>", true)]);
+        tokenizer.with_post_processor(template_processor(&tokens, Some(0), None, true, false));
+
+        assert_eq!(encode_chat(&tokenizer, "<s> hi", "<s>")?, [0, 1]);
+        assert_eq!(encode_chat(&tokenizer, "hi", "<s>")?, [0, 1]);
+        // templates of models without a bos token get whatever the tokenizer adds
+        assert_eq!(encode_chat(&tokenizer, "hi", "")?, [0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn sentencepiece_merges_follow_scores() {
+        let tokens = ["▁", "a", "b", "ab", "▁a", "▁ab"].map(String::from);
+        let scores = [0.0, 0.0, 0.0, -1.0, -2.0, -3.0];
+        let pair = |l: &str, r: &str| (l.to_string(), r.to_string());
+        assert_eq!(
+            merges_from_scores(&tokens, &scores),
+            vec![
+                pair("a", "b"),
+                pair("▁", "a"),
+                pair("▁", "ab"),
+                pair("▁a", "b"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,459 @@
+//! Classical panel segmentation, on CPU and without model weights.
+//!
+//! The page margin is flood-filled from the page edge to find the gutters, along with the long thin
+//! blank strips that speech bubbles reaching over a gutter cut off from it. The page is then cut
+//! at bands of rows or columns that are mostly gutter, so art reaching over a gutter doesn't merge
+//! two panels, and the outlines of what the gutters enclose are the panels.
+
+use image::{DynamicImage, GrayImage, Luma};
+use imageproc::{
+    contours::{BorderType, find_contours},
+    distance_transform::Norm,
+    geometry::approximate_polygon_dp,
+    morphology::dilate,
+    point::Point,
+    region_labelling::{Connectivity, connected_components},
+};
+use serde::{Deserialize, Serialize};
+
+/// Blank regions cut off from the page margin are still gutter when they are at least this long
+/// and at most this wide on average, as shares of the short side of the page.
+const MIN_GUTTER_LENGTH: f32 = 0.1;
+const MAX_GUTTER_WIDTH: f32 = 0.02;
+
+/// Tuning of panel detection. Missing fields take their default when deserialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PanelOptions {
+    /// How far, out of 255, the luma of a pixel may be from the page margin and still be gutter.
+    pub gutter_tolerance: u8,
+    /// Gaps of up to this many pixels in panel borders are bridged so the gutter doesn't leak in.
+    pub border_gap: u8,
+    /// Share of a row or column, running from gutter to gutter, that has to be gutter for the page
+    /// to be cut there.
+    pub gutter_coverage: f32,
+    /// Smallest panel, as a share of the page area.
+    pub min_area: f32,
+    /// How far outlines may stray when simplified, as a share of the short side of the page.
+    pub simplify: f32,
+}
+
+impl Default for PanelOptions {
+    fn default() -> Self {
+        Self {
+            gutter_tolerance: 40,
+            border_gap: 2,
+            gutter_coverage: 0.25,
+            min_area: 0.01,
+            simplify: 0.005,
+        }
+    }
+}
+
+/// A panel of a page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Panel {
+    /// Outline of the panel in page pixels, the last point joining back to the first.
+    pub polygon: Vec<[u32; 2]>,
+}
+
+impl Panel {
+    /// Extent of the outline as `[x0, y0, x1, y1]`, `x1` and `y1` exclusive.
+    pub fn bbox(&self) -> [u32; 4] {
+        self.polygon
+            .iter()
+            .fold([u32::MAX, u32::MAX, 0, 0], |[x0, y0, x1, y1], &[x, y]| {
+                [x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)]
+            })
+    }
+
+    /// Whether `(x, y)` lies inside the outline.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        let n = self.polygon.len();
+        let mut inside = false;
+        for i in 0..n {
+            let [xi, yi] = self.polygon[i].map(|v| v as f32);
+            let [xj, yj] = self.polygon[(i + n - 1) % n].map(|v| v as f32);
+            if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+/// Panels of `image`, top to bottom.
+pub fn detect_panels(image: &DynamicImage, opts: &PanelOptions) -> Vec<Panel> {
+    panels(&gutters(image, opts), opts)
+}
+
+/// Gutter mask of `image`, 255 on the page margin reached from the page edge and on the pieces
+/// of gutter that art or speech bubbles reaching over it cut off from the margin.
+pub fn gutters(image: &DynamicImage, opts: &PanelOptions) -> GrayImage {
+    let gray = image.to_luma8();
+    let (width, height) = gray.dimensions();
+    if width == 0 || height == 0 {
+        return gray;
+    }
+
+    // works the same for white and black gutters
+    let margin = margin_luma(&gray) as i16;
+    let tolerance = opts.gutter_tolerance as i16;
+    let ink = GrayImage::from_fn(width, height, |x, y| {
+        let luma = gray.get_pixel(x, y).0[0] as i16;
+        Luma([if (luma - margin).abs() > tolerance {
+            255
+        } else {
+            0
+        }])
+    });
+    let ink = dilate(&ink, Norm::LInf, opts.border_gap);
+
+    // area, extent and whether they touch the page edge, of the blank regions
+    let labels = connected_components(&ink, Connectivity::Four, Luma([255u8]));
+    let count = labels.pixels().map(|p| p.0[0]).max().unwrap_or(0) as usize;
+    let mut blanks = vec![Blank::default(); count + 1];
+    for (x, y, label) in labels.enumerate_pixels() {
+        let blank = &mut blanks[label.0[0] as usize];
+        if blank.area == 0 {
+            blank.extent = [x, y, x, y];
+        }
+        blank.area += 1;
+        blank.extent = [
+            blank.extent[0].min(x),
+            blank.extent[1].min(y),
+            blank.extent[2].max(x),
+            blank.extent[3].max(y),
+        ];
+        blank.on_edge |= x == 0 || y == 0 || x == width - 1 || y == height - 1;
+    }
+
+    let short_side = width.min(height) as f32;
+    let is_gutter: Vec<bool> = blanks
+        .iter()
+        .enumerate()
+        .map(|(label, blank)| {
+            let [x0, y0, x1, y1] = blank.extent;
+            let length = (x1 - x0).max(y1 - y0) as f32 + 1.0;
+            label != 0
+                && (blank.on_edge
+                    || (length >= MIN_GUTTER_LENGTH * short_side
+                        && blank.area as f32 / length <= MAX_GUTTER_WIDTH * short_side))
+        })
+        .collect();
+
+    GrayImage::from_fn(width, height, |x, y| {
+        Luma([if is_gutter[labels.get_pixel(x, y).0[0] as usize] {
+            255
+        } else {
+            0
+        }])
+    })
+}
+
+/// Panels enclosed by a `gutters` mask, top to bottom.
+pub fn panels(gutters: &GrayImage, opts: &PanelOptions) -> Vec<Panel> {
+    let (width, height) = gutters.dimensions();
+    let page = Region {
+        x0: 0,
+        y0: 0,
+        x1: width,
+        y1: height,
+    };
+    let short_side = width.min(height) as f32;
+    let rule = GutterLine {
+        coverage: opts.gutter_coverage,
+        reach: (MAX_GUTTER_WIDTH * short_side) as u32,
+    };
+    let mut leaves = Vec::new();
+    cut(gutters, page, &rule, &mut leaves);
+
+    let min_area = opts.min_area * (width as f32) * (height as f32);
+    let epsilon = (opts.simplify * short_side).max(1.0) as f64;
+    leaves
+        .into_iter()
+        .flat_map(|leaf| outlines(gutters, leaf, min_area, epsilon))
+        .collect()
+}
+
+/// Luma of the page margin, the median of the outermost pixels.
+fn margin_luma(gray: &GrayImage) -> u8 {
+    let (width, height) = gray.dimensions();
+    let mut frame: Vec<u8> = (0..width)
+        .flat_map(|x| [(x, 0), (x, height - 1)])
+        .chain((0..height).flat_map(|y| [(0, y), (width - 1, y)]))
+        .map(|(x, y)| gray.get_pixel(x, y).0[0])
+        .collect();
+    frame.sort_unstable();
+    frame[frame.len() / 2]
+}
+
+#[derive(Debug, Clone, Default)]
+struct Blank {
+    area: u32,
+    extent: [u32; 4],
+    on_edge: bool,
+}
+
+/// A rectangle of the page, `x1` and `y1` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+}
+
+/// When a line across a region is gutter.
+struct GutterLine {
+    /// Share of the line that has to be gutter.
+    coverage: f32,
+    /// The line has to meet gutter this close to both of its ends, so that blank strips inside
+    /// a panel don't count.
+    reach: u32,
+}
+
+/// Cut `region` at bands of gutter rows, then of gutter columns, and append the regions no band
+/// crosses to `leaves`.
+fn cut(gutters: &GrayImage, region: Region, rule: &GutterLine, leaves: &mut Vec<Region>) {
+    let Some(region) = trim(gutters, region) else {
+        return;
+    };
+    for axis in [Axis::Y, Axis::X] {
+        let pieces = pieces(gutters, region, axis, rule);
+        match pieces.as_slice() {
+            [] => return,
+            [piece] if *piece == region => continue,
+            _ => {
+                for piece in pieces {
+                    cut(gutters, piece, rule, leaves);
+                }
+                return;
+            }
+        }
+    }
+    leaves.push(region);
+}
+
+/// `region` shrunk to the extent of what isn't gutter in it, if anything.
+fn trim(gutters: &GrayImage, region: Region) -> Option<Region> {
+    let mut extent: Option<Region> = None;
+    for y in region.y0..region.y1 {
+        for x in region.x0..region.x1 {
+            if gutters.get_pixel(x, y).0[0] != 0 {
+                continue;
+            }
+            let e = extent.get_or_insert(Region {
+                x0: x,
+                y0: y,
+                x1: x + 1,
+                y1: y + 1,
+            });
+            e.x0 = e.x0.min(x);
+            e.x1 = e.x1.max(x + 1);
+            e.y1 = y + 1;
+        }
+    }
+    extent
+}
+
+/// Parts of `region` between the bands of gutter lines across `axis`.
+fn pieces(gutters: &GrayImage, region: Region, axis: Axis, rule: &GutterLine) -> Vec<Region> {
+    let (lines, across) = match axis {
+        Axis::Y => (region.y0..region.y1, region.x0..region.x1),
+        Axis::X => (region.x0..region.x1, region.y0..region.y1),
+    };
+    let needed = rule.coverage * across.len() as f32;
+    let reach = rule.reach.clamp(1, across.len() as u32);
+    let is_gutter = |line: u32| {
+        let at = |i: u32| {
+            let (x, y) = match axis {
+                Axis::Y => (i, line),
+                Axis::X => (line, i),
+            };
+            gutters.get_pixel(x, y).0[0] != 0
+        };
+        (across.start..across.start + reach).any(at)
+            && (across.end - reach..across.end).any(at)
+            && across.clone().filter(|&i| at(i)).count() as f32 >= needed
+    };
+
+    let mut pieces = Vec::new();
+    let mut start = None;
+    for line in lines.clone().chain([lines.end]) {
+        match (line == lines.end || is_gutter(line), start) {
+            (false, None) => start = Some(line),
+            (true, Some(from)) => {
+                pieces.push(match axis {
+                    Axis::Y => Region {
+                        y0: from,
+                        y1: line,
+                        ..region
+                    },
+                    Axis::X => Region {
+                        x0: from,
+                        x1: line,
+                        ..region
+                    },
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    pieces
+}
+
+/// Outlines of the parts of `region` that aren't gutter and cover at least `min_area` pixels.
+fn outlines(gutters: &GrayImage, region: Region, min_area: f32, epsilon: f64) -> Vec<Panel> {
+    // padded by a pixel so parts touching the region edge still get a closed outline
+    let (width, height) = (region.x1 - region.x0, region.y1 - region.y0);
+    let content = GrayImage::from_fn(width + 2, height + 2, |x, y| {
+        let inside = (1..=width).contains(&x) && (1..=height).contains(&y);
+        Luma([
+            if inside && gutters.get_pixel(region.x0 + x - 1, region.y0 + y - 1).0[0] == 0 {
+                255
+            } else {
+                0
+            },
+        ])
+    });
+
+    find_contours::<i32>(&content)
+        .into_iter()
+        .filter(|contour| contour.border_type == BorderType::Outer && contour.parent.is_none())
+        .filter(|contour| area(&contour.points) >= min_area)
+        .map(|contour| {
+            let mut points = approximate_polygon_dp(&contour.points, epsilon, true);
+            if points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+            Panel {
+                polygon: points
+                    .into_iter()
+                    .map(|p| {
+                        [
+                            region.x0 + (p.x - 1).max(0) as u32,
+                            region.y0 + (p.y - 1).max(0) as u32,
+                        ]
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Area enclosed by a closed outline.
+fn area(points: &[Point<i32>]) -> f32 {
+    let n = points.len();
+    let twice: i64 = (0..n)
+        .map(|i| {
+            let (a, b) = (points[i], points[(i + 1) % n]);
+            a.x as i64 * b.y as i64 - b.x as i64 * a.y as i64
+        })
+        .sum();
+    twice.abs() as f32 / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+    use imageproc::{
+        drawing::{draw_filled_rect_mut, draw_hollow_rect_mut},
+        rect::Rect,
+    };
+
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+    /// A bordered panel at `x`, `y`, with a thick border.
+    fn panel(page: &mut image::RgbImage, x: i32, y: i32, width: u32, height: u32) {
+        for inset in 0..3 {
+            draw_hollow_rect_mut(
+                page,
+                Rect::at(x + inset, y + inset)
+                    .of_size(width - 2 * inset as u32, height - 2 * inset as u32),
+                BLACK,
+            );
+        }
+    }
+
+    fn bboxes(page: image::RgbImage) -> Vec<[u32; 4]> {
+        detect_panels(&DynamicImage::ImageRgb8(page), &PanelOptions::default())
+            .iter()
+            .map(Panel::bbox)
+            .collect()
+    }
+
+    fn assert_near(actual: &[[u32; 4]], expected: &[[u32; 4]]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                a.iter().zip(e).all(|(a, e)| a.abs_diff(*e) <= 3),
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn grid_with_white_gutters() {
+        let mut page = image::RgbImage::from_pixel(200, 200, WHITE);
+        panel(&mut page, 10, 10, 85, 85);
+        panel(&mut page, 105, 10, 85, 85);
+        panel(&mut page, 10, 105, 180, 85);
+
+        assert_near(
+            &bboxes(page),
+            &[[10, 10, 95, 95], [105, 10, 190, 95], [10, 105, 190, 190]],
+        );
+    }
+
+    #[test]
+    fn grid_with_black_gutters() {
+        let mut page = image::RgbImage::from_pixel(200, 200, BLACK);
+        for (x, y) in [(10, 10), (105, 10), (10, 105), (105, 105)] {
+            draw_filled_rect_mut(&mut page, Rect::at(x, y).of_size(85, 85), WHITE);
+        }
+
+        assert_near(
+            &bboxes(page),
+            &[
+                [10, 10, 95, 95],
+                [105, 10, 190, 95],
+                [10, 105, 95, 190],
+                [105, 105, 190, 190],
+            ],
+        );
+    }
+
+    #[test]
+    fn art_over_a_gutter_keeps_panels_apart() {
+        let mut page = image::RgbImage::from_pixel(200, 200, WHITE);
+        panel(&mut page, 10, 10, 180, 85);
+        panel(&mut page, 10, 105, 180, 85);
+        // a head reaching from the lower panel into the upper one
+        draw_filled_rect_mut(&mut page, Rect::at(130, 60).of_size(30, 60), BLACK);
+
+        assert_near(&bboxes(page), &[[10, 10, 190, 95], [10, 105, 190, 190]]);
+    }
+
+    #[test]
+    fn outline_contains_its_inside() {
+        let panel = Panel {
+            polygon: vec![[0, 0], [100, 0], [100, 50], [0, 100]],
+        };
+        assert_eq!(panel.bbox(), [0, 0, 101, 101]);
+        assert!(panel.contains(10.0, 10.0));
+        assert!(panel.contains(90.0, 40.0));
+        assert!(!panel.contains(90.0, 90.0));
+        assert!(!panel.contains(150.0, 10.0));
+    }
+}
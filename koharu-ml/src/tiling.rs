@@ -0,0 +1,114 @@
+/// A window along one axis of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Span {
+    /// The window is `start..end`.
+    pub start: u32,
+    pub end: u32,
+    /// The part of the window nearer to its middle than to its neighbours', `own_start..own_end`.
+    /// The owned parts of all windows cover the axis without overlapping.
+    pub own_start: u32,
+    pub own_end: u32,
+}
+
+impl Span {
+    pub fn len(&self) -> u32 {
+        self.end - self.start
+    }
+}
+
+/// Windows of `size` covering `0..total`, spread evenly so that neighbours overlap by at least
+/// `overlap`. A single window when `total` fits.
+pub(crate) fn spans(total: u32, size: u32, overlap: u32) -> Vec<Span> {
+    if total <= size {
+        return vec![Span {
+            start: 0,
+            end: total,
+            own_start: 0,
+            own_end: total,
+        }];
+    }
+    let stride = size.saturating_sub(overlap).max(1);
+    let count = (total - size).div_ceil(stride) + 1;
+    let starts: Vec<u32> = (0..count)
+        .map(|i| ((total - size) as u64 * i as u64 / (count - 1) as u64) as u32)
+        .collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            // neighbours split their overlap in the middle
+            let own_start = match i {
+                0 => 0,
+                _ => (starts[i - 1] + size + start) / 2,
+            };
+            let own_end = match starts.get(i + 1) {
+                Some(next) => (start + size + next) / 2,
+                None => total,
+            };
+            Span {
+                start,
+                end: start + size,
+                own_start,
+                own_end,
+            }
+        })
+        .collect()
+}
+
+/// A window of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Tile {
+    pub x: Span,
+    pub y: Span,
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x.len()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y.len()
+    }
+}
+
+/// Square windows of `size` covering a `width` x `height` image, row by row.
+pub(crate) fn tiles(width: u32, height: u32, size: u32, overlap: u32) -> Vec<Tile> {
+    let columns = spans(width, size, overlap);
+    spans(height, size, overlap)
+        .into_iter()
+        .flat_map(|y| columns.iter().map(move |&x| Tile { x, y }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_cover_the_axis_once() {
+        let spans = spans(1000, 300, 60);
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans.first().unwrap().start, 0);
+        assert_eq!(spans.last().unwrap().end, 1000);
+        for pair in spans.windows(2) {
+            assert!(pair[0].end - pair[1].start >= 60);
+            assert_eq!(pair[0].own_end, pair[1].own_start);
+            assert!(pair[0].own_end > pair[1].start && pair[0].own_end < pair[0].end);
+        }
+        assert_eq!(spans.first().unwrap().own_start, 0);
+        assert_eq!(spans.last().unwrap().own_end, 1000);
+    }
+
+    #[test]
+    fn small_images_are_one_tile() {
+        let tiles = tiles(800, 600, 1024, 128);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!((tiles[0].width(), tiles[0].height()), (800, 600));
+
+        // a strip is only split along its length
+        let tiles = super::tiles(800, 20000, 800, 200);
+        assert!(tiles.len() > 1);
+        assert!(tiles.iter().all(|tile| tile.width() == 800));
+    }
+}
@@ -1,16 +1,48 @@
 use std::path::Path;
 
-use koharu_ml::comic_text_detector::ComicTextDetector;
+use image::{DynamicImage, GenericImageView, RgbImage};
+use koharu_ml::comic_text_detector::{ComicTextDetector, DetectOptions};
 
 #[tokio::test]
 async fn comic_text_detector() -> anyhow::Result<()> {
     let model = ComicTextDetector::load(false).await?;
 
     let img = image::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/1.jpg"))?;
-    let (boxes, mask) = model.inference(&img)?;
+    let (boxes, mask) = model.inference(&img, &DetectOptions::default())?;
 
     assert!(!boxes.is_empty());
     assert!(mask.iter().any(|&v| v > 0u8));
 
+    let strict = DetectOptions {
+        confidence_threshold: 0.9,
+        ..Default::default()
+    };
+    let (strict_boxes, _) = model.inference(&img, &strict)?;
+    assert!(strict_boxes.len() <= boxes.len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn comic_text_detector_tiles_long_strips() -> anyhow::Result<()> {
+    let model = ComicTextDetector::load(false).await?;
+
+    let page = image::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/1.jpg"))?;
+    let (width, height) = page.dimensions();
+    let mut strip = RgbImage::new(width, height * 4);
+    for i in 0..4 {
+        image::imageops::replace(&mut strip, &page.to_rgb8(), 0, (height * i) as i64);
+    }
+    let strip = DynamicImage::ImageRgb8(strip);
+
+    let (boxes, mask) = model.inference(&strip, &DetectOptions::default())?;
+
+    assert_eq!(mask.dimensions(), strip.dimensions());
+    let last_page = (height * 3) as f32;
+    assert!(boxes.iter().any(|bbox| bbox.ymin < height as f32));
+    assert!(boxes.iter().any(|bbox| bbox.ymin >= last_page));
+    let last_rows = mask.as_raw().len() - (width * height) as usize;
+    assert!(mask.as_raw()[last_rows..].iter().any(|&v| v > 0u8));
+
     Ok(())
 }
@@ -34,3 +34,41 @@ async fn lama_inpainting_updates_masked_region() -> anyhow::Result<()> {
     );
     Ok(())
 }
+
+#[tokio::test]
+#[ignore]
+async fn lama_tiles_leave_unmasked_pixels_alone() -> anyhow::Result<()> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+
+    let lama = Lama::load(false).await?;
+    let base = image::open(fixtures.join("image.jpg"))?;
+    let mask = image::open(fixtures.join("mask.png"))?;
+    let tile_size = base.width().min(base.height()) / 2;
+
+    let output = lama.inference_tiled(&base, &mask, tile_size)?;
+
+    assert_eq!(output.dimensions(), base.dimensions());
+
+    let mask = mask.to_luma8();
+    let base = base.to_rgb8();
+    let output = output.to_rgb8();
+    let mut changed = false;
+    for ((mask_px, base_px), out_px) in mask.pixels().zip(base.pixels()).zip(output.pixels()) {
+        if mask_px.0[0] > 1 {
+            changed |= base_px.0 != out_px.0;
+        } else {
+            assert!(
+                base_px
+                    .0
+                    .iter()
+                    .zip(out_px.0)
+                    .all(|(&a, b)| a.abs_diff(b) <= 1)
+            );
+        }
+    }
+    assert!(
+        changed,
+        "inpainting should change at least one masked pixel"
+    );
+    Ok(())
+}
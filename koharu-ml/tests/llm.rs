@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use koharu_ml::llm::{GenerateOptions, Llm, ModelId};
+use koharu_ml::llm::{CancellationToken, GenerateOptions, Llm, ModelId, PromptContext};
 use strum::IntoEnumIterator;
 
 #[tokio::test]
@@ -20,17 +20,18 @@ async fn llm_generates_text_for_all_models() -> anyhow::Result<()> {
     for model in ModelId::iter() {
         let mut llm = Llm::load(model, false).await?;
         let opts = GenerateOptions {
-            max_tokens: 100,
+            max_tokens: Some(100),
             temperature: 0.3,
             top_k: None,
             top_p: None,
-            seed: 1,
+            seed: Some(1),
             split_prompt: false,
             repeat_penalty: 1.0,
             repeat_last_n: 64,
+            greedy: false,
         };
 
-        let generated = llm.generate(prompt, &opts)?;
+        let generated = llm.generate(prompt, &PromptContext::default(), &opts)?;
         assert!(
             !generated.trim().is_empty(),
             "model {model:?} should return some text"
@@ -42,3 +43,118 @@ async fn llm_generates_text_for_all_models() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[ignore] // Ignored because it requires downloading a model.
+async fn llm_streams_tokens_and_cancels() -> anyhow::Result<()> {
+    let model_dir = dirs::data_local_dir()
+        .map(|path| path.join("Koharu"))
+        .unwrap_or(PathBuf::from("."))
+        .join("models");
+
+    koharu_ml::set_cache_dir(model_dir)?;
+
+    let mut llm = Llm::load(ModelId::Lfm2_350mEnjpMt, false).await?;
+    let opts = GenerateOptions {
+        max_tokens: Some(50),
+        seed: Some(1),
+        ..Default::default()
+    };
+
+    let mut streamed = String::new();
+    let generated = llm.generate_stream(
+        "こんにちは。",
+        &PromptContext::default(),
+        &opts,
+        &CancellationToken::new(),
+        |text| streamed.push_str(text),
+    )?;
+    assert_eq!(streamed, generated);
+
+    let cancel = CancellationToken::new();
+    let mut pieces = 0;
+    let result = llm.generate_stream(
+        "こんにちは。",
+        &PromptContext::default(),
+        &opts,
+        &cancel,
+        |_| {
+            pieces += 1;
+            cancel.cancel();
+        },
+    );
+    assert!(result.is_err(), "a cancelled generation should fail");
+    assert_eq!(pieces, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore] // Ignored because it requires downloading models.
+async fn llm_prefix_cache_matches_a_fresh_prompt() -> anyhow::Result<()> {
+    let model_dir = dirs::data_local_dir()
+        .map(|path| path.join("Koharu"))
+        .unwrap_or(PathBuf::from("."))
+        .join("models");
+
+    koharu_ml::set_cache_dir(model_dir)?;
+
+    let opts = GenerateOptions {
+        max_tokens: Some(30),
+        greedy: true,
+        ..Default::default()
+    };
+    for model in [ModelId::Lfm2_350mEnjpMt, ModelId::Sakura1_5bQwen2_5v1_0] {
+        let mut llm = Llm::load(model, false).await?;
+        // the second and third prompts reuse the system prompt of the first
+        llm.generate("おはよう。", &PromptContext::default(), &opts)?;
+        llm.generate("こんばんは。", &PromptContext::default(), &opts)?;
+        let cached = llm.generate("こんにちは。", &PromptContext::default(), &opts)?;
+
+        llm.clear_prompt_cache();
+        let fresh = llm.generate("こんにちは。", &PromptContext::default(), &opts)?;
+        assert_eq!(
+            cached, fresh,
+            "model {model:?} should not change with the cache"
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore] // Ignored because it requires downloading models.
+async fn llm_batch_matches_one_prompt_at_a_time() -> anyhow::Result<()> {
+    let model_dir = dirs::data_local_dir()
+        .map(|path| path.join("Koharu"))
+        .unwrap_or(PathBuf::from("."))
+        .join("models");
+
+    koharu_ml::set_cache_dir(model_dir)?;
+
+    let opts = GenerateOptions {
+        max_tokens: Some(30),
+        greedy: true,
+        ..Default::default()
+    };
+    let prompts = ["おはよう。", "今日はいい天気ですね。", "ありがとう！"];
+    for model in [ModelId::Lfm2_350mEnjpMt, ModelId::Sakura1_5bQwen2_5v1_0] {
+        let mut llm = Llm::load(model, false).await?;
+        let mut streamed = vec![String::new(); prompts.len()];
+        let batched = llm.generate_batch(
+            &prompts,
+            &PromptContext::default(),
+            &opts,
+            &CancellationToken::new(),
+            |i, text| streamed[i].push_str(text),
+        )?;
+        assert_eq!(streamed, batched);
+
+        for (prompt, batched) in prompts.iter().zip(&batched) {
+            let single = llm.generate(prompt, &PromptContext::default(), &opts)?;
+            assert_eq!(&single, batched, "model {model:?} prompt {prompt}");
+        }
+    }
+
+    Ok(())
+}
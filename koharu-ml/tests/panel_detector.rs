@@ -0,0 +1,21 @@
+use std::path::Path;
+
+use koharu_ml::panel_detector::{PanelOptions, detect_panels};
+
+#[test]
+fn panel_detector() -> anyhow::Result<()> {
+    let img = image::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/1.jpg"))?;
+    let panels = detect_panels(&img, &PanelOptions::default());
+
+    // four strips stacked top to bottom, with art and bubbles reaching over the gutters
+    assert_eq!(panels.len(), 4);
+    let bboxes: Vec<_> = panels.iter().map(|panel| panel.bbox()).collect();
+    for pair in bboxes.windows(2) {
+        assert!(pair[0][3] <= pair[1][1]);
+    }
+    for [x0, _, x1, _] in bboxes {
+        assert!(x1 - x0 > img.width() * 3 / 4);
+    }
+
+    Ok(())
+}
@@ -11,15 +11,22 @@ use actix_web::{
 };
 use actix_multipart::form::{MultipartForm, tempfile::TempFile, text::Text};
 use anyhow::Result;
-use image::{DynamicImage, GenericImageView};
+use futures::StreamExt;
+use image::GenericImageView;
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 use tracing_subscriber::filter::EnvFilter;
 
-use koharu_ml::{cuda_is_available, llm::ModelId};
+use koharu_ml::{
+    cuda_is_available,
+    llm::{GenerateOptions, ModelId},
+};
 use koharu_runtime::{ensure_dylibs, preload_dylibs};
 use koharu::ml::Model as KoharuModel;
 use koharu::renderer::Renderer;
 use koharu::llm::Model as LLMModel;
+use koharu::pipeline::{self, Pipeline, PipelineEvent};
+use koharu::reading_order::ReadingOrder;
 use koharu::state::Document;
 
 // 应用程序状态结构体，用于在Actix Web应用中共享模型和渲染器
@@ -122,7 +129,15 @@ struct TranslateRequest {
     #[multipart(rename = "image")]  // 表单字段名称为image
     image: TempFile,                // 上传的图像文件
     #[multipart(rename = "config")]  // 表单字段名称为config，用于接收JSON配置
-    config: Option<Text<String>>,         // 配置字符串，前端可能会传递，但我们会忽略
+    config: Option<Text<String>>,         // 配置字符串，未知字段会被忽略
+}
+
+// config字段中的翻译配置，所有字段都是可选的
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct TranslateConfig {
+    generate_options: Option<GenerateOptions>,  // 生成参数，未指定时使用模型默认值
+    reading_order: Option<ReadingOrder>,        // 文本块阅读顺序，未指定时按漫画顺序（从右到左）
 }
 
 
@@ -149,6 +164,13 @@ async fn translate_image_stream(
     MultipartForm(form): MultipartForm<TranslateRequest>,
 ) -> actix_web::Result<impl Responder> {
 
+        // 解析配置，格式错误时返回400
+        let config: TranslateConfig = match form.config.as_ref() {
+            Some(config) if !config.trim().is_empty() => serde_json::from_str(config)
+                .map_err(|e| actix_web::error::ErrorBadRequest(format!("配置格式错误: {}", e)))?,
+            _ => TranslateConfig::default(),
+        };
+
         // 加载图像
         let image_data = std::fs::read(form.image.file.path())?;
         let dynamic_image = image::load_from_memory(&image_data)
@@ -162,124 +184,75 @@ async fn translate_image_stream(
             id,
             path: std::path::PathBuf::new(),
             name: "temp".to_string(),
-            image: serializable_image.clone(),
+            image: serializable_image,
             width,
             height,
             ..Default::default()
         };
 
-        // 使用actix-web的流响应
-        Ok(HttpResponse::Ok()
-            .content_type("application/octet-stream")
-            .streaming(futures::stream::once(async move {
-                // 步骤3：检测对话框
-                let (text_blocks, segment) = match data.model.detect_dialog(&serializable_image).await {
-                    Ok(result) => result,
-                    Err(e) => {
-                        let msg = build_message(2, format!("对话框检测失败: {}", e).as_bytes());
-                        error!("对话框检测失败: {}", e);
-                        return Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg));
-                    }
-                };
-                document.text_blocks = text_blocks;
-                document.segment = Some(segment);
+        // 使用共享的处理流水线：检测 -> 字体识别 -> OCR -> 修复 -> 翻译 -> 渲染
+        let ctx = pipeline::Context::default()
+            .with_ml(data.model.clone())
+            .with_llm(data.llm_model.clone())
+            .with_renderer(data.renderer.clone());
+        let llm_model = data.llm_model.clone();
+        let (tx, rx) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+
+        actix_web::rt::spawn(async move {
+            // 确保翻译模型已加载完成
+            if !llm_model.ready().await {
+                info!("LLM模型尚未加载，开始加载SakuraGalTransl7Bv3_7模型...");
+                llm_model.load(ModelId::SakuraGalTransl7Bv3_7).await;
+            }
+            if let Err(e) = llm_model.wait_ready().await {
+                error!("LLM模型加载失败: {}", e);
+                let _ = tx.unbounded_send(build_message(2, format!("LLM模型加载失败: {}", e).as_bytes()));
+                return;
+            }
 
-                // 步骤4：OCR识别
-                let text_blocks = match data.model.ocr(&serializable_image, &document.text_blocks).await {
-                    Ok(result) => result,
-                    Err(e) => {
-                        let msg = build_message(2, format!("OCR识别失败: {}", e).as_bytes());
-                        error!("OCR识别失败: {}", e);
-                        return Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg));
-                    }
-                };
-                document.text_blocks = text_blocks;
-
-                // 步骤5：图像修复(Inpaint)
-                let segment = match document.segment.as_ref() {
-                    Some(seg) => seg,
-                    None => {
-                        let msg = build_message(2, "分割结果不存在".as_bytes());
-                        error!("分割结果不存在");
-                        return Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg));
+            // 每个阶段开始时发送进度消息（类型1）
+            let result = Pipeline::full()
+                .replace_stage(pipeline::Detect {
+                    reading_order: config.reading_order.unwrap_or_default(),
+                    ..Default::default()
+                })
+                .replace_stage(pipeline::Translate {
+                    options: config.generate_options,
+                    ..Default::default()
+                })
+                .run(&ctx, &mut document, |event| {
+                    if let PipelineEvent::StageStarted { stage, .. } = event {
+                        let _ = tx.unbounded_send(build_message(1, stage.to_string().as_bytes()));
                     }
-                };
-
-                let mut segment_data = segment.to_rgba8();
-                let (seg_width, seg_height) = segment_data.dimensions();
-                for y in 0..seg_height {
-                    for x in 0..seg_width {
-                        let pixel = segment_data.get_pixel_mut(x, y);
-                        if pixel.0 != [0, 0, 0, 255] {
-                            let mut inside_any_block = false;
-                            for block in &document.text_blocks {
-                                if x >= block.x as u32
-                                    && x < (block.x + block.width) as u32
-                                    && y >= block.y as u32
-                                    && y < (block.y + block.height) as u32
-                                {
-                                    inside_any_block = true;
-                                    break;
-                                }
-                            }
-                            if !inside_any_block {
-                                *pixel = image::Rgba([0, 0, 0, 255]);
-                            }
-                        }
-                    }
-                }
+                })
+                .await;
+            if let Err(e) = result {
+                error!("处理失败: {:#}", e);
+                let _ = tx.unbounded_send(build_message(2, format!("处理失败: {:#}", e).as_bytes()));
+                return;
+            }
 
-                let mask = koharu::image::SerializableDynamicImage::from(DynamicImage::ImageRgba8(segment_data));
-                let inpainted = match data.model.inpaint(&serializable_image, &mask).await {
-                    Ok(result) => result,
+            // 返回结果图像
+            let msg = match document.rendered.as_ref() {
+                Some(rendered_image) => match image_to_png(rendered_image) {
+                    Ok(png_data) => build_message(0, &png_data),
                     Err(e) => {
-                        let msg = build_message(2, format!("图像修复失败: {}", e).as_bytes());
-                        error!("图像修复失败: {}", e);
-                        return Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg));
+                        error!("图像转换失败: {}", e);
+                        build_message(2, format!("图像转换失败: {}", e).as_bytes())
                     }
-                };
-                document.inpainted = Some(inpainted);
-
-                // 步骤6：翻译文本
-                if !data.llm_model.ready().await {
-                    info!("LLM模型尚未加载，开始加载SakuraGalTransl7Bv3_7模型...");
-                    data.llm_model.load(ModelId::SakuraGalTransl7Bv3_7).await;
-                    info!("SakuraGalTransl7Bv3_7模型加载完成");
-                }
-
-                if let Err(e) = data.llm_model.generate(&mut document).await {
-                    let msg = build_message(2, format!("文本翻译失败: {}", e).as_bytes());
-                    error!("文本翻译失败: {}", e);
-                    return Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg));
-                }
-
-                // 步骤7：渲染翻译后的文本
-                if let Err(e) = data.renderer.render(&mut document, None, Default::default()) {
-                    let msg = build_message(2, format!("图像渲染失败: {}", e).as_bytes());
-                    error!("图像渲染失败: {}", e);
-                    return Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg));
+                },
+                None => {
+                    error!("渲染后的图像不存在");
+                    build_message(2, "渲染后的图像不存在".as_bytes())
                 }
+            };
+            let _ = tx.unbounded_send(msg);
+        });
 
-                // 步骤8：返回结果图像
-                match document.rendered.as_ref() {
-                    Some(rendered_image) => match image_to_png(rendered_image) {
-                        Ok(png_data) => {
-                            let msg = build_message(0, &png_data);
-                            Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg))
-                        }
-                        Err(e) => {
-                            let msg = build_message(2, format!("图像转换失败: {}", e).as_bytes());
-                            error!("图像转换失败: {}", e);
-                            Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg))
-                        }
-                    },
-                    None => {
-                        let msg = build_message(2, "渲染后的图像不存在".as_bytes());
-                        error!("渲染后的图像不存在");
-                        Ok::<actix_web::web::Bytes, actix_web::Error>(actix_web::web::Bytes::from(msg))
-                    }
-                }
-            })))
+        // 使用actix-web的流响应，逐条发送进度和结果消息
+        Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .streaming(rx.map(|msg| Ok::<web::Bytes, actix_web::Error>(web::Bytes::from(msg)))))
 }
 
 // CLI命令行参数
@@ -1,22 +1,22 @@
 use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Result;
-use clap::{Parser, ValueHint};
+use clap::{Parser, Subcommand, ValueHint};
 use koharu_ml::cuda_is_available;
 use koharu_runtime::{ensure_dylibs, preload_dylibs};
 use once_cell::sync::Lazy;
 use rfd::MessageDialog;
-use tauri::{Emitter, Manager};
+use tauri::{DragDropEvent, Emitter, Manager, WindowEvent};
 use tokio::sync::RwLock;
 use tracing::warn;
 use tracing_subscriber::fmt::format::FmtSpan;
 
 use crate::{
-    command,
-    khr::{deserialize_khr, has_khr_magic},
-    llm, ml,
+    batch, command, llm, ml,
+    project::Project,
+    prompts::PromptProfiles,
     renderer::Renderer,
-    state::{Document, State},
+    state::{AppState, State, load_documents},
     update,
 };
 
@@ -119,6 +119,8 @@ mod windows_file_assoc {
 static APP_ROOT: Lazy<PathBuf> = Lazy::new(resolve_app_root);
 static LIB_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("libs"));
 static MODEL_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("models"));
+static CUSTOM_MODELS: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("custom-models.json"));
+static PROMPT_ROOT: Lazy<PathBuf> = Lazy::new(|| APP_ROOT.join("prompts"));
 
 #[derive(Parser)]
 #[command(version = crate::version::APP_VERSION, about)]
@@ -132,6 +134,7 @@ struct Cli {
     download: bool,
     #[arg(
         long,
+        global = true,
         help = "Force using CPU even if GPU is available",
         default_value_t = false
     )]
@@ -142,24 +145,17 @@ struct Cli {
         help = "Open file on startup"
     )]
     path: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn load_documents_from_path(path: PathBuf) -> Result<Vec<Document>> {
-    if !path.exists() {
-        return Err(anyhow::anyhow!("File not found: {}", path.display()));
-    }
-
-    let bytes = std::fs::read(&path)?;
-
-    if has_khr_magic(&bytes) {
-        return deserialize_khr(&bytes)
-            .map_err(|err| anyhow::anyhow!("Failed to load documents: {err}"));
-    }
-
-    Ok(vec![Document::open(path)?])
+#[derive(Subcommand)]
+enum Command {
+    /// Run the translation pipeline over a folder of pages without opening a window
+    Batch(batch::BatchArgs),
 }
 
-fn initialize() -> Result<()> {
+fn initialize(headless: bool) -> Result<()> {
     #[cfg(target_os = "windows")]
     {
         windows_ansi::enable_ansi_support().ok();
@@ -177,15 +173,18 @@ fn initialize() -> Result<()> {
     // hook model cache dir
     koharu_ml::set_cache_dir(MODEL_ROOT.to_path_buf())?;
 
-    std::panic::set_hook(Box::new(|info| {
-        let msg = info.to_string();
-        MessageDialog::new()
-            .set_level(rfd::MessageLevel::Error)
-            .set_title("Panic")
-            .set_description(&msg)
-            .show();
-        std::process::exit(1);
-    }));
+    // There may be no display to show the dialog on when running headless.
+    if !headless {
+        std::panic::set_hook(Box::new(|info| {
+            let msg = info.to_string();
+            MessageDialog::new()
+                .set_level(rfd::MessageLevel::Error)
+                .set_title("Panic")
+                .set_description(&msg)
+                .show();
+            std::process::exit(1);
+        }));
+    }
 
     #[cfg(feature = "bundle")]
     {
@@ -205,67 +204,85 @@ async fn prefetch() -> Result<()> {
     Ok(())
 }
 
-async fn setup(
-    app: tauri::AppHandle,
-    use_cpu: bool,
-    startup_document: Option<PathBuf>,
-) -> Result<()> {
+async fn load_dylibs() -> Result<()> {
     // Preload dynamic libraries only if CUDA is available.
-    if cuda_is_available() {
-        ensure_dylibs(LIB_ROOT.to_path_buf()).await?;
-        preload_dylibs(LIB_ROOT.to_path_buf())?;
+    if !cuda_is_available() {
+        return Ok(());
+    }
 
-        // Only search DLLs in the custom directory on Windows, this avoids potential
-        // conflicts with existing DLLs in the system PATH.
-        #[cfg(target_os = "windows")]
-        {
-            if let Err(err) = windows_file_assoc::register_khr() {
-                warn!(?err, "Failed to register .khr file association");
-            }
+    ensure_dylibs(LIB_ROOT.to_path_buf()).await?;
+    preload_dylibs(LIB_ROOT.to_path_buf())?;
 
-            use std::os::windows::ffi::OsStrExt;
-            use windows_sys::Win32::System::LibraryLoader::{
-                AddDllDirectory, LOAD_LIBRARY_SEARCH_SYSTEM32, LOAD_LIBRARY_SEARCH_USER_DIRS,
-                SetDefaultDllDirectories,
-            };
-
-            let wide = LIB_ROOT
-                .as_os_str()
-                .encode_wide()
-                .chain(std::iter::once(0))
-                .collect::<Vec<_>>();
-            unsafe {
-                if SetDefaultDllDirectories(
-                    LOAD_LIBRARY_SEARCH_USER_DIRS | LOAD_LIBRARY_SEARCH_SYSTEM32,
-                ) == 0
-                {
-                    anyhow::bail!(
-                        "Failed to set default DLL directories: {}",
-                        std::io::Error::last_os_error()
-                    );
-                }
-                if AddDllDirectory(wide.as_ptr()).is_null() {
-                    anyhow::bail!(
-                        "Failed to add DLL directory: {}",
-                        std::io::Error::last_os_error()
-                    );
-                }
+    // Only search DLLs in the custom directory on Windows, this avoids potential
+    // conflicts with existing DLLs in the system PATH.
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use windows_sys::Win32::System::LibraryLoader::{
+            AddDllDirectory, LOAD_LIBRARY_SEARCH_SYSTEM32, LOAD_LIBRARY_SEARCH_USER_DIRS,
+            SetDefaultDllDirectories,
+        };
+
+        let wide = LIB_ROOT
+            .as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect::<Vec<_>>();
+        unsafe {
+            if SetDefaultDllDirectories(
+                LOAD_LIBRARY_SEARCH_USER_DIRS | LOAD_LIBRARY_SEARCH_SYSTEM32,
+            ) == 0
+            {
+                anyhow::bail!(
+                    "Failed to set default DLL directories: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            if AddDllDirectory(wide.as_ptr()).is_null() {
+                anyhow::bail!(
+                    "Failed to add DLL directory: {}",
+                    std::io::Error::last_os_error()
+                );
             }
         }
+    }
+
+    tracing::info!(
+        "CUDA is available, loaded dynamic libraries from {:?}",
+        *LIB_ROOT
+    );
 
-        tracing::info!(
-            "CUDA is available, loaded dynamic libraries from {:?}",
-            *LIB_ROOT
-        );
+    Ok(())
+}
+
+async fn setup(
+    app: tauri::AppHandle,
+    use_cpu: bool,
+    startup_document: Option<PathBuf>,
+) -> Result<()> {
+    #[cfg(target_os = "windows")]
+    if cuda_is_available()
+        && let Err(err) = windows_file_assoc::register_khr()
+    {
+        warn!(?err, "Failed to register .khr file association");
     }
 
+    load_dylibs().await?;
+
     let ml = Arc::new(ml::Model::new(use_cpu).await?);
     let llm = Arc::new(llm::Model::new(use_cpu));
+    let registry = Arc::new(llm::Registry::load(CUSTOM_MODELS.as_path()));
+    let profiles = Arc::new(PromptProfiles::new(PROMPT_ROOT.as_path()));
+    if let Err(err) = profiles.install_defaults() {
+        warn!(?err, "Failed to install default prompt profiles");
+    }
     let renderer = Arc::new(Renderer::new()?);
     let state = Arc::new(RwLock::new(State::default()));
 
     app.manage(ml);
     app.manage(llm);
+    app.manage(registry);
+    app.manage(profiles);
     app.manage(renderer);
 
     app.get_webview_window("splashscreen").unwrap().close()?;
@@ -273,11 +290,14 @@ async fn setup(
     main_window.show()?;
 
     if let Some(path) = startup_document {
-        match load_documents_from_path(path) {
+        let project = Project::load(std::slice::from_ref(&path));
+        match load_documents(vec![path]) {
             Ok(documents) => {
                 {
                     let mut guard = state.write().await;
                     guard.documents = documents.clone();
+                    guard.project = project;
+                    guard.history.clear();
                 }
                 if let Err(err) = main_window.emit("documents:opened", &documents) {
                     warn!(?err, "Failed to emit documents:opened event");
@@ -299,47 +319,108 @@ async fn setup(
     Ok(())
 }
 
-pub async fn run() -> Result<()> {
-    initialize()?;
+/// Replace the open documents with the dropped images and projects.
+async fn open_dropped(app: tauri::AppHandle, paths: Vec<PathBuf>) -> Result<()> {
+    // documents can't be opened before setup has finished
+    let Some(state) = app.try_state::<AppState>() else {
+        return Ok(());
+    };
 
+    let (documents, project) = tauri::async_runtime::spawn_blocking(move || {
+        let project = Project::load(&paths);
+        load_documents(paths).map(|documents| (documents, project))
+    })
+    .await??;
+    {
+        let mut state = state.write().await;
+        state.documents = documents.clone();
+        state.project = project;
+        state.history.clear();
+    }
+    app.emit("documents:opened", &documents)?;
+
+    Ok(())
+}
+
+pub async fn run() -> Result<()> {
     let Cli {
         download,
         cpu,
         path,
+        command,
     } = Cli::parse();
 
+    initialize(command.is_some())?;
+
     if download {
         prefetch().await?;
         return Ok(());
     }
 
+    if let Some(Command::Batch(args)) = command {
+        load_dylibs().await?;
+        return batch::run(args, cpu).await;
+    }
+
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
             command::app_version,
             command::open_external,
             command::get_documents,
             command::open_documents,
+            command::append_documents,
             command::save_documents,
             command::export_document,
             command::export_all_documents,
+            command::export_archive,
+            command::export_panels,
+            command::export_layered,
+            command::get_glossary,
+            command::update_glossary,
+            command::list_prompt_profiles,
+            command::save_prompt_profile,
+            command::get_prompt_profile,
+            command::set_prompt_profile,
+            command::get_generate_options,
+            command::set_generate_options,
+            command::get_reading_order,
+            command::set_reading_order,
             command::detect,
             command::ocr,
             command::inpaint,
+            command::process,
             command::inpaint_partial,
             command::render,
             command::update_brush_layer,
             command::update_text_blocks,
+            command::undo,
+            command::redo,
             command::update_inpaint_mask,
             command::list_font_families,
             command::llm_list,
             command::llm_load,
+            command::llm_register,
+            command::llm_unregister,
             command::llm_offload,
             command::llm_ready,
             command::llm_generate,
+            command::llm_generate_blocks,
+            command::llm_cancel,
             update::apply_available_update,
             update::get_available_update,
             update::ignore_update,
         ])
+        .on_window_event(|window, event| {
+            if let WindowEvent::DragDrop(DragDropEvent::Drop { paths, .. }) = event {
+                let app = window.app_handle().clone();
+                let paths = paths.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = open_dropped(app, paths).await {
+                        warn!(?err, "Failed to open dropped files");
+                    }
+                });
+            }
+        })
         .setup(move |app| {
             app.manage(update::UpdateState::new(APP_ROOT.to_path_buf()));
             update::spawn_background_update_check(app.handle().clone());
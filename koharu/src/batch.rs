@@ -0,0 +1,381 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueHint};
+use koharu_ml::comic_text_detector::DetectOptions;
+use koharu_ml::llm::{ContextTurn, GenerateOptions, ModelId};
+use tracing::{debug, info, warn};
+
+use crate::{
+    export::{self, ArchiveFormat, ArchiveWriter, ExportOptions, ImageCodec, PanelLayout},
+    import::natural_cmp,
+    khr::{self, KhrWriter},
+    llm,
+    memory::TranslationMemory,
+    ml,
+    pipeline::{self, Pipeline, PipelineEvent, Stage, StageKind},
+    project::Project,
+    prompts,
+    reading_order::ReadingOrder,
+    renderer::Renderer,
+    state::Document,
+};
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp"];
+
+#[derive(Debug, Args)]
+pub struct BatchArgs {
+    #[arg(value_name = "INPUT_DIR", value_hint = ValueHint::DirPath)]
+    input: PathBuf,
+    #[arg(value_name = "OUTPUT_DIR", value_hint = ValueHint::DirPath)]
+    output: PathBuf,
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "detect,font-detect,ocr,inpaint,translate,render",
+        help = "Pipeline stages to run, in order"
+    )]
+    stages: Vec<StageKind>,
+    #[arg(
+        long,
+        default_value = "sakura-galtransl-7b-v3.7",
+        help = "LLM used for translation"
+    )]
+    model: ModelId,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        help = "Local GGUF model used instead of --model"
+    )]
+    model_file: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "model_file",
+        help = "System prompt for --model-file, a generic translation prompt by default"
+    )]
+    system_prompt: Option<String>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+        help = "Prompt profile directory with user.jinja and an optional system.jinja"
+    )]
+    prompt_profile: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Target language for translation models that support several"
+    )]
+    language: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        help = "JSON glossary of {source, target, notes} terms to keep consistent"
+    )]
+    glossary: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = llm::DEFAULT_CONTEXT_PAGES,
+        help = "Previous pages shown to the translation model, 0 to translate pages independently"
+    )]
+    context_pages: usize,
+    #[arg(
+        long,
+        help = "Translate with greedy decoding, so the same pages give the same translations"
+    )]
+    greedy: bool,
+    #[arg(
+        long,
+        help = "Translate every text block with its own prompt, decoded together by models that support it"
+    )]
+    per_block: bool,
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        help = "Group detected text by speech bubble, bridging mask gaps of up to this many pixels"
+    )]
+    group_bubbles: Option<u32>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Order text blocks are read and translated in [default: manga]"
+    )]
+    reading_order: Option<ReadingOrder>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Also pack the rendered pages into an archive"
+    )]
+    export: Option<ArchiveFormat>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "LAYOUT",
+        help = "Also save the panels of the rendered pages, one image each or one strip per page"
+    )]
+    export_panels: Option<PanelLayout>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "jpeg",
+        help = "Image codec for archive pages"
+    )]
+    codec: ImageCodec,
+    #[arg(
+        long,
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "JPEG quality for archive pages"
+    )]
+    quality: u8,
+}
+
+impl BatchArgs {
+    fn runs(&self, stage: StageKind) -> bool {
+        self.stages.contains(&stage)
+    }
+}
+
+fn list_images(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read input directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect::<Vec<_>>();
+    paths.sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name().unwrap_or_default().to_string_lossy(),
+            &b.file_name().unwrap_or_default().to_string_lossy(),
+        )
+    });
+    Ok(paths)
+}
+
+/// Run the pipeline over every image in `args.input` without opening a window.
+pub async fn run(args: BatchArgs, use_cpu: bool) -> Result<()> {
+    let paths = list_images(&args.input)?;
+    if paths.is_empty() {
+        anyhow::bail!("No images found in {}", args.input.display());
+    }
+    if args.export.is_some() && !args.runs(StageKind::Render) {
+        anyhow::bail!("--export needs the render stage");
+    }
+    if args.export_panels.is_some() && !args.runs(StageKind::Render) {
+        anyhow::bail!("--export-panels needs the render stage");
+    }
+    std::fs::create_dir_all(&args.output)?;
+
+    let needs_ml = [
+        StageKind::Detect,
+        StageKind::FontDetect,
+        StageKind::Ocr,
+        StageKind::Inpaint,
+    ]
+    .into_iter()
+    .any(|stage| args.runs(stage));
+
+    let mut ctx = pipeline::Context::default();
+    if needs_ml {
+        ctx = ctx.with_ml(Arc::new(ml::Model::new(use_cpu).await?));
+    }
+    if args.runs(StageKind::Translate) {
+        let llm = llm::Model::new(use_cpu);
+        match args.model_file.clone() {
+            Some(path) => {
+                llm.load_custom(llm::CustomModel {
+                    id: "custom".to_string(),
+                    path,
+                    prompt: args.system_prompt.clone(),
+                })
+                .await
+            }
+            None => llm.load(args.model).await,
+        }
+        llm.wait_ready().await?;
+        ctx = ctx.with_llm(Arc::new(llm));
+    }
+    if args.runs(StageKind::Render) {
+        ctx = ctx.with_renderer(Arc::new(Renderer::new()?));
+    }
+
+    let mut project = match args.glossary.as_ref() {
+        Some(path) => {
+            let bytes = std::fs::read(path)
+                .with_context(|| format!("Failed to read glossary {}", path.display()))?;
+            Project {
+                glossary: serde_json::from_slice(&bytes)
+                    .with_context(|| format!("Failed to parse glossary {}", path.display()))?,
+                ..Default::default()
+            }
+        }
+        None => Project::default(),
+    };
+    if args.greedy {
+        project.generate_options = Some(GenerateOptions {
+            greedy: true,
+            ..Default::default()
+        });
+    }
+    project.reading_order = args.reading_order;
+    let profile = args
+        .prompt_profile
+        .as_deref()
+        .map(prompts::read_profile)
+        .transpose()?;
+
+    // lines repeated across pages are translated once
+    let memory = Arc::new(Mutex::new(TranslationMemory::default()));
+    // the translate stage carries the previous pages, so the pipeline is rebuilt for every page
+    let build_pipeline = |context: Vec<ContextTurn>| {
+        let stages = args
+            .stages
+            .iter()
+            .map(|stage| match stage {
+                StageKind::Detect => Box::new(pipeline::Detect {
+                    options: DetectOptions {
+                        group_bubbles: args.group_bubbles,
+                        ..Default::default()
+                    },
+                    reading_order: project.reading_order.unwrap_or_default(),
+                    keep_panels: args.export_panels.is_some(),
+                    ..Default::default()
+                }) as Box<dyn Stage>,
+                StageKind::Translate => Box::new(pipeline::Translate {
+                    text_block_index: None,
+                    language: args.language.clone(),
+                    glossary: project.glossary.clone(),
+                    profile: profile.clone(),
+                    options: project.generate_options.clone(),
+                    context: context.clone(),
+                    memory: Some(memory.clone()),
+                    per_block: args.per_block,
+                    ..Default::default()
+                }) as Box<dyn Stage>,
+                stage => stage.stage(),
+            })
+            .collect();
+        Pipeline::from_stages(stages)
+    };
+    let mut context = VecDeque::new();
+
+    let project_name = args
+        .input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("project");
+    let project_path = args.output.join(format!("{project_name}.khr"));
+    // the thumbnail leads the file, so every page is shrunk for it before any is processed
+    let thumbnails = paths
+        .iter()
+        .map(|path| {
+            let image = image::load_from_memory(&std::fs::read(path)?)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            Ok(khr::page_thumbnail(&image))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let thumbnail = khr::contact_sheet(&thumbnails.iter().collect::<Vec<_>>());
+    let mut khr = None;
+
+    let mut archive = match args.export {
+        Some(format) => {
+            let archive_path = args
+                .output
+                .join(format!("{project_name}.{}", format.extension()));
+            let options = ExportOptions {
+                format,
+                codec: args.codec,
+                quality: args.quality,
+                title: Some(project_name.to_string()),
+            };
+            Some((ArchiveWriter::create(&archive_path, options)?, archive_path))
+        }
+        None => None,
+    };
+
+    let total = paths.len();
+    for (index, path) in paths.into_iter().enumerate() {
+        info!("[{}/{total}] {}", index + 1, path.display());
+
+        let mut document = Document::open_image(path)?;
+        let pipeline = build_pipeline(context.iter().cloned().collect());
+        pipeline
+            .run(&ctx, &mut document, |event| {
+                if let PipelineEvent::StageFinished {
+                    stage, elapsed_ms, ..
+                } = event
+                {
+                    debug!("{stage} finished in {elapsed_ms}ms");
+                }
+            })
+            .await?;
+
+        if let Some(turn) = llm::context_turn(&document.text_blocks) {
+            context.push_back(turn);
+            if context.len() > args.context_pages {
+                context.pop_front();
+            }
+        }
+
+        if args.runs(StageKind::Render) {
+            save_rendered(&document, &args.output)?;
+        }
+        if let Some((writer, _)) = archive.as_mut() {
+            writer.add_page(&document)?;
+        }
+        if let Some(layout) = args.export_panels {
+            export::export_panels(&args.output, std::slice::from_ref(&document), layout)?;
+        }
+
+        // pages are written as they finish so the whole volume never sits in memory
+        let writer = match khr.as_mut() {
+            Some(writer) => writer,
+            None => {
+                let mut writer = KhrWriter::create(&project_path, &thumbnail)?;
+                writer.set_project(project.clone());
+                khr.insert(writer)
+            }
+        };
+        writer.write_page(&document)?;
+    }
+
+    if let Some((writer, archive_path)) = archive {
+        writer.finish()?;
+        info!("Exported pages to {}", archive_path.display());
+    }
+
+    if let Some(writer) = khr {
+        writer.finish()?;
+        info!("Saved project to {}", project_path.display());
+    }
+
+    Ok(())
+}
+
+fn save_rendered(document: &Document, output: &Path) -> Result<()> {
+    let Some(rendered) = document.rendered.as_ref() else {
+        warn!("Nothing rendered for {}, run inpaint first", document.name);
+        return Ok(());
+    };
+
+    let document_ext = document
+        .path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("jpg");
+    let filename = format!("{}_koharu.{}", document.name, document_ext);
+    rendered
+        .save(output.join(&filename))
+        .map_err(|e| anyhow::anyhow!("Failed to save image: {e}"))?;
+
+    Ok(())
+}
@@ -1,21 +1,32 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use image::{self, GenericImageView, RgbaImage};
-use koharu_ml::{llm::ModelId, set_locale};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use koharu_ml::comic_text_detector::DetectOptions;
+use koharu_ml::llm::{GenerateOptions, GlossaryEntry, ModelId, OpenAiConfig, PromptProfile};
 use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use sys_locale::get_locale;
-use tauri::State;
-use tracing::instrument;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tracing::{instrument, warn};
 
 use crate::{
+    export::{self, ExportOptions, PanelLayout},
     image::SerializableDynamicImage,
-    khr::{deserialize_khr, has_khr_magic, serialize_khr},
-    llm, ml,
+    khr::write_khr,
+    layered::{self, LayeredFormat},
+    llm,
+    memory::TranslationMemory,
+    ml,
+    pipeline::{self, Pipeline, PipelineEvent, Stage, StageKind},
+    project::Project,
+    prompts::{NamedProfile, PromptProfiles},
+    reading_order::ReadingOrder,
     renderer::Renderer,
     result::Result,
-    state::{AppState, Document, TextBlock, TextStyle},
+    state::{AppState, Document, Layer, Snapshot, TextBlock, load_documents},
     version,
 };
 use koharu_renderer::renderer::TextShaderEffect;
@@ -52,50 +63,67 @@ pub fn open_external(url: &str) -> Result<()> {
     Ok(())
 }
 
-#[tauri::command]
-pub async fn open_documents(state: State<'_, AppState>) -> Result<Vec<Document>> {
-    let paths = rfd::FileDialog::new()
-        .add_filter("Supported Files", &["khr", "png", "jpg", "jpeg", "webp"])
+fn pick_documents() -> Vec<std::path::PathBuf> {
+    rfd::FileDialog::new()
+        .add_filter(
+            "Supported Files",
+            &[
+                "khr", "png", "jpg", "jpeg", "webp", "cbz", "cbr", "zip", "pdf",
+            ],
+        )
         .set_title("Pick Files")
         .pick_files()
-        .unwrap_or_default();
-
-    let single_file_bytes = if paths.len() == 1 {
-        Some(std::fs::read(&paths[0])?)
-    } else {
-        None
-    };
-
-    let load_images = |paths: Vec<std::path::PathBuf>| {
-        let mut documents = paths
-            .into_par_iter()
-            .filter_map(|path| Document::open(path).ok())
-            .collect::<Vec<_>>();
-
-        documents.sort_by_key(|doc| doc.name.clone());
+        .unwrap_or_default()
+}
 
-        documents
-    };
+#[tauri::command]
+pub async fn open_documents(state: State<'_, AppState>) -> Result<Vec<Document>> {
+    let paths = pick_documents();
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    // khr loader or load image files
-    let documents: Vec<Document> = if let Some(bytes) = single_file_bytes.as_ref() {
-        if has_khr_magic(bytes) {
-            deserialize_khr(bytes).map_err(|e| anyhow::anyhow!("Failed to load documents: {e}"))?
-        } else {
-            load_images(paths)
-        }
-    } else {
-        load_images(paths)
-    };
+    let (documents, project) = tauri::async_runtime::spawn_blocking(move || {
+        let project = Project::load(&paths);
+        load_documents(paths).map(|documents| (documents, project))
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+    .map_err(|e| anyhow::anyhow!("Failed to load documents: {e:#}"))?;
 
     // store documents in app state
     let mut state = state.write().await;
     state.documents = documents.clone();
+    state.project = project;
+    state.history.clear();
 
     // return opened documents as a copy
     Ok(documents)
 }
 
+/// Add images or the pages of other KHR projects after the open documents.
+#[tauri::command]
+pub async fn append_documents(state: State<'_, AppState>) -> Result<Vec<Document>> {
+    let paths = pick_documents();
+    if paths.is_empty() {
+        return Ok(state.read().await.documents.clone());
+    }
+
+    let (documents, project) = tauri::async_runtime::spawn_blocking(move || {
+        let project = Project::load(&paths);
+        load_documents(paths).map(|documents| (documents, project))
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+    .map_err(|e| anyhow::anyhow!("Failed to load documents: {e:#}"))?;
+
+    let mut state = state.write().await;
+    state.documents.extend(documents);
+    state.project.merge(project);
+
+    Ok(state.documents.clone())
+}
+
 #[tauri::command]
 pub fn app_version() -> String {
     version::current().to_string()
@@ -107,6 +135,92 @@ pub async fn get_documents(state: State<'_, AppState>) -> Result<Vec<Document>>
     Ok(state.documents.clone())
 }
 
+#[tauri::command]
+pub async fn get_glossary(state: State<'_, AppState>) -> Result<Vec<GlossaryEntry>> {
+    let state = state.read().await;
+    Ok(state.project.glossary.clone())
+}
+
+#[tauri::command]
+pub async fn update_glossary(
+    state: State<'_, AppState>,
+    glossary: Vec<GlossaryEntry>,
+) -> Result<Vec<GlossaryEntry>> {
+    let mut state = state.write().await;
+    state.project.glossary = glossary
+        .into_iter()
+        .filter(|entry| !entry.source.trim().is_empty())
+        .collect();
+    Ok(state.project.glossary.clone())
+}
+
+#[tauri::command]
+pub fn list_prompt_profiles(profiles: State<'_, Arc<PromptProfiles>>) -> Result<Vec<NamedProfile>> {
+    Ok(profiles.list()?)
+}
+
+#[tauri::command]
+pub fn save_prompt_profile(
+    profiles: State<'_, Arc<PromptProfiles>>,
+    name: String,
+    profile: PromptProfile,
+) -> Result<()> {
+    profiles.save(&name, &profile)?;
+    Ok(())
+}
+
+/// The prompt profile selected for the open project, `None` for the model's own prompts.
+#[tauri::command]
+pub async fn get_prompt_profile(state: State<'_, AppState>) -> Result<Option<String>> {
+    let state = state.read().await;
+    Ok(state.project.prompt_profile.clone())
+}
+
+#[tauri::command]
+pub async fn set_prompt_profile(
+    state: State<'_, AppState>,
+    profiles: State<'_, Arc<PromptProfiles>>,
+    name: Option<String>,
+) -> Result<()> {
+    if let Some(name) = &name {
+        profiles.load(name)?;
+    }
+    state.write().await.project.prompt_profile = name;
+    Ok(())
+}
+
+/// Sampling settings of the open project, `None` for the model's defaults.
+#[tauri::command]
+pub async fn get_generate_options(state: State<'_, AppState>) -> Result<Option<GenerateOptions>> {
+    let state = state.read().await;
+    Ok(state.project.generate_options.clone())
+}
+
+#[tauri::command]
+pub async fn set_generate_options(
+    state: State<'_, AppState>,
+    options: Option<GenerateOptions>,
+) -> Result<()> {
+    state.write().await.project.generate_options = options;
+    Ok(())
+}
+
+/// Reading order of the open project, `None` for manga order.
+#[tauri::command]
+pub async fn get_reading_order(state: State<'_, AppState>) -> Result<Option<ReadingOrder>> {
+    Ok(state.read().await.project.reading_order)
+}
+
+/// Takes effect on the next detection.
+#[tauri::command]
+pub async fn set_reading_order(
+    state: State<'_, AppState>,
+    reading_order: Option<ReadingOrder>,
+) -> Result<()> {
+    state.write().await.project.reading_order = reading_order;
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn export_document(state: State<'_, AppState>, index: usize) -> Result<()> {
     let mut state = state.write().await;
@@ -171,6 +285,87 @@ pub async fn export_all_documents(state: State<'_, AppState>) -> Result<()> {
     Ok(())
 }
 
+/// Pack every rendered page, in order, into a CBZ or PDF.
+#[tauri::command]
+pub async fn export_archive(
+    state: State<'_, AppState>,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let options = options.unwrap_or_default();
+    let state = state.read().await;
+
+    if state.documents.is_empty() {
+        return Ok(());
+    }
+
+    let extension = options.format.extension();
+    let Some(dest) = rfd::FileDialog::new()
+        .set_title("Select Export Destinition")
+        .add_filter(extension.to_uppercase(), &[extension])
+        .set_file_name(format!("project.{extension}"))
+        .save_file()
+    else {
+        return Ok(());
+    };
+
+    let title = options.title.clone().or_else(|| {
+        dest.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+    });
+    export::export_archive(&dest, &state.documents, ExportOptions { title, ..options })?;
+
+    Ok(())
+}
+
+/// Save the panels of every rendered page as separate images, or as one strip per page.
+#[tauri::command]
+pub async fn export_panels(state: State<'_, AppState>, layout: Option<PanelLayout>) -> Result<()> {
+    let state = state.read().await;
+
+    if state.documents.is_empty() {
+        return Ok(());
+    }
+
+    let Some(dest) = rfd::FileDialog::new()
+        .set_title("Select Export Destinition Folder")
+        .pick_folder()
+    else {
+        return Ok(());
+    };
+
+    export::export_panels(&dest, &state.documents, layout.unwrap_or_default())?;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn export_layered(
+    state: State<'_, AppState>,
+    index: usize,
+    format: Option<LayeredFormat>,
+) -> Result<()> {
+    let format = format.unwrap_or_default();
+    let state = state.read().await;
+    let document = state
+        .documents
+        .get(index)
+        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+
+    let extension = format.extension();
+    let Some(dest) = rfd::FileDialog::new()
+        .set_title("Select Export Destinition")
+        .add_filter(extension.to_uppercase(), &[extension])
+        .set_file_name(format!("{}.{extension}", document.name))
+        .save_file()
+    else {
+        return Ok(());
+    };
+
+    layered::export_layered(&dest, document, format)?;
+
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn save_documents(state: State<'_, AppState>) -> Result<()> {
     let state = state.read().await;
@@ -200,139 +395,233 @@ pub async fn save_documents(state: State<'_, AppState>) -> Result<()> {
         return Ok(());
     };
 
-    let bytes = serialize_khr(&state.documents)
+    write_khr(dest, &state.documents, &state.project)
         .map_err(|e| anyhow::anyhow!("Failed to serialize documents: {e}"))?;
-    std::fs::write(dest, bytes)?;
 
     Ok(())
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PipelineProgress {
+    index: usize,
+    #[serde(flatten)]
+    event: PipelineEvent,
+}
+
+/// Model output so far for the page (or single block) being translated.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LlmToken {
+    index: usize,
+    text_block_index: Option<usize>,
+    output: String,
+}
+
+/// Forward the model output to the UI as `llm:token` events while it is generated.
+fn emit_llm_output(
+    app: &AppHandle,
+    index: usize,
+    text_block_index: Option<usize>,
+) -> Arc<llm::OnOutput> {
+    let app = app.clone();
+    Arc::new(move |output| {
+        let token = LlmToken {
+            index,
+            text_block_index,
+            output: output.to_string(),
+        };
+        if let Err(err) = app.emit("llm:token", token) {
+            warn!(?err, "Failed to emit llm:token event");
+        }
+    })
+}
+
+/// Like [`emit_llm_output`] for blocks translated on their own, tagging each event with its block.
+fn emit_block_output(app: &AppHandle, index: usize) -> Arc<llm::OnBlockOutput> {
+    let app = app.clone();
+    Arc::new(move |text_block_index, output| {
+        let token = LlmToken {
+            index,
+            text_block_index: Some(text_block_index),
+            output: output.to_string(),
+        };
+        if let Err(err) = app.emit("llm:token", token) {
+            warn!(?err, "Failed to emit llm:token event");
+        }
+    })
+}
+
+/// Translate stage with the project glossary, remembering the translations of the other pages
+/// and replaying the `context_pages` before this one.
+///
+/// A single text block is always sent to the model, since retranslating it is an explicit request.
+fn translate_stage(
+    state: &crate::state::State,
+    profiles: &PromptProfiles,
+    index: usize,
+    text_block_index: Option<usize>,
+    language: Option<String>,
+    context_pages: usize,
+) -> anyhow::Result<pipeline::Translate> {
+    let memory = text_block_index.is_none().then(|| {
+        let others = state
+            .documents
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, document)| document);
+        Arc::new(Mutex::new(TranslationMemory::from_documents(others)))
+    });
+
+    let profile = match &state.project.prompt_profile {
+        Some(name) => Some(profiles.load(name)?),
+        None => None,
+    };
+
+    Ok(pipeline::Translate {
+        text_block_index,
+        language,
+        glossary: state.project.glossary.clone(),
+        profile,
+        options: state.project.generate_options.clone(),
+        context: llm::context_turns(&state.documents, index, text_block_index, context_pages),
+        memory,
+        ..Default::default()
+    })
+}
+
+async fn run_pipeline(
+    app: &AppHandle,
+    state: &AppState,
+    ctx: &pipeline::Context,
+    pipeline: &Pipeline,
+    index: usize,
+) -> Result<Document> {
+    let mut state = state.write().await;
+    let (document, history) = state.document_with_history(index)?;
+
+    let snapshot = pipeline.kinds().into_iter().fold(
+        Snapshot::new().text_blocks(document),
+        |snapshot, kind| match kind {
+            StageKind::Detect => snapshot.layer(document, Layer::Segment, None),
+            StageKind::Inpaint => snapshot.layer(document, Layer::Inpainted, None),
+            StageKind::Render => snapshot.layer(document, Layer::Rendered, None),
+            _ => snapshot,
+        },
+    );
+
+    let result = pipeline
+        .run(ctx, document, |event| {
+            if let Err(err) = app.emit("pipeline:progress", PipelineProgress { index, event }) {
+                warn!(?err, "Failed to emit pipeline:progress event");
+            }
+        })
+        .await;
+    history.record(snapshot.finish(document));
+    result?;
+
+    Ok(document.clone())
+}
+
 #[tauri::command]
 #[instrument(level = "info", skip_all)]
 pub async fn detect(
+    app: AppHandle,
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
     index: usize,
+    options: Option<DetectOptions>,
 ) -> Result<Document> {
-    let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-
-    let (text_blocks, segment) = model.detect_dialog(&document.image).await?;
-    document.text_blocks = text_blocks;
-    document.segment = Some(segment);
-
-    // detect fonts for each text block
-    if !document.text_blocks.is_empty() {
-        let images: Vec<image::DynamicImage> = document
-            .text_blocks
-            .iter()
-            .map(|block| {
-                document.image.crop_imm(
-                    block.x as u32,
-                    block.y as u32,
-                    block.width as u32,
-                    block.height as u32,
-                )
-            })
-            .collect();
-        let font_predictions = model.detect_fonts(&images, 1).await?;
-        for (block, prediction) in document
-            .text_blocks
-            .iter_mut()
-            .zip(font_predictions.into_iter())
-        {
-            tracing::debug!("Detected font for block {:?}: {:?}", block.text, prediction);
-
-            // fill style with prediction, and use default font families for now
-            let color = prediction.text_color;
-            let font_size = (prediction.font_size_px > 0.0).then_some(prediction.font_size_px);
-
-            block.font_prediction = Some(prediction);
-            block.style = Some(TextStyle {
-                font_size,
-                color: [color[0], color[1], color[2], 255],
-                ..Default::default()
-            });
-        }
-    }
+    let ctx = pipeline::Context::default().with_ml(model.inner().clone());
+    let detect = pipeline::Detect {
+        options: options.unwrap_or_default(),
+        reading_order: state.read().await.project.reading_order.unwrap_or_default(),
+        // the panels can be exported at any time
+        keep_panels: true,
+        ..Default::default()
+    };
+    let pipeline =
+        Pipeline::from_kinds(&[StageKind::Detect, StageKind::FontDetect]).replace_stage(detect);
 
-    Ok(document.clone())
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
 }
 
 #[tauri::command]
 #[instrument(level = "info", skip_all)]
 pub async fn ocr(
+    app: AppHandle,
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
     index: usize,
 ) -> Result<Document> {
-    let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let ctx = pipeline::Context::default().with_ml(model.inner().clone());
+    let pipeline = Pipeline::from_kinds(&[StageKind::Ocr]);
 
-    let text_blocks = model.ocr(&document.image, &document.text_blocks).await?;
-    document.text_blocks = text_blocks;
-
-    Ok(document.clone())
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
 }
 
 #[tauri::command]
 #[instrument(level = "info", skip_all)]
 pub async fn inpaint(
+    app: AppHandle,
     state: State<'_, AppState>,
     model: State<'_, Arc<ml::Model>>,
     index: usize,
 ) -> Result<Document> {
-    let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-
-    let segment = document
-        .segment
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("Segment image not found"))?;
+    let ctx = pipeline::Context::default().with_ml(model.inner().clone());
+    let pipeline = Pipeline::from_kinds(&[StageKind::Inpaint]);
 
-    let text_blocks = document.text_blocks.clone();
-
-    // for every pixel in segment_ref that is not black, check if it's inside any text block, else set to black
-    let mut segment_data = segment.to_rgba8();
-    let (seg_width, seg_height) = segment_data.dimensions();
-    for y in 0..seg_height {
-        for x in 0..seg_width {
-            let pixel = segment_data.get_pixel_mut(x, y);
-            if pixel.0 != [0, 0, 0, 255] {
-                let mut inside_any_block = false;
-                for block in &text_blocks {
-                    if x >= block.x as u32
-                        && x < (block.x + block.width) as u32
-                        && y >= block.y as u32
-                        && y < (block.y + block.height) as u32
-                    {
-                        inside_any_block = true;
-                        break;
-                    }
-                }
-                if !inside_any_block {
-                    *pixel = image::Rgba([0, 0, 0, 255]);
-                }
-            }
-        }
-    }
-
-    let mask = SerializableDynamicImage::from(image::DynamicImage::ImageRgba8(segment_data));
-
-    let inpainted = model.inpaint(&document.image, &mask).await?;
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
+}
 
-    document.inpainted = Some(inpainted);
+#[tauri::command]
+#[instrument(level = "info", skip_all)]
+pub async fn process(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    model: State<'_, Arc<ml::Model>>,
+    llm: State<'_, Arc<llm::Model>>,
+    renderer: State<'_, Arc<Renderer>>,
+    index: usize,
+    stages: Vec<StageKind>,
+) -> Result<Document> {
+    let ctx = pipeline::Context::default()
+        .with_ml(model.inner().clone())
+        .with_llm(llm.inner().clone())
+        .with_renderer(renderer.inner().clone());
+    let reading_order = state.read().await.project.reading_order.unwrap_or_default();
+    // a repeated translate stage falls back to the defaults
+    let mut translate = Some(pipeline::Translate {
+        on_output: Some(emit_llm_output(&app, index, None)),
+        ..translate_stage(
+            &*state.read().await,
+            &app.state::<Arc<PromptProfiles>>(),
+            index,
+            None,
+            None,
+            llm::DEFAULT_CONTEXT_PAGES,
+        )?
+    });
+    let pipeline = Pipeline::from_stages(
+        stages
+            .iter()
+            .map(|kind| match kind {
+                StageKind::Detect => Box::new(pipeline::Detect {
+                    reading_order,
+                    keep_panels: true,
+                    ..Default::default()
+                }) as Box<dyn Stage>,
+                StageKind::Translate => match translate.take() {
+                    Some(translate) => Box::new(translate) as Box<dyn Stage>,
+                    None => kind.stage(),
+                },
+                kind => kind.stage(),
+            })
+            .collect(),
+    );
 
-    Ok(document.clone())
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
 }
 
 #[tauri::command]
@@ -344,10 +633,7 @@ pub async fn update_inpaint_mask(
     region: Option<InpaintRegion>,
 ) -> Result<Document> {
     let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let (document, history) = state.document_with_history(index)?;
 
     let update_image = image::load_from_memory(&mask)
         .map_err(|e| anyhow::anyhow!("Failed to decode mask: {e}"))?;
@@ -364,6 +650,7 @@ pub async fn update_inpaint_mask(
         })
         .to_rgba8();
 
+    let snapshot;
     match region {
         Some(region) => {
             let (patch_width, patch_height) = update_image.dimensions();
@@ -385,6 +672,8 @@ pub async fn update_inpaint_mask(
             if x1 <= x0 || y1 <= y0 {
                 return Ok(document.clone());
             }
+            snapshot =
+                Snapshot::new().layer(document, Layer::Segment, Some((x0, y0, x1 - x0, y1 - y0)));
 
             let dest_width = x1 - x0;
             let dest_height = y1 - y0;
@@ -408,11 +697,13 @@ pub async fn update_inpaint_mask(
                 .into());
             }
 
+            snapshot = Snapshot::new().layer(document, Layer::Segment, None);
             base_mask = update_image.to_rgba8();
         }
     }
 
     document.segment = Some(image::DynamicImage::ImageRgba8(base_mask).into());
+    history.record(snapshot.finish(document));
 
     Ok(document.clone())
 }
@@ -426,10 +717,7 @@ pub async fn update_brush_layer(
     region: InpaintRegion,
 ) -> Result<Document> {
     let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let (document, history) = state.document_with_history(index)?;
 
     let (img_width, img_height) = (document.width, document.height);
     let Some((x0, y0, width, height)) = clamp_region(&region, img_width, img_height) else {
@@ -451,6 +739,8 @@ pub async fn update_brush_layer(
     }
 
     let brush_rgba = patch_image.to_rgba8();
+    let snapshot =
+        Snapshot::new().layer(document, Layer::BrushLayer, Some((x0, y0, width, height)));
 
     let mut brush_layer = document
         .brush_layer
@@ -468,6 +758,7 @@ pub async fn update_brush_layer(
     }
 
     document.brush_layer = Some(image::DynamicImage::ImageRgba8(brush_layer).into());
+    history.record(snapshot.finish(document));
 
     Ok(document.clone())
 }
@@ -481,10 +772,7 @@ pub async fn inpaint_partial(
     region: InpaintRegion,
 ) -> Result<Document> {
     let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let (document, history) = state.document_with_history(index)?;
 
     let mask_image = document
         .segment
@@ -551,7 +839,13 @@ pub async fn inpaint_partial(
         }
     }
 
+    let snapshot = Snapshot::new().layer(
+        document,
+        Layer::Inpainted,
+        Some((x0, y0, crop_width, crop_height)),
+    );
     document.inpainted = Some(image::DynamicImage::ImageRgba8(stitched).into());
+    history.record(snapshot.finish(document));
 
     Ok(document.clone())
 }
@@ -559,25 +853,20 @@ pub async fn inpaint_partial(
 #[tauri::command]
 #[instrument(level = "info", skip_all)]
 pub async fn render(
+    app: AppHandle,
     state: State<'_, AppState>,
     renderer: State<'_, Arc<Renderer>>,
     index: usize,
     text_block_index: Option<usize>,
     shader_effect: Option<TextShaderEffect>,
 ) -> Result<Document> {
-    let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-
-    renderer.render(
-        document,
+    let ctx = pipeline::Context::default().with_renderer(renderer.inner().clone());
+    let pipeline = Pipeline::new().with_stage(pipeline::Render {
         text_block_index,
-        shader_effect.unwrap_or_default(),
-    )?;
+        effect: shader_effect.unwrap_or_default(),
+    });
 
-    Ok(document.clone())
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
 }
 
 #[tauri::command]
@@ -587,23 +876,84 @@ pub async fn update_text_blocks(
     text_blocks: Vec<TextBlock>,
 ) -> Result<Document> {
     let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let (document, history) = state.document_with_history(index)?;
 
+    let snapshot = Snapshot::new().text_blocks(document);
     document.text_blocks = text_blocks;
+    history.record(snapshot.finish(document));
+
+    Ok(document.clone())
+}
+
+/// Revert the latest edit of a page. Text blocks that had a rendered bitmap get it again with
+/// `shader_effect`, since the history doesn't keep them.
+#[tauri::command]
+pub async fn undo(
+    state: State<'_, AppState>,
+    renderer: State<'_, Arc<Renderer>>,
+    index: usize,
+    shader_effect: Option<TextShaderEffect>,
+) -> Result<Document> {
+    let mut state = state.write().await;
+    let (document, history) = state.document_with_history(index)?;
+
+    if let Some(stale) = history.undo(document) {
+        render_text_blocks(
+            &renderer,
+            document,
+            &stale,
+            shader_effect.unwrap_or_default(),
+        )?;
+    }
 
     Ok(document.clone())
 }
 
+/// Reapply the latest undone edit of a page, rendering its text blocks again like [`undo`].
+#[tauri::command]
+pub async fn redo(
+    state: State<'_, AppState>,
+    renderer: State<'_, Arc<Renderer>>,
+    index: usize,
+    shader_effect: Option<TextShaderEffect>,
+) -> Result<Document> {
+    let mut state = state.write().await;
+    let (document, history) = state.document_with_history(index)?;
+
+    if let Some(stale) = history.redo(document) {
+        render_text_blocks(
+            &renderer,
+            document,
+            &stale,
+            shader_effect.unwrap_or_default(),
+        )?;
+    }
+
+    Ok(document.clone())
+}
+
+fn render_text_blocks(
+    renderer: &Renderer,
+    document: &mut Document,
+    text_block_indices: &[usize],
+    effect: TextShaderEffect,
+) -> anyhow::Result<()> {
+    for &text_block_index in text_block_indices {
+        renderer.render(document, Some(text_block_index), effect)?;
+    }
+    Ok(())
+}
+
 #[tauri::command]
 pub fn list_font_families(renderer: State<'_, Arc<Renderer>>) -> Result<Vec<String>> {
     Ok(renderer.available_fonts()?)
 }
 
 #[tauri::command]
-pub fn llm_list(model: State<'_, Arc<llm::Model>>) -> Vec<llm::ModelInfo> {
+pub fn llm_list(
+    model: State<'_, Arc<llm::Model>>,
+    registry: State<'_, Arc<llm::Registry>>,
+) -> Vec<llm::ModelInfo> {
     let mut models: Vec<ModelId> = ModelId::iter().collect();
 
     let cpu_factor = match model.is_cpu() {
@@ -628,19 +978,69 @@ pub fn llm_list(model: State<'_, Arc<llm::Model>>) -> Vec<llm::ModelInfo> {
         ModelId::SakuraGalTransl7Bv3_7 => 300 / zh_locale_factor,
         ModelId::Sakura1_5bQwen2_5v1_0 => 400 / zh_locale_factor / cpu_factor,
         ModelId::HunyuanMT7B => 500 / non_zh_en_locale_factor,
+        ModelId::Qwen3_4B => 600 / non_zh_en_locale_factor,
+        ModelId::Gemma3_4B => 700 / non_zh_en_locale_factor,
+        ModelId::Gemma2_2B => 800 / cpu_factor,
+        ModelId::MistralNemo12B => 900,
     });
 
-    models.into_iter().map(llm::ModelInfo::new).collect()
+    models
+        .into_iter()
+        .map(llm::ModelInfo::new)
+        .chain(registry.models().iter().map(llm::ModelInfo::custom))
+        .chain([llm::ModelInfo::openai_compatible()])
+        .collect()
 }
 
 #[tauri::command]
 #[instrument(level = "info", skip_all)]
-pub async fn llm_load(model: State<'_, Arc<llm::Model>>, id: String) -> Result<()> {
+pub async fn llm_load(
+    model: State<'_, Arc<llm::Model>>,
+    registry: State<'_, Arc<llm::Registry>>,
+    id: String,
+    openai: Option<OpenAiConfig>,
+) -> Result<()> {
+    if id == llm::OPENAI_COMPATIBLE_ID {
+        model.connect(openai.unwrap_or_default()).await?;
+        return Ok(());
+    }
+    if let Some(custom) = registry.get(&id) {
+        model.load_custom(custom).await;
+        return Ok(());
+    }
     let id = ModelId::from_str(&id)?;
     model.load(id).await;
     Ok(())
 }
 
+/// Add a GGUF file from disk to the model list, asking for one when `path` is missing.
+#[tauri::command]
+pub fn llm_register(
+    registry: State<'_, Arc<llm::Registry>>,
+    path: Option<std::path::PathBuf>,
+    prompt: Option<String>,
+) -> Result<Option<llm::ModelInfo>> {
+    let path = match path {
+        Some(path) => path,
+        None => match rfd::FileDialog::new()
+            .add_filter("GGUF Models", &["gguf"])
+            .set_title("Pick Model")
+            .pick_file()
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+    };
+    let model = registry.register(&path, prompt)?;
+    Ok(Some(llm::ModelInfo::custom(&model)))
+}
+
+#[tauri::command]
+pub fn llm_unregister(registry: State<'_, Arc<llm::Registry>>, id: String) -> Result<()> {
+    registry.unregister(&id)?;
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn llm_offload(model: State<'_, Arc<llm::Model>>) -> Result<()> {
     model.offload().await;
@@ -652,38 +1052,93 @@ pub async fn llm_ready(model: State<'_, Arc<llm::Model>>) -> Result<bool> {
     Ok(model.ready().await)
 }
 
+/// How a page is translated by [`llm_generate`] and [`llm_generate_blocks`].
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranslateRequest {
+    /// Language to translate into, the one of the system when missing.
+    pub language: Option<String>,
+    /// Pages before this one replayed as context, [`llm::DEFAULT_CONTEXT_PAGES`] when missing.
+    pub context_pages: Option<usize>,
+    /// Replaces the generation options of the project.
+    pub options: Option<GenerateOptions>,
+}
+
+impl TranslateRequest {
+    fn stage(
+        self,
+        state: &crate::state::State,
+        profiles: &PromptProfiles,
+        index: usize,
+        text_block_index: Option<usize>,
+    ) -> anyhow::Result<pipeline::Translate> {
+        let mut translate = translate_stage(
+            state,
+            profiles,
+            index,
+            text_block_index,
+            self.language,
+            self.context_pages.unwrap_or(llm::DEFAULT_CONTEXT_PAGES),
+        )?;
+        if self.options.is_some() {
+            translate.options = self.options;
+        }
+        Ok(translate)
+    }
+}
+
 #[tauri::command]
 #[instrument(level = "info", skip_all)]
 pub async fn llm_generate(
+    app: AppHandle,
     state: State<'_, AppState>,
     model: State<'_, Arc<llm::Model>>,
+    profiles: State<'_, Arc<PromptProfiles>>,
     index: usize,
     text_block_index: Option<usize>,
-    language: Option<String>,
+    request: Option<TranslateRequest>,
 ) -> Result<Document> {
-    let mut state = state.write().await;
-    let document = state
-        .documents
-        .get_mut(index)
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let ctx = pipeline::Context::default().with_llm(model.inner().clone());
+    let translate = pipeline::Translate {
+        on_output: Some(emit_llm_output(&app, index, text_block_index)),
+        ..request.unwrap_or_default().stage(
+            &*state.read().await,
+            &profiles,
+            index,
+            text_block_index,
+        )?
+    };
+    let pipeline = Pipeline::new().with_stage(translate);
 
-    if let Some(locale) = language.as_ref() {
-        set_locale(locale.clone());
-    }
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
+}
 
-    match text_block_index {
-        Some(bi) => {
-            let text_block = document
-                .text_blocks
-                .get_mut(bi)
-                .ok_or_else(|| anyhow::anyhow!("Text block not found"))?;
+/// Translate every block of a page with its own prompt, decoded together by models that support
+/// it. The output of each block is emitted as `llm:token` events with its index.
+#[tauri::command]
+pub async fn llm_generate_blocks(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    model: State<'_, Arc<llm::Model>>,
+    profiles: State<'_, Arc<PromptProfiles>>,
+    index: usize,
+    request: Option<TranslateRequest>,
+) -> Result<Document> {
+    let ctx = pipeline::Context::default().with_llm(model.inner().clone());
+    let translate = pipeline::Translate {
+        per_block: true,
+        on_block_output: Some(emit_block_output(&app, index)),
+        ..request
+            .unwrap_or_default()
+            .stage(&*state.read().await, &profiles, index, None)?
+    };
+    let pipeline = Pipeline::new().with_stage(translate);
 
-            model.generate(text_block).await?;
-        }
-        None => {
-            model.generate(document).await?;
-        }
-    }
+    run_pipeline(&app, &state, &ctx, &pipeline, index).await
+}
 
-    Ok(document.clone())
+/// Stop the running translation, which then fails like any other stage.
+#[tauri::command]
+pub fn llm_cancel(model: State<'_, Arc<llm::Model>>) {
+    model.cancel();
 }
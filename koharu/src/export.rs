@@ -0,0 +1,602 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Cursor, Seek, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use flate2::{Compression, write::ZlibEncoder};
+use image::{
+    ColorType, DynamicImage, GenericImageView, ImageFormat, Rgba, RgbaImage,
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops,
+};
+use koharu_ml::panel_detector::Panel;
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::state::Document;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum ArchiveFormat {
+    #[default]
+    Cbz,
+    Pdf,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Cbz => "cbz",
+            ArchiveFormat::Pdf => "pdf",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageCodec {
+    #[default]
+    Jpeg,
+    Png,
+    /// Lossless WebP. PDF has no WebP filter, so pages are stored deflated instead.
+    Webp,
+}
+
+impl ImageCodec {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageCodec::Jpeg => "jpg",
+            ImageCodec::Png => "png",
+            ImageCodec::Webp => "webp",
+        }
+    }
+}
+
+/// How the panels of a page are exported.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum PanelLayout {
+    /// One image per panel.
+    #[default]
+    Separate,
+    /// The panels stacked into one strip per page, for vertical-scroll reading.
+    Strip,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportOptions {
+    pub format: ArchiveFormat,
+    pub codec: ImageCodec,
+    /// JPEG quality from 1 to 100, ignored by lossless codecs.
+    pub quality: u8,
+    /// Title written to ComicInfo.xml or the PDF metadata.
+    pub title: Option<String>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: ArchiveFormat::default(),
+            codec: ImageCodec::default(),
+            quality: 90,
+            title: None,
+        }
+    }
+}
+
+fn rendered(document: &Document) -> anyhow::Result<&DynamicImage> {
+    document
+        .rendered
+        .as_deref()
+        .with_context(|| format!("No rendered image found for {}", document.name))
+}
+
+fn encode_image(image: &DynamicImage, codec: ImageCodec, quality: u8) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match codec {
+        ImageCodec::Jpeg => {
+            JpegEncoder::new_with_quality(&mut buf, quality.clamp(1, 100))
+                .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?;
+        }
+        ImageCodec::Png => image.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?,
+        ImageCodec::Webp => {
+            let rgba = image.to_rgba8();
+            WebPEncoder::new_lossless(&mut buf).encode(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                ColorType::Rgba8.into(),
+            )?;
+        }
+    }
+    Ok(buf)
+}
+
+/// Writes rendered pages into a CBZ or PDF one page at a time.
+pub enum ArchiveWriter<W: Write + Seek> {
+    Cbz(Box<CbzWriter<W>>),
+    Pdf(PdfWriter<W>),
+}
+
+impl ArchiveWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, options: ExportOptions) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Self::new(BufWriter::new(file), options)
+    }
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(writer: W, options: ExportOptions) -> anyhow::Result<Self> {
+        Ok(match options.format {
+            ArchiveFormat::Cbz => ArchiveWriter::Cbz(Box::new(CbzWriter::new(writer, options))),
+            ArchiveFormat::Pdf => ArchiveWriter::Pdf(PdfWriter::new(writer, options)?),
+        })
+    }
+
+    pub fn add_page(&mut self, document: &Document) -> anyhow::Result<()> {
+        match self {
+            ArchiveWriter::Cbz(writer) => writer.add_page(document),
+            ArchiveWriter::Pdf(writer) => writer.add_page(document),
+        }
+    }
+
+    pub fn finish(self) -> anyhow::Result<W> {
+        match self {
+            ArchiveWriter::Cbz(writer) => writer.finish(),
+            ArchiveWriter::Pdf(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Pack the rendered image of every document, in order, into `path`.
+pub fn export_archive(
+    path: impl AsRef<Path>,
+    documents: &[Document],
+    options: ExportOptions,
+) -> anyhow::Result<()> {
+    let mut writer = ArchiveWriter::create(path, options)?;
+    for document in documents {
+        writer.add_page(document)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Space between panels stacked into a strip, in pixels.
+const STRIP_GAP: u32 = 48;
+
+/// Save the panels of every rendered page into `dir` as PNGs.
+pub fn export_panels(
+    dir: impl AsRef<Path>,
+    documents: &[Document],
+    layout: PanelLayout,
+) -> anyhow::Result<()> {
+    let dir = dir.as_ref();
+    for document in documents {
+        let panels = panel_images(document)?;
+        if layout == PanelLayout::Strip {
+            let path = dir.join(format!("{}_strip.png", document.name));
+            vertical_strip(&panels)
+                .save(&path)
+                .with_context(|| format!("Failed to save {}", path.display()))?;
+            continue;
+        }
+        for (index, panel) in panels.iter().enumerate() {
+            let path = dir.join(format!("{}_{:02}.png", document.name, index + 1));
+            panel
+                .save(&path)
+                .with_context(|| format!("Failed to save {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The panels of the rendered page in reading order, each cropped to its outline with what lies
+/// outside the outline blanked. A page without panels is a single panel.
+pub fn panel_images(document: &Document) -> anyhow::Result<Vec<DynamicImage>> {
+    let image = rendered(document)?;
+    if document.panels.is_empty() {
+        return Ok(vec![image.clone()]);
+    }
+    Ok(document
+        .panels
+        .iter()
+        .map(|panel| crop_panel(image, panel))
+        .collect())
+}
+
+fn crop_panel(image: &DynamicImage, panel: &Panel) -> DynamicImage {
+    let [x0, y0, x1, y1] = panel.bbox();
+    let (x1, y1) = (x1.min(image.width()), y1.min(image.height()));
+    let (width, height) = (x1.saturating_sub(x0), y1.saturating_sub(y0));
+    let mut crop = image.crop_imm(x0, y0, width, height).to_rgba8();
+    for (x, y, pixel) in crop.enumerate_pixels_mut() {
+        if !panel.contains((x0 + x) as f32, (y0 + y) as f32) {
+            *pixel = Rgba([255, 255, 255, 255]);
+        }
+    }
+    DynamicImage::ImageRgba8(crop)
+}
+
+/// Stack `panels` top to bottom, centered on a white strip as wide as the widest panel.
+pub fn vertical_strip(panels: &[DynamicImage]) -> DynamicImage {
+    let width = panels.iter().map(|panel| panel.width()).max().unwrap_or(1);
+    let height = panels.iter().map(|panel| panel.height()).sum::<u32>()
+        + STRIP_GAP * panels.len().saturating_sub(1) as u32;
+    let mut strip = RgbaImage::from_pixel(width, height.max(1), Rgba([255, 255, 255, 255]));
+
+    let mut y = 0;
+    for panel in panels {
+        let x = (width - panel.width()) / 2;
+        imageops::overlay(&mut strip, &panel.to_rgba8(), x as i64, y as i64);
+        y += panel.height() + STRIP_GAP;
+    }
+    DynamicImage::ImageRgba8(strip)
+}
+
+struct ComicPage {
+    width: u32,
+    height: u32,
+    size: usize,
+}
+
+pub struct CbzWriter<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    options: ExportOptions,
+    pages: Vec<ComicPage>,
+}
+
+impl<W: Write + Seek> CbzWriter<W> {
+    pub fn new(writer: W, options: ExportOptions) -> Self {
+        Self {
+            zip: ZipWriter::new(writer),
+            options,
+            pages: Vec::new(),
+        }
+    }
+
+    pub fn add_page(&mut self, document: &Document) -> anyhow::Result<()> {
+        let image = rendered(document)?;
+        let bytes = encode_image(image, self.options.codec, self.options.quality)?;
+
+        // pages are already compressed, zero padding keeps readers' name ordering right
+        let name = format!(
+            "{:04}.{}",
+            self.pages.len() + 1,
+            self.options.codec.extension()
+        );
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        self.zip.start_file(name, options)?;
+        self.zip.write_all(&bytes)?;
+
+        self.pages.push(ComicPage {
+            width: image.width(),
+            height: image.height(),
+            size: bytes.len(),
+        });
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file("ComicInfo.xml", options)?;
+        self.zip.write_all(self.comic_info().as_bytes())?;
+        Ok(self.zip.finish()?)
+    }
+
+    fn comic_info(&self) -> String {
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+             xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n",
+        );
+        if let Some(title) = self.options.title.as_deref() {
+            xml.push_str(&format!("  <Title>{}</Title>\n", escape_xml(title)));
+        }
+        xml.push_str(&format!("  <PageCount>{}</PageCount>\n", self.pages.len()));
+        xml.push_str("  <Pages>\n");
+        for (index, page) in self.pages.iter().enumerate() {
+            xml.push_str(&format!(
+                "    <Page Image=\"{index}\" ImageSize=\"{}\" ImageWidth=\"{}\" ImageHeight=\"{}\" />\n",
+                page.size, page.width, page.height
+            ));
+        }
+        xml.push_str("  </Pages>\n</ComicInfo>\n");
+        xml
+    }
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+const PDF_CATALOG: usize = 1;
+const PDF_PAGES: usize = 2;
+
+/// Minimal PDF writer with one full-page image per page, sized 1pt per pixel.
+pub struct PdfWriter<W: Write> {
+    writer: W,
+    options: ExportOptions,
+    position: usize,
+    /// Byte offset of every object, indexed by object number - 1.
+    offsets: Vec<usize>,
+    kids: Vec<usize>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    pub fn new(writer: W, options: ExportOptions) -> anyhow::Result<Self> {
+        let mut pdf = Self {
+            writer,
+            options,
+            position: 0,
+            offsets: Vec::new(),
+            kids: Vec::new(),
+        };
+        pdf.write(b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n")?;
+
+        // the page tree is written last, once all its kids are known
+        pdf.reserve();
+        pdf.reserve();
+        Ok(pdf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len();
+        Ok(())
+    }
+
+    fn reserve(&mut self) -> usize {
+        self.offsets.push(0);
+        self.offsets.len()
+    }
+
+    fn object(&mut self, id: usize, dict: &str, stream: Option<&[u8]>) -> anyhow::Result<()> {
+        self.offsets[id - 1] = self.position;
+        self.write(format!("{id} 0 obj\n{dict}\n").as_bytes())?;
+        if let Some(stream) = stream {
+            self.write(b"stream\n")?;
+            self.write(stream)?;
+            self.write(b"\nendstream\n")?;
+        }
+        self.write(b"endobj\n")
+    }
+
+    pub fn add_page(&mut self, document: &Document) -> anyhow::Result<()> {
+        let image = rendered(document)?;
+        let (width, height) = image.dimensions();
+
+        let (filter, data) = match self.options.codec {
+            ImageCodec::Jpeg => (
+                "/DCTDecode",
+                encode_image(image, ImageCodec::Jpeg, self.options.quality)?,
+            ),
+            ImageCodec::Png | ImageCodec::Webp => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(image.to_rgb8().as_raw())?;
+                ("/FlateDecode", encoder.finish()?)
+            }
+        };
+
+        let image_id = self.reserve();
+        self.object(
+            image_id,
+            &format!(
+                "<< /Type /XObject /Subtype /Image /Width {width} /Height {height} \
+                 /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter {filter} /Length {} >>",
+                data.len()
+            ),
+            Some(&data),
+        )?;
+
+        let content = format!("q {width} 0 0 {height} 0 0 cm /Im0 Do Q");
+        let content_id = self.reserve();
+        self.object(
+            content_id,
+            &format!("<< /Length {} >>", content.len()),
+            Some(content.as_bytes()),
+        )?;
+
+        let page_id = self.reserve();
+        self.object(
+            page_id,
+            &format!(
+                "<< /Type /Page /Parent {PDF_PAGES} 0 R /MediaBox [0 0 {width} {height}] \
+                 /Resources << /XObject << /Im0 {image_id} 0 R >> >> /Contents {content_id} 0 R >>"
+            ),
+            None,
+        )?;
+        self.kids.push(page_id);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let kids = self
+            .kids
+            .iter()
+            .map(|id| format!("{id} 0 R"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.object(
+            PDF_PAGES,
+            &format!(
+                "<< /Type /Pages /Kids [{kids}] /Count {} >>",
+                self.kids.len()
+            ),
+            None,
+        )?;
+        self.object(
+            PDF_CATALOG,
+            &format!("<< /Type /Catalog /Pages {PDF_PAGES} 0 R >>"),
+            None,
+        )?;
+
+        let title = match self.options.title.as_deref() {
+            Some(title) => format!(" /Title {}", pdf_text(title)),
+            None => String::new(),
+        };
+        let info_id = self.reserve();
+        self.object(info_id, &format!("<< /Producer (Koharu){title} >>"), None)?;
+
+        let xref = self.position;
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f\r\n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            table.push_str(&format!("{offset:010} 00000 n\r\n"));
+        }
+        table.push_str(&format!(
+            "trailer\n<< /Size {} /Root {PDF_CATALOG} 0 R /Info {info_id} 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            self.offsets.len() + 1
+        ));
+        self.write(table.as_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// UTF-16BE hex string, which every reader decodes regardless of the characters used.
+fn pdf_text(text: &str) -> String {
+    let hex = text
+        .encode_utf16()
+        .map(|unit| format!("{unit:04X}"))
+        .collect::<String>();
+    format!("<FEFF{hex}>")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    /// Sizes and shades of the exported pages, in order.
+    const PAGES: [(u32, u32, u8); 2] = [(40, 30, 10), (20, 50, 200)];
+
+    fn export(options: ExportOptions) -> anyhow::Result<Vec<u8>> {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), options)?;
+        for (index, (width, height, shade)) in PAGES.into_iter().enumerate() {
+            let image = RgbaImage::from_pixel(width, height, Rgba([shade, 0, 255 - shade, 255]));
+            writer.add_page(&Document {
+                name: index.to_string(),
+                width,
+                height,
+                rendered: Some(DynamicImage::ImageRgba8(image).into()),
+                ..Default::default()
+            })?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    #[test]
+    fn cbz_pages_read_back_in_order() -> anyhow::Result<()> {
+        let bytes = export(ExportOptions {
+            codec: ImageCodec::Png,
+            title: Some("Koharu & co".to_string()),
+            ..Default::default()
+        })?;
+        let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+        let names = (0..zip.len())
+            .map(|index| Ok(zip.by_index(index)?.name().to_string()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(names, ["0001.png", "0002.png", "ComicInfo.xml"]);
+
+        for (index, (width, height, shade)) in PAGES.into_iter().enumerate() {
+            let mut bytes = Vec::new();
+            zip.by_index(index)?.read_to_end(&mut bytes)?;
+            let image = image::load_from_memory(&bytes)?;
+            assert_eq!(image.dimensions(), (width, height));
+            assert_eq!(image.get_pixel(0, 0).0, [shade, 0, 255 - shade, 255]);
+        }
+
+        let mut comic_info = String::new();
+        zip.by_name("ComicInfo.xml")?
+            .read_to_string(&mut comic_info)?;
+        assert!(comic_info.contains("<Title>Koharu &amp; co</Title>"));
+        assert!(comic_info.contains("<PageCount>2</PageCount>"));
+        assert!(comic_info.contains("ImageWidth=\"20\" ImageHeight=\"50\""));
+        Ok(())
+    }
+
+    #[test]
+    fn pdf_pages_read_back_in_order() -> anyhow::Result<()> {
+        for codec in [ImageCodec::Jpeg, ImageCodec::Png] {
+            let bytes = export(ExportOptions {
+                format: ArchiveFormat::Pdf,
+                codec,
+                title: Some("コハル".to_string()),
+                ..Default::default()
+            })?;
+
+            // every cross-reference entry points at the start of its object
+            let startxref = bytes
+                .windows(10)
+                .rposition(|window| window == b"startxref\n")
+                .context("no startxref")?;
+            let trailer = std::str::from_utf8(&bytes[startxref + 10..])?;
+            let xref = trailer
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .parse::<usize>()?;
+            let table = std::str::from_utf8(&bytes[xref..])?;
+            let mut lines = table.lines();
+            assert_eq!(lines.next(), Some("xref"));
+            let size = lines
+                .next()
+                .and_then(|line| line.strip_prefix("0 "))
+                .context("no xref subsection")?
+                .parse::<usize>()?;
+            // object 0 is the head of the free list
+            for (id, entry) in lines.skip(1).take(size - 1).enumerate() {
+                let offset = entry[..10].parse::<usize>()?;
+                assert!(bytes[offset..].starts_with(format!("{} 0 obj", id + 1).as_bytes()));
+            }
+
+            let pdf = lopdf::Document::load_mem(&bytes)?;
+            let pages = pdf.get_pages();
+            assert_eq!(pages.len(), PAGES.len());
+            for ((_, page_id), (width, height, shade)) in pages.into_iter().zip(PAGES) {
+                let media_box = pdf
+                    .get_object(page_id)?
+                    .as_dict()?
+                    .get(b"MediaBox")?
+                    .as_array()?
+                    .iter()
+                    .map(|value| value.as_i64())
+                    .collect::<Result<Vec<_>, _>>()?;
+                assert_eq!(media_box, [0, 0, width as i64, height as i64]);
+
+                let images = pdf.get_page_images(page_id)?;
+                assert_eq!(images.len(), 1);
+                assert_eq!(
+                    (images[0].width, images[0].height),
+                    (width as i64, height as i64)
+                );
+                if codec == ImageCodec::Png {
+                    let pixels = pdf
+                        .get_object(images[0].id)?
+                        .as_stream()?
+                        .decompressed_content()?;
+                    assert_eq!(pixels.len(), (width * height * 3) as usize);
+                    assert_eq!(pixels[..3], [shade, 0, 255 - shade]);
+                }
+            }
+
+            let info = pdf.trailer.get(b"Info")?.as_reference()?;
+            let title = pdf.get_object(info)?.as_dict()?.get(b"Title")?;
+            assert_eq!(lopdf::decode_text_string(title)?, "コハル");
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,35 @@
 use std::ops::Deref;
 
-use image::{ColorType, DynamicImage, codecs::webp::WebPEncoder};
+use image::{ColorType, DynamicImage, ImageResult, codecs::webp::WebPEncoder};
 use serde::{Deserialize, Serialize, Serializer};
 
 #[derive(Debug, Default, Clone)]
 pub struct SerializableDynamicImage(pub DynamicImage);
 
-impl Serialize for SerializableDynamicImage {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
+impl SerializableDynamicImage {
+    /// The image as lossless WebP, the form it is serialized in.
+    pub fn encode(&self) -> ImageResult<Vec<u8>> {
         let rgba = self.0.to_rgba8();
         let (width, height) = rgba.dimensions();
         let raw = rgba.into_raw();
 
         let mut buf = Vec::new();
         let enc = WebPEncoder::new_lossless(&mut buf);
-        enc.encode(&raw, width, height, ColorType::Rgba8.into())
-            .map_err(serde::ser::Error::custom)?;
+        enc.encode(&raw, width, height, ColorType::Rgba8.into())?;
+        Ok(buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> ImageResult<Self> {
+        Ok(SerializableDynamicImage(image::load_from_memory(bytes)?))
+    }
+}
 
+impl Serialize for SerializableDynamicImage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let buf = self.encode().map_err(serde::ser::Error::custom)?;
         serde_bytes::serialize(&buf, serializer)
     }
 }
@@ -30,8 +40,7 @@ impl<'de> Deserialize<'de> for SerializableDynamicImage {
         D: serde::Deserializer<'de>,
     {
         let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
-        let img = image::load_from_memory(&bytes).map_err(serde::de::Error::custom)?;
-        Ok(SerializableDynamicImage(img))
+        Self::decode(&bytes).map_err(serde::de::Error::custom)
     }
 }
 
@@ -0,0 +1,327 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufReader, Read, Seek},
+    iter::Peekable,
+    path::Path,
+    str::Chars,
+};
+
+use anyhow::{Context, bail};
+use image::{DynamicImage, GrayImage, RgbImage};
+use lopdf::xobject::PdfImage;
+use tracing::warn;
+
+use crate::state::Document;
+
+const ARCHIVE_EXTENSIONS: &[&str] = &["cbz", "zip", "cbr", "rar", "pdf"];
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "bmp", "gif", "tif", "tiff"];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const RAR_MAGIC: &[u8] = b"Rar!\x1a\x07";
+const PDF_MAGIC: &[u8] = b"%PDF";
+
+/// Whether `path` looks like an archive or PDF that [`import`] can expand into pages.
+pub fn is_importable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ARCHIVE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Expand a CBZ/ZIP archive or a PDF into one document per page.
+///
+/// The container is detected from its content, so a `.cbr` that is really a zip still opens.
+pub fn import(path: &Path) -> anyhow::Result<Vec<Document>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut magic = [0u8; 8];
+    let read = file.read(&mut magic)?;
+    let magic = &magic[..read];
+    file.rewind()?;
+
+    let documents = if magic.starts_with(ZIP_MAGIC) {
+        import_zip(path, BufReader::new(file))?
+    } else if magic.starts_with(PDF_MAGIC) {
+        import_pdf(path)?
+    } else if magic.starts_with(RAR_MAGIC) {
+        bail!(
+            "RAR archives (CBR) are not supported, repack {} as CBZ",
+            path.display()
+        );
+    } else {
+        bail!("Unrecognized archive format: {}", path.display());
+    };
+
+    if documents.is_empty() {
+        bail!("No supported images found in {}", path.display());
+    }
+    Ok(documents)
+}
+
+fn import_zip<R: Read + Seek>(archive: &Path, reader: R) -> anyhow::Result<Vec<Document>> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+
+    let mut entries = zip
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| natural_cmp(a, b));
+
+    let mut documents = Vec::new();
+    for entry in entries {
+        let entry_path = Path::new(&entry);
+        let is_image = entry_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+        if !is_image {
+            if !entry.eq_ignore_ascii_case("ComicInfo.xml") {
+                warn!(
+                    "Skipping unsupported entry {entry} in {}",
+                    archive.display()
+                );
+            }
+            continue;
+        }
+
+        let bytes = match read_entry(&mut zip, &entry) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("Skipping {entry} in {}: {err:#}", archive.display());
+                continue;
+            }
+        };
+
+        // nested folders are flattened into the name so exported files don't collide
+        let name = entry_path
+            .with_extension("")
+            .to_string_lossy()
+            .replace(['/', '\\'], "_");
+        match Document::from_image_bytes(archive.join(&entry), name, &bytes) {
+            Ok(document) => documents.push(document),
+            Err(err) => warn!("Skipping {entry} in {}: {err:#}", archive.display()),
+        }
+    }
+
+    Ok(documents)
+}
+
+/// Read an entry whole, which also checks its CRC.
+fn read_entry<R: Read + Seek>(zip: &mut zip::ZipArchive<R>, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    zip.by_name(name)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn import_pdf(path: &Path) -> anyhow::Result<Vec<Document>> {
+    let pdf = lopdf::Document::load(path)
+        .with_context(|| format!("Failed to read PDF {}", path.display()))?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let mut documents = Vec::new();
+    for (number, page_id) in pdf.get_pages() {
+        // scanned pages hold one full-page image, take the largest if there are several
+        let image = pdf.get_page_images(page_id).ok().and_then(|images| {
+            images
+                .into_iter()
+                .max_by_key(|image| image.width * image.height)
+        });
+        let Some(image) = image else {
+            warn!(
+                "Skipping page {number} of {}: no image found",
+                path.display()
+            );
+            continue;
+        };
+
+        let bytes = match pdf_image_bytes(&pdf, &image) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("Skipping page {number} of {}: {err:#}", path.display());
+                continue;
+            }
+        };
+        let decoded = match decode_pdf_image(&image, &bytes) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                warn!("Skipping page {number} of {}: {err:#}", path.display());
+                continue;
+            }
+        };
+
+        let name = format!("{stem}_{number:03}");
+        let page_path = path.join(format!("page-{number}"));
+        documents.push(Document::from_image(page_path, name, &bytes, decoded));
+    }
+
+    Ok(documents)
+}
+
+fn filters<'a>(image: &'a PdfImage) -> Vec<&'a str> {
+    image.filters.iter().flatten().map(String::as_str).collect()
+}
+
+fn pdf_image_bytes(pdf: &lopdf::Document, image: &PdfImage) -> anyhow::Result<Vec<u8>> {
+    match filters(image).as_slice() {
+        ["DCTDecode"] => Ok(image.content.to_vec()),
+        [] | ["FlateDecode"] => Ok(pdf
+            .get_object(image.id)?
+            .as_stream()?
+            .decompressed_content()?),
+        other => bail!("unsupported image filter {other:?}"),
+    }
+}
+
+fn decode_pdf_image(image: &PdfImage, bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+    if filters(image).as_slice() == ["DCTDecode"] {
+        return Ok(image::load_from_memory(bytes)?);
+    }
+
+    if image.bits_per_component != Some(8) {
+        bail!(
+            "unsupported bits per component {:?}",
+            image.bits_per_component
+        );
+    }
+    let (width, height) = (image.width as u32, image.height as u32);
+    let decoded = match image.color_space.as_deref() {
+        Some("DeviceRGB") => {
+            RgbImage::from_raw(width, height, bytes.to_vec()).map(DynamicImage::from)
+        }
+        Some("DeviceGray") => {
+            GrayImage::from_raw(width, height, bytes.to_vec()).map(DynamicImage::from)
+        }
+        other => bail!("unsupported color space {other:?}"),
+    };
+    decoded.context("image data is shorter than its size")
+}
+
+/// Compare names so that `page2` sorts before `page10`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ordering = match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => {
+                a.next();
+                b.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write},
+        path::PathBuf,
+    };
+
+    use image::ImageFormat;
+    use zip::{CompressionMethod, write::SimpleFileOptions};
+
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "page10.png",
+            "Page2.png",
+            "page1.png",
+            "page02b.png",
+            "cover.png",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            [
+                "cover.png",
+                "page1.png",
+                "Page2.png",
+                "page02b.png",
+                "page10.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn zip_entries_are_sorted_and_filtered() -> anyhow::Result<()> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(4, 3).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for name in [
+            "ch1/10.png",
+            "ch1/2.png",
+            "notes.txt",
+            "ComicInfo.xml",
+            "ch1/bad.jpg",
+        ] {
+            zip.start_file(name, SimpleFileOptions::default())?;
+            zip.write_all(if name.ends_with(".png") {
+                &png
+            } else {
+                b"text"
+            })?;
+        }
+        let bytes = zip.finish()?.into_inner();
+
+        let archive = Path::new("chapter.cbz");
+        let documents = import_zip(archive, Cursor::new(bytes))?;
+        let names = documents
+            .iter()
+            .map(|document| document.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["ch1_2", "ch1_10"]);
+        assert_eq!(documents[0].path, PathBuf::from("chapter.cbz/ch1/2.png"));
+        assert_eq!((documents[1].width, documents[1].height), (4, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_zip_entries_are_skipped() -> anyhow::Result<()> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(4, 3).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        for name in ["1.png", "2.png"] {
+            zip.start_file(name, stored)?;
+            zip.write_all(&png)?;
+        }
+        let mut bytes = zip.finish()?.into_inner();
+        // flip a byte of the first entry's data so its CRC no longer matches
+        let data = bytes
+            .windows(png.len())
+            .position(|window| window == png.as_slice())
+            .unwrap();
+        bytes[data + png.len() - 1] ^= 0xFF;
+
+        let documents = import_zip(Path::new("chapter.cbz"), Cursor::new(bytes))?;
+        let names = documents
+            .iter()
+            .map(|document| document.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["2"]);
+        Ok(())
+    }
+}
@@ -1,13 +1,39 @@
-use std::io::Cursor;
+//! Koharu project files.
+//!
+//! Every version starts with a JPEG contact sheet so shell previews can show it.
+//!
+//! - v2: `[thumbnail][page 0]..[page n][index][index offset: u64][version: u32]["khr2"]`.
+//!   Each page holds the [`Document`] as JSON with its images as WebP beside it, and the
+//!   postcard-encoded index lists where the pages are along with the [`Project`] settings as
+//!   JSON, so that fields and settings can be added without another version.
+//! - v1: `[thumbnail][postcard Vec<Document>][offset: u64]["khr!"]`.
+//! - legacy: a bare postcard `Vec<Document>` or `Document`.
 
-use anyhow::bail;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
 use image::{DynamicImage, GenericImageView, ImageFormat, RgbaImage, imageops};
+use koharu_ml::{font_detector::FontPrediction, panel_detector::Panel};
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 
-use crate::state::Document;
+use crate::{
+    image::SerializableDynamicImage,
+    project::Project,
+    state::{Document, TextBlock, TextStyle, TranslationStatus},
+};
 
 pub const KHR_MAGIC: &[u8; 4] = b"khr!";
+pub const KHR_V2_MAGIC: &[u8; 4] = b"khr2";
+pub const KHR_VERSION: u32 = 2;
 const KHR_FOOTER_LEN: usize = KHR_MAGIC.len() + std::mem::size_of::<u64>();
+const KHR_V2_FOOTER_LEN: usize =
+    KHR_V2_MAGIC.len() + std::mem::size_of::<u32>() + std::mem::size_of::<u64>();
 const THUMBNAIL_HEIGHT: u32 = 300;
 const THUMBNAIL_WIDTH: u32 = THUMBNAIL_HEIGHT * 4 / 3; // 4:3 aspect for contact sheet
 const ICON_BYTES: &[u8] = include_bytes!("../icons/Square142x142Logo.png");
@@ -19,27 +45,330 @@ static ICON_IMAGE: Lazy<RgbaImage> = Lazy::new(|| {
 });
 
 pub fn has_khr_magic(bytes: &[u8]) -> bool {
-    bytes.len() >= KHR_MAGIC.len() && &bytes[bytes.len() - KHR_MAGIC.len()..] == KHR_MAGIC
+    bytes.ends_with(KHR_MAGIC) || bytes.ends_with(KHR_V2_MAGIC)
 }
 
-pub fn serialize_khr(documents: &[Document]) -> anyhow::Result<Vec<u8>> {
-    let thumbnail = thumbnail_contact_sheet(documents);
-    let mut thumbnail_bytes = Vec::new();
-    thumbnail.write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Jpeg)?;
+/// Check the footer magic without reading the whole file.
+pub fn is_khr_file(path: impl AsRef<Path>) -> anyhow::Result<bool> {
+    let mut file = File::open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < KHR_MAGIC.len() as u64 {
+        return Ok(false);
+    }
+
+    let mut magic = [0u8; 4];
+    file.seek(SeekFrom::End(-(KHR_MAGIC.len() as i64)))?;
+    file.read_exact(&mut magic)?;
+    Ok(has_khr_magic(&magic))
+}
+
+/// Location and summary of a page, readable without decoding the page itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageEntry {
+    pub offset: u64,
+    pub len: u64,
+    pub id: String,
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PageEntry {
+    fn new(document: &Document, offset: u64, len: u64) -> Self {
+        Self {
+            offset,
+            len,
+            id: document.id.clone(),
+            name: document.name.clone(),
+            width: document.width,
+            height: document.height,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KhrIndex {
+    pages: Vec<PageEntry>,
+    /// JSON-encoded [`Project`].
+    project: String,
+}
+
+impl KhrIndex {
+    fn new(pages: Vec<PageEntry>, project: &Project) -> anyhow::Result<Self> {
+        Ok(Self {
+            pages,
+            project: serde_json::to_string(project)?,
+        })
+    }
+
+    fn project(&self) -> anyhow::Result<Project> {
+        serde_json::from_str(&self.project).context("Failed to decode KHR project settings")
+    }
+}
+
+struct Footer {
+    version: u32,
+    index_offset: u64,
+}
+
+fn read_footer<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Footer>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < KHR_V2_FOOTER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut footer = [0u8; KHR_V2_FOOTER_LEN];
+    reader.seek(SeekFrom::End(-(KHR_V2_FOOTER_LEN as i64)))?;
+    reader.read_exact(&mut footer)?;
+    if &footer[12..] != KHR_V2_MAGIC {
+        return Ok(None);
+    }
+
+    let index_offset = u64::from_le_bytes(footer[..8].try_into().expect("slice with exact length"));
+    let version = u32::from_le_bytes(footer[8..12].try_into().expect("slice with exact length"));
+    if version != KHR_VERSION {
+        bail!("Unsupported KHR version {version}, expected {KHR_VERSION}");
+    }
+    if index_offset > len - KHR_V2_FOOTER_LEN as u64 {
+        bail!("Invalid KHR index offset in file");
+    }
+
+    Ok(Some(Footer {
+        version,
+        index_offset,
+    }))
+}
+
+fn read_index<R: Read + Seek>(reader: &mut R, footer: &Footer) -> anyhow::Result<KhrIndex> {
+    let end = reader.seek(SeekFrom::End(0))? - KHR_V2_FOOTER_LEN as u64;
+    let mut bytes = vec![0u8; (end - footer.index_offset) as usize];
+    reader.seek(SeekFrom::Start(footer.index_offset))?;
+    reader.read_exact(&mut bytes)?;
+    Ok(postcard::from_bytes(&bytes)?)
+}
+
+/// Read only the project settings of a KHR file, v1 and legacy files have default ones.
+pub fn read_project(path: impl AsRef<Path>) -> anyhow::Result<Project> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    match read_footer(&mut reader)? {
+        Some(footer) => read_index(&mut reader, &footer)?.project(),
+        None => Ok(Project::default()),
+    }
+}
+
+/// Reads pages of a KHR file one at a time.
+///
+/// v1 and legacy files have no index, so they are decoded up front.
+pub struct KhrReader<R> {
+    reader: R,
+    version: u32,
+    pages: Vec<PageEntry>,
+    project: Project,
+    documents: Option<Vec<Document>>,
+}
+
+impl KhrReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> KhrReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        if let Some(footer) = read_footer(&mut reader)? {
+            let index = read_index(&mut reader, &footer)?;
+            return Ok(Self {
+                reader,
+                version: footer.version,
+                project: index.project()?,
+                pages: index.pages,
+                documents: None,
+            });
+        }
+
+        let mut bytes = Vec::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut bytes)?;
+        let (version, documents) = decode_v1(&bytes)?;
+        let pages = documents
+            .iter()
+            .map(|document| PageEntry::new(document, 0, 0))
+            .collect();
 
-    let khr_bytes = postcard::to_allocvec(documents)?;
-    let khr_offset = thumbnail_bytes.len() as u64;
+        Ok(Self {
+            reader,
+            version,
+            pages,
+            project: Project::default(),
+            documents: Some(documents),
+        })
+    }
+
+    /// Format version of the file, `0` for the legacy format without footer.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn pages(&self) -> &[PageEntry] {
+        &self.pages
+    }
+
+    pub fn project(&self) -> &Project {
+        &self.project
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn read_page(&mut self, index: usize) -> anyhow::Result<Document> {
+        let entry = self
+            .pages
+            .get(index)
+            .with_context(|| format!("Page {index} not found in KHR file"))?;
+
+        if let Some(documents) = self.documents.as_ref() {
+            return Ok(documents[index].clone());
+        }
+
+        let mut bytes = vec![0u8; entry.len as usize];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.reader.read_exact(&mut bytes)?;
+        decode_page(&bytes)
+            .with_context(|| format!("Failed to decode page {} of KHR file", entry.name))
+    }
+
+    pub fn documents(mut self) -> anyhow::Result<Vec<Document>> {
+        if let Some(documents) = self.documents.take() {
+            return Ok(documents);
+        }
+        (0..self.len()).map(|index| self.read_page(index)).collect()
+    }
+}
+
+/// Writes a v2 KHR file one page at a time. Call [`KhrWriter::finish`] to write the index.
+pub struct KhrWriter<W: Write> {
+    writer: W,
+    position: u64,
+    pages: Vec<PageEntry>,
+    project: Project,
+}
+
+impl KhrWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, thumbnail: &DynamicImage) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Self::new(BufWriter::new(file), thumbnail)
+    }
+}
+
+impl<W: Write> KhrWriter<W> {
+    pub fn new(mut writer: W, thumbnail: &DynamicImage) -> anyhow::Result<Self> {
+        let mut thumbnail_bytes = Vec::new();
+        DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+            .write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Jpeg)?;
+        writer.write_all(&thumbnail_bytes)?;
 
-    let mut output = thumbnail_bytes;
-    output.extend_from_slice(&khr_bytes);
-    output.extend_from_slice(&khr_offset.to_le_bytes());
-    output.extend_from_slice(KHR_MAGIC);
+        Ok(Self {
+            writer,
+            position: thumbnail_bytes.len() as u64,
+            pages: Vec::new(),
+            project: Project::default(),
+        })
+    }
 
-    Ok(output)
+    pub fn write_page(&mut self, document: &Document) -> anyhow::Result<()> {
+        let bytes = encode_page(document)?;
+        self.writer.write_all(&bytes)?;
+        self.pages
+            .push(PageEntry::new(document, self.position, bytes.len() as u64));
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn pages(&self) -> &[PageEntry] {
+        &self.pages
+    }
+
+    /// Project settings written to the index by [`KhrWriter::finish`].
+    pub fn set_project(&mut self, project: Project) {
+        self.project = project;
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let index = KhrIndex::new(self.pages, &self.project)?;
+        let bytes = postcard::to_allocvec(&index)?;
+        self.writer.write_all(&bytes)?;
+        self.writer.write_all(&self.position.to_le_bytes())?;
+        self.writer.write_all(&KHR_VERSION.to_le_bytes())?;
+        self.writer.write_all(KHR_V2_MAGIC)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Read + Write + Seek> KhrWriter<W> {
+    /// Reopen a file to add pages after the existing ones, keeping its thumbnail.
+    ///
+    /// New pages overwrite the old index, and the file only ever grows, so no truncation is needed.
+    pub fn append(mut writer: W) -> anyhow::Result<Self> {
+        let Some(footer) = read_footer(&mut writer)? else {
+            bail!(
+                "Pages can only be appended to KHR v{KHR_VERSION} files, save the project again first"
+            );
+        };
+        let index = read_index(&mut writer, &footer)?;
+        writer.seek(SeekFrom::Start(footer.index_offset))?;
+
+        Ok(Self {
+            writer,
+            position: footer.index_offset,
+            project: index.project()?,
+            pages: index.pages,
+        })
+    }
+}
+
+pub fn serialize_khr(documents: &[Document]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = KhrWriter::new(Vec::new(), &thumbnail_contact_sheet(documents))?;
+    for document in documents {
+        writer.write_page(document)?;
+    }
+    writer.finish()
 }
 
 pub fn deserialize_khr(bytes: &[u8]) -> anyhow::Result<Vec<Document>> {
-    if bytes.len() >= KHR_FOOTER_LEN && has_khr_magic(bytes) {
+    KhrReader::new(Cursor::new(bytes))?.documents()
+}
+
+/// Write `documents` and the project settings to `path`, encoding one page at a time.
+pub fn write_khr(
+    path: impl AsRef<Path>,
+    documents: &[Document],
+    project: &Project,
+) -> anyhow::Result<()> {
+    let mut writer = KhrWriter::create(path, &thumbnail_contact_sheet(documents))?;
+    writer.set_project(project.clone());
+    for document in documents {
+        writer.write_page(document)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn decode_v1(bytes: &[u8]) -> anyhow::Result<(u32, Vec<Document>)> {
+    if bytes.len() >= KHR_FOOTER_LEN && bytes.ends_with(KHR_MAGIC) {
         let offset_start = bytes.len() - KHR_FOOTER_LEN;
         let offset_bytes: [u8; 8] = bytes[offset_start..offset_start + 8]
             .try_into()
@@ -52,24 +381,280 @@ pub fn deserialize_khr(bytes: &[u8]) -> anyhow::Result<Vec<Document>> {
         }
 
         let khr_bytes = &bytes[khr_offset..khr_end];
-        return decode_postcard(khr_bytes);
+        return Ok((1, decode_postcard(khr_bytes)?));
     }
 
     // fallback to legacy format without footer/signature
-    decode_postcard(bytes)
+    Ok((0, decode_postcard(bytes)?))
+}
+
+/// A v2 page. JSON tolerates added and removed fields, and the images stay binary beside it.
+#[derive(Serialize, Deserialize)]
+struct Page {
+    /// [`PageDocument`] as JSON.
+    document: String,
+    images: Vec<ByteBuf>,
+}
+
+/// [`Document`] as stored in a [`Page`], its images being indices into [`Page::images`].
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageDocument {
+    id: String,
+    path: PathBuf,
+    name: String,
+    image: usize,
+    width: u32,
+    height: u32,
+    text_blocks: Vec<PageTextBlock>,
+    #[serde(default)]
+    panels: Vec<Panel>,
+    segment: Option<usize>,
+    inpainted: Option<usize>,
+    rendered: Option<usize>,
+    brush_layer: Option<usize>,
+}
+
+/// [`TextBlock`] as stored in a [`Page`].
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageTextBlock {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    confidence: f32,
+    text: Option<String>,
+    translation: Option<String>,
+    style: Option<TextStyle>,
+    font_prediction: Option<FontPrediction>,
+    rendered: Option<usize>,
+    #[serde(default)]
+    status: TranslationStatus,
+    #[serde(default)]
+    class_index: usize,
+}
+
+/// WebP images of a page, referred to by index.
+#[derive(Default)]
+struct PageImages(Vec<ByteBuf>);
+
+impl PageImages {
+    fn push(&mut self, image: &SerializableDynamicImage) -> anyhow::Result<usize> {
+        self.0.push(ByteBuf::from(image.encode()?));
+        Ok(self.0.len() - 1)
+    }
+
+    fn push_optional(
+        &mut self,
+        image: Option<&SerializableDynamicImage>,
+    ) -> anyhow::Result<Option<usize>> {
+        image.map(|image| self.push(image)).transpose()
+    }
+
+    fn get(&self, index: usize) -> anyhow::Result<SerializableDynamicImage> {
+        let bytes = self
+            .0
+            .get(index)
+            .with_context(|| format!("Image {index} of the page not found"))?;
+        Ok(SerializableDynamicImage::decode(bytes)?)
+    }
+
+    fn get_optional(
+        &self,
+        index: Option<usize>,
+    ) -> anyhow::Result<Option<SerializableDynamicImage>> {
+        index.map(|index| self.get(index)).transpose()
+    }
 }
 
+fn encode_page(document: &Document) -> anyhow::Result<Vec<u8>> {
+    let mut images = PageImages::default();
+    let text_blocks = document
+        .text_blocks
+        .iter()
+        .map(|block| {
+            Ok(PageTextBlock {
+                x: block.x,
+                y: block.y,
+                width: block.width,
+                height: block.height,
+                confidence: block.confidence,
+                text: block.text.clone(),
+                translation: block.translation.clone(),
+                style: block.style.clone(),
+                font_prediction: block.font_prediction.clone(),
+                rendered: images.push_optional(block.rendered.as_ref())?,
+                status: block.status,
+                class_index: block.class_index,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let page_document = PageDocument {
+        id: document.id.clone(),
+        path: document.path.clone(),
+        name: document.name.clone(),
+        image: images.push(&document.image)?,
+        width: document.width,
+        height: document.height,
+        text_blocks,
+        panels: document.panels.clone(),
+        segment: images.push_optional(document.segment.as_ref())?,
+        inpainted: images.push_optional(document.inpainted.as_ref())?,
+        rendered: images.push_optional(document.rendered.as_ref())?,
+        brush_layer: images.push_optional(document.brush_layer.as_ref())?,
+    };
+    let page = Page {
+        document: serde_json::to_string(&page_document)?,
+        images: images.0,
+    };
+    Ok(postcard::to_allocvec(&page)?)
+}
+
+fn decode_page(bytes: &[u8]) -> anyhow::Result<Document> {
+    let page: Page = postcard::from_bytes(bytes)?;
+    let document: PageDocument = serde_json::from_str(&page.document)?;
+    let images = PageImages(page.images);
+    let text_blocks = document
+        .text_blocks
+        .into_iter()
+        .map(|block| {
+            Ok(TextBlock {
+                x: block.x,
+                y: block.y,
+                width: block.width,
+                height: block.height,
+                confidence: block.confidence,
+                text: block.text,
+                translation: block.translation,
+                style: block.style,
+                font_prediction: block.font_prediction,
+                rendered: images.get_optional(block.rendered)?,
+                status: block.status,
+                class_index: block.class_index,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Document {
+        id: document.id,
+        path: document.path,
+        name: document.name,
+        image: images.get(document.image)?,
+        width: document.width,
+        height: document.height,
+        text_blocks,
+        panels: document.panels,
+        segment: images.get_optional(document.segment)?,
+        inpainted: images.get_optional(document.inpainted)?,
+        rendered: images.get_optional(document.rendered)?,
+        brush_layer: images.get_optional(document.brush_layer)?,
+    })
+}
+
+/// v1 and legacy files store positional postcard documents.
 fn decode_postcard(bytes: &[u8]) -> anyhow::Result<Vec<Document>> {
-    if let Ok(documents) = postcard::from_bytes(bytes) {
-        return Ok(documents);
+    if let Ok(documents) = postcard::from_bytes::<Vec<compat::DocumentV1>>(bytes) {
+        return Ok(documents.into_iter().map(Document::from).collect());
     }
 
-    let document: Document = postcard::from_bytes(bytes)?;
-    Ok(vec![document])
+    let document: compat::DocumentV1 = postcard::from_bytes(bytes)?;
+    Ok(vec![document.into()])
 }
 
-fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
-    if documents.is_empty() {
+/// Page layout of v1 and legacy files. postcard isn't self-describing, so it is kept
+/// here as it was written.
+mod compat {
+    use std::path::PathBuf;
+
+    use koharu_ml::font_detector::FontPrediction;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        image::SerializableDynamicImage,
+        state::{Document, TextBlock, TextStyle},
+    };
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct TextBlockV1 {
+        pub x: f32,
+        pub y: f32,
+        pub width: f32,
+        pub height: f32,
+        pub confidence: f32,
+        pub text: Option<String>,
+        pub translation: Option<String>,
+        pub style: Option<TextStyle>,
+        pub font_prediction: Option<FontPrediction>,
+        pub rendered: Option<SerializableDynamicImage>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct DocumentV1 {
+        pub id: String,
+        pub path: PathBuf,
+        pub name: String,
+        pub image: SerializableDynamicImage,
+        pub width: u32,
+        pub height: u32,
+        pub text_blocks: Vec<TextBlockV1>,
+        pub segment: Option<SerializableDynamicImage>,
+        pub inpainted: Option<SerializableDynamicImage>,
+        pub rendered: Option<SerializableDynamicImage>,
+        pub brush_layer: Option<SerializableDynamicImage>,
+    }
+
+    impl From<TextBlockV1> for TextBlock {
+        fn from(block: TextBlockV1) -> Self {
+            TextBlock {
+                x: block.x,
+                y: block.y,
+                width: block.width,
+                height: block.height,
+                confidence: block.confidence,
+                text: block.text,
+                translation: block.translation,
+                style: block.style,
+                font_prediction: block.font_prediction,
+                rendered: block.rendered,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<DocumentV1> for Document {
+        fn from(document: DocumentV1) -> Self {
+            Document {
+                id: document.id,
+                path: document.path,
+                name: document.name,
+                image: document.image,
+                width: document.width,
+                height: document.height,
+                text_blocks: document.text_blocks.into_iter().map(Into::into).collect(),
+                segment: document.segment,
+                inpainted: document.inpainted,
+                rendered: document.rendered,
+                brush_layer: document.brush_layer,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+pub fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
+    let images: Vec<&DynamicImage> = documents.iter().map(|document| &*document.image).collect();
+    contact_sheet(&images)
+}
+
+/// `image` shrunk to the largest size a page takes on a contact sheet.
+pub fn page_thumbnail(image: &DynamicImage) -> DynamicImage {
+    image.thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)
+}
+
+/// The contact sheet of `images`, which may be [`page_thumbnail`]s when the pages themselves
+/// aren't kept in memory.
+pub fn contact_sheet(images: &[&DynamicImage]) -> DynamicImage {
+    if images.is_empty() {
         return DynamicImage::new_rgba8(1, 1);
     }
 
@@ -80,10 +665,8 @@ fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
     );
 
     // If there's only one document, fill the entire canvas with it.
-    if documents.len() == 1 {
-        let thumb = documents[0]
-            .image
-            .thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+    if images.len() == 1 {
+        let thumb = page_thumbnail(images[0]);
         let (thumb_w, thumb_h) = thumb.dimensions();
         let x = ((THUMBNAIL_WIDTH - thumb_w) / 2) as i64;
         let y = ((THUMBNAIL_HEIGHT - thumb_h) / 2) as i64;
@@ -91,14 +674,14 @@ fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
     } else {
         // First image takes the left 1/3 of the canvas.
         let left_width = THUMBNAIL_WIDTH / 3;
-        let first_thumb = documents[0].image.thumbnail(left_width, THUMBNAIL_HEIGHT);
+        let first_thumb = images[0].thumbnail(left_width, THUMBNAIL_HEIGHT);
         let (first_w, first_h) = first_thumb.dimensions();
         let first_x = ((left_width - first_w) / 2) as i64;
         let first_y = ((THUMBNAIL_HEIGHT - first_h) / 2) as i64;
         imageops::overlay(&mut canvas, &first_thumb.to_rgba8(), first_x, first_y);
 
         // Remaining images are packed into the right 2/3 area.
-        let remaining = &documents[1..];
+        let remaining = &images[1..];
         if !remaining.is_empty() {
             let area_width = THUMBNAIL_WIDTH - left_width;
             let area_height = THUMBNAIL_HEIGHT;
@@ -109,8 +692,8 @@ fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
             let cell_w = (area_width / cols).max(1);
             let cell_h = (area_height / rows).max(1);
 
-            for (idx, document) in remaining.iter().enumerate() {
-                let thumb = document.image.thumbnail(cell_w, cell_h);
+            for (idx, image) in remaining.iter().enumerate() {
+                let thumb = image.thumbnail(cell_w, cell_h);
                 let (thumb_w, thumb_h) = thumb.dimensions();
 
                 let col = (idx as u32) % cols;
@@ -142,3 +725,249 @@ fn thumbnail_contact_sheet(documents: &[Document]) -> DynamicImage {
 
     DynamicImage::ImageRgba8(canvas)
 }
+
+#[cfg(test)]
+mod tests {
+    use koharu_ml::{
+        llm::{GenerateOptions, GlossaryEntry},
+        panel_detector::Panel,
+    };
+
+    use super::*;
+    use crate::{
+        reading_order::ReadingOrder,
+        state::{TextBlock, TranslationStatus},
+    };
+
+    fn document(name: &str, shade: u8) -> Document {
+        let image = RgbaImage::from_pixel(8, 6, image::Rgba([shade, shade, shade, 255]));
+        Document {
+            id: name.to_string(),
+            name: name.to_string(),
+            image: DynamicImage::ImageRgba8(image).into(),
+            width: 8,
+            height: 6,
+            text_blocks: vec![TextBlock {
+                translation: Some(format!("{name} translation")),
+                status: TranslationStatus::Translated,
+                class_index: 1,
+                ..Default::default()
+            }],
+            panels: vec![Panel {
+                polygon: vec![[0, 0], [7, 0], [7, 5], [0, 5]],
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// The same page in the v1 layout.
+    fn document_v1(name: &str, shade: u8) -> compat::DocumentV1 {
+        let document = document(name, shade);
+        compat::DocumentV1 {
+            id: document.id,
+            path: document.path,
+            name: document.name,
+            image: document.image,
+            width: document.width,
+            height: document.height,
+            text_blocks: document
+                .text_blocks
+                .into_iter()
+                .map(|block| compat::TextBlockV1 {
+                    x: block.x,
+                    y: block.y,
+                    width: block.width,
+                    height: block.height,
+                    confidence: block.confidence,
+                    text: block.text,
+                    translation: block.translation,
+                    style: block.style,
+                    font_prediction: block.font_prediction,
+                    rendered: block.rendered,
+                })
+                .collect(),
+            segment: document.segment,
+            inpainted: document.inpainted,
+            rendered: document.rendered,
+            brush_layer: document.brush_layer,
+        }
+    }
+
+    fn assert_pages(documents: &[Document], names: &[&str]) {
+        let actual = documents
+            .iter()
+            .map(|document| document.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(actual, names);
+    }
+
+    #[test]
+    fn v2_roundtrip_reads_pages_lazily() -> anyhow::Result<()> {
+        let bytes = serialize_khr(&[document("a", 10), document("b", 200)])?;
+        assert!(has_khr_magic(&bytes));
+        assert!(
+            bytes.starts_with(&[0xFF, 0xD8]),
+            "thumbnail JPEG comes first"
+        );
+
+        let mut reader = KhrReader::new(Cursor::new(&bytes))?;
+        assert_eq!(reader.version(), KHR_VERSION);
+        assert_eq!(reader.pages()[1].name, "b");
+
+        let page = reader.read_page(1)?;
+        assert_eq!(
+            page.image.to_rgba8().get_pixel(0, 0).0,
+            [200, 200, 200, 255]
+        );
+        assert_eq!(
+            page.text_blocks[0].translation.as_deref(),
+            Some("b translation")
+        );
+        assert_eq!(page.text_blocks[0].status, TranslationStatus::Translated);
+        assert_eq!(page.text_blocks[0].class_index, 1);
+        assert_eq!(page.panels[0].polygon.len(), 4);
+        assert!(reader.read_page(2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn page_images_are_stored_by_index() -> anyhow::Result<()> {
+        let shade = |shade: u8| -> SerializableDynamicImage {
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([shade; 4]))).into()
+        };
+        let mut document = document("a", 10);
+        document.text_blocks[0].rendered = Some(shade(30));
+        document.brush_layer = Some(shade(40));
+
+        let bytes = encode_page(&document)?;
+        let page: Page = postcard::from_bytes(&bytes)?;
+        assert_eq!(page.images.len(), 3);
+        let json: serde_json::Value = serde_json::from_str(&page.document)?;
+        assert_eq!(json["image"], 1);
+        assert_eq!(json["textBlocks"][0]["rendered"], 0);
+        assert_eq!(json["brushLayer"], 2);
+        assert!(json["segment"].is_null());
+
+        let pixel =
+            |image: Option<&SerializableDynamicImage>| image.unwrap().to_rgba8().get_pixel(0, 0).0;
+        let page = decode_page(&bytes)?;
+        assert_eq!(pixel(Some(&page.image)), [10, 10, 10, 255]);
+        assert_eq!(pixel(page.text_blocks[0].rendered.as_ref()), [30; 4]);
+        assert_eq!(pixel(page.brush_layer.as_ref()), [40; 4]);
+        assert!(page.segment.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn append_adds_pages_after_existing_ones() -> anyhow::Result<()> {
+        let bytes = serialize_khr(&[document("a", 10)])?;
+
+        let mut writer = KhrWriter::append(Cursor::new(bytes))?;
+        writer.write_page(&document("b", 20))?;
+        writer.write_page(&document("c", 30))?;
+        let bytes = writer.finish()?.into_inner();
+
+        assert_pages(&deserialize_khr(&bytes)?, &["a", "b", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn project_survives_append() -> anyhow::Result<()> {
+        let project = Project {
+            glossary: vec![GlossaryEntry {
+                source: "コハル".to_string(),
+                target: "Koharu".to_string(),
+                notes: None,
+            }],
+            prompt_profile: Some("casual".to_string()),
+            generate_options: Some(GenerateOptions {
+                greedy: true,
+                ..Default::default()
+            }),
+            reading_order: Some(ReadingOrder::Comic),
+        };
+        let mut writer = KhrWriter::new(Vec::new(), &DynamicImage::new_rgb8(1, 1))?;
+        writer.set_project(project.clone());
+        writer.write_page(&document("a", 10))?;
+        let bytes = writer.finish()?;
+
+        let mut writer = KhrWriter::append(Cursor::new(bytes))?;
+        writer.write_page(&document("b", 20))?;
+        let bytes = writer.finish()?.into_inner();
+        assert_eq!(KhrReader::new(Cursor::new(&bytes))?.project(), &project);
+        Ok(())
+    }
+
+    #[test]
+    fn contact_sheet_of_page_thumbnails_shows_every_page() {
+        let documents = [document("a", 10), document("b", 200)];
+        let thumbnails: Vec<_> = documents
+            .iter()
+            .map(|document| page_thumbnail(&document.image))
+            .collect();
+        let sheet = contact_sheet(&thumbnails.iter().collect::<Vec<_>>()).to_rgba8();
+
+        assert_eq!(sheet.dimensions(), (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT));
+        // the first page on the left third, the others packed on the right
+        let middle = THUMBNAIL_HEIGHT / 2;
+        assert_eq!(
+            sheet.get_pixel(THUMBNAIL_WIDTH / 6, middle).0,
+            [10, 10, 10, 255]
+        );
+        assert_eq!(
+            sheet.get_pixel(THUMBNAIL_WIDTH * 2 / 3, middle).0,
+            [200, 200, 200, 255]
+        );
+    }
+
+    #[test]
+    fn pages_tolerate_missing_and_unknown_fields() -> anyhow::Result<()> {
+        let page = encode_page(&document("a", 10))?;
+        let page: Page = postcard::from_bytes(&page)?;
+        let mut json: serde_json::Value = serde_json::from_str(&page.document)?;
+        json.as_object_mut().unwrap().remove("panels");
+        json["textBlocks"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("status");
+        json["addedLater"] = serde_json::json!({ "any": [1, 2] });
+        let page = Page {
+            document: json.to_string(),
+            images: page.images,
+        };
+
+        let document = decode_page(&postcard::to_allocvec(&page)?)?;
+        assert!(document.panels.is_empty());
+        assert_eq!(document.text_blocks[0].status, TranslationStatus::Pending);
+        assert_eq!(document.text_blocks[0].class_index, 1);
+        assert_eq!(
+            document.image.to_rgba8().get_pixel(0, 0).0,
+            [10, 10, 10, 255]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_v1_and_legacy_files() -> anyhow::Result<()> {
+        let documents = vec![document_v1("a", 10), document_v1("b", 20)];
+        let payload = postcard::to_allocvec(&documents)?;
+
+        let thumbnail = b"not really a jpeg";
+        let mut v1 = thumbnail.to_vec();
+        v1.extend_from_slice(&payload);
+        v1.extend_from_slice(&(thumbnail.len() as u64).to_le_bytes());
+        v1.extend_from_slice(KHR_MAGIC);
+        assert!(has_khr_magic(&v1));
+
+        let reader = KhrReader::new(Cursor::new(&v1))?;
+        assert_eq!(reader.version(), 1);
+        assert_pages(&reader.documents()?, &["a", "b"]);
+
+        let reader = KhrReader::new(Cursor::new(&payload))?;
+        assert_eq!(reader.version(), 0);
+        assert_pages(&reader.documents()?, &["a", "b"]);
+
+        assert!(KhrWriter::append(Cursor::new(v1)).is_err());
+        Ok(())
+    }
+}
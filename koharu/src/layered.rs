@@ -0,0 +1,351 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Cursor, Seek, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use image::{DynamicImage, ImageFormat, RgbaImage, imageops};
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{export::escape_xml, state::Document};
+
+const ORA_THUMBNAIL_SIZE: u32 = 256;
+const MAX_LAYER_NAME_CHARS: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum LayeredFormat {
+    /// OpenRaster, opened by Krita and GIMP.
+    #[default]
+    Ora,
+    /// Photoshop document.
+    Psd,
+}
+
+impl LayeredFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            LayeredFormat::Ora => "ora",
+            LayeredFormat::Psd => "psd",
+        }
+    }
+}
+
+/// A positioned bitmap layer, possibly extending past the canvas.
+pub struct ImageLayer {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub image: RgbaImage,
+}
+
+/// Layers of a document from bottom to top: original, inpainted, brush, then one per rendered text block.
+pub fn document_layers(document: &Document) -> Vec<ImageLayer> {
+    let full = |name: &str, image: &DynamicImage| ImageLayer {
+        name: name.to_string(),
+        x: 0,
+        y: 0,
+        image: image.to_rgba8(),
+    };
+
+    let mut layers = vec![full("Original", &document.image)];
+    if let Some(inpainted) = document.inpainted.as_ref() {
+        layers.push(full("Inpainted", inpainted));
+    }
+    if let Some(brush_layer) = document.brush_layer.as_ref() {
+        layers.push(full("Brush", brush_layer));
+    }
+
+    for (index, text_block) in document.text_blocks.iter().enumerate() {
+        let Some(rendered) = text_block.rendered.as_ref() else {
+            continue;
+        };
+        let name = text_block
+            .translation
+            .as_deref()
+            .map(|translation| {
+                translation
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .chars()
+                    .take(MAX_LAYER_NAME_CHARS)
+                    .collect::<String>()
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| format!("Text {}", index + 1));
+        layers.push(ImageLayer {
+            name,
+            x: text_block.x as i32,
+            y: text_block.y as i32,
+            image: rendered.to_rgba8(),
+        });
+    }
+
+    layers
+}
+
+/// Flatten `layers` onto a transparent canvas of the given size.
+fn merge(layers: &[ImageLayer], width: u32, height: u32) -> RgbaImage {
+    let mut canvas = RgbaImage::new(width, height);
+    for layer in layers {
+        imageops::overlay(&mut canvas, &layer.image, layer.x as i64, layer.y as i64);
+    }
+    canvas
+}
+
+/// Write `document` as separate, editable layers.
+pub fn export_layered(
+    path: impl AsRef<Path>,
+    document: &Document,
+    format: LayeredFormat,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let layers = document_layers(document);
+    let (width, height) = (document.width, document.height);
+
+    let mut writer = match format {
+        LayeredFormat::Ora => write_ora(BufWriter::new(file), &layers, width, height)?,
+        LayeredFormat::Psd => write_psd(BufWriter::new(file), &layers, width, height)?,
+    };
+    writer.flush()?;
+    Ok(())
+}
+
+fn png_bytes(image: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+/// OpenRaster: a zip with an uncompressed `mimetype` first, `stack.xml` and one PNG per layer.
+pub fn write_ora<W: Write + Seek>(
+    writer: W,
+    layers: &[ImageLayer],
+    width: u32,
+    height: u32,
+) -> anyhow::Result<W> {
+    let mut zip = ZipWriter::new(writer);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"image/openraster")?;
+
+    // stack.xml lists the topmost layer first
+    let mut stack = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <image version=\"0.0.3\" w=\"{width}\" h=\"{height}\">\n  <stack>\n"
+    );
+    for (index, layer) in layers.iter().enumerate().rev() {
+        let src = format!("data/layer{index:03}.png");
+        stack.push_str(&format!(
+            "    <layer name=\"{}\" src=\"{src}\" x=\"{}\" y=\"{}\" opacity=\"1.0\" visibility=\"visible\" />\n",
+            escape_xml(&layer.name),
+            layer.x,
+            layer.y
+        ));
+        zip.start_file(src, stored)?;
+        zip.write_all(&png_bytes(&layer.image)?)?;
+    }
+    stack.push_str("  </stack>\n</image>\n");
+    zip.start_file("stack.xml", deflated)?;
+    zip.write_all(stack.as_bytes())?;
+
+    let merged = merge(layers, width, height);
+    zip.start_file("mergedimage.png", stored)?;
+    zip.write_all(&png_bytes(&merged)?)?;
+
+    let thumbnail =
+        DynamicImage::ImageRgba8(merged).thumbnail(ORA_THUMBNAIL_SIZE, ORA_THUMBNAIL_SIZE);
+    zip.start_file("Thumbnails/thumbnail.png", stored)?;
+    zip.write_all(&png_bytes(&thumbnail.to_rgba8())?)?;
+
+    Ok(zip.finish()?)
+}
+
+/// Split an RGBA image into raw R, G, B and A planes.
+fn planes(image: &RgbaImage) -> [Vec<u8>; 4] {
+    let mut planes: [Vec<u8>; 4] = Default::default();
+    for plane in &mut planes {
+        plane.reserve((image.width() * image.height()) as usize);
+    }
+    for pixel in image.pixels() {
+        for (plane, value) in planes.iter_mut().zip(pixel.0) {
+            plane.push(value);
+        }
+    }
+    planes
+}
+
+/// Pascal string padded so that its total length is a multiple of 4, as layer records require.
+fn pascal_string(name: &str) -> Vec<u8> {
+    let ascii = name
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+        .take(255)
+        .collect::<Vec<_>>();
+    let mut bytes = vec![ascii.len() as u8];
+    bytes.extend_from_slice(&ascii);
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+    bytes
+}
+
+/// `luni` additional layer info, which carries the full unicode layer name.
+fn unicode_name(name: &str) -> Vec<u8> {
+    let units = name.encode_utf16().collect::<Vec<_>>();
+    let mut data = (units.len() as u32).to_be_bytes().to_vec();
+    for unit in units {
+        data.extend_from_slice(&unit.to_be_bytes());
+    }
+    data.resize(data.len().div_ceil(4) * 4, 0);
+
+    let mut bytes = b"8BIMluni".to_vec();
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&data);
+    bytes
+}
+
+/// Photoshop document with 8-bit RGB layers and uncompressed channel data.
+pub fn write_psd<W: Write>(
+    mut writer: W,
+    layers: &[ImageLayer],
+    width: u32,
+    height: u32,
+) -> anyhow::Result<W> {
+    // header
+    writer.write_all(b"8BPS")?;
+    writer.write_all(&1u16.to_be_bytes())?;
+    writer.write_all(&[0; 6])?;
+    writer.write_all(&4u16.to_be_bytes())?;
+    writer.write_all(&height.to_be_bytes())?;
+    writer.write_all(&width.to_be_bytes())?;
+    writer.write_all(&8u16.to_be_bytes())?;
+    writer.write_all(&3u16.to_be_bytes())?; // RGB
+
+    // color mode data and image resources
+    writer.write_all(&0u32.to_be_bytes())?;
+    writer.write_all(&0u32.to_be_bytes())?;
+
+    // layer info: records bottom to top, then the channel data of every layer
+    let mut records = Vec::new();
+    let mut channels = Vec::new();
+    for layer in layers {
+        let (layer_width, layer_height) = layer.image.dimensions();
+        records.extend_from_slice(&layer.y.to_be_bytes());
+        records.extend_from_slice(&layer.x.to_be_bytes());
+        records.extend_from_slice(&(layer.y + layer_height as i32).to_be_bytes());
+        records.extend_from_slice(&(layer.x + layer_width as i32).to_be_bytes());
+
+        let [red, green, blue, alpha] = planes(&layer.image);
+        records.extend_from_slice(&4u16.to_be_bytes());
+        for (id, plane) in [(-1i16, &alpha), (0, &red), (1, &green), (2, &blue)] {
+            records.extend_from_slice(&id.to_be_bytes());
+            records.extend_from_slice(&(2 + plane.len() as u32).to_be_bytes());
+            channels.extend_from_slice(&0u16.to_be_bytes()); // raw
+            channels.extend_from_slice(plane);
+        }
+
+        records.extend_from_slice(b"8BIMnorm");
+        records.extend_from_slice(&[255, 0, 0, 0]); // opacity, clipping, flags, filler
+
+        let mut extra = Vec::new();
+        extra.extend_from_slice(&0u32.to_be_bytes()); // layer mask
+        extra.extend_from_slice(&0u32.to_be_bytes()); // blending ranges
+        extra.extend_from_slice(&pascal_string(&layer.name));
+        extra.extend_from_slice(&unicode_name(&layer.name));
+        records.extend_from_slice(&(extra.len() as u32).to_be_bytes());
+        records.extend_from_slice(&extra);
+    }
+
+    let mut layer_info = (layers.len() as i16).to_be_bytes().to_vec();
+    layer_info.extend_from_slice(&records);
+    layer_info.extend_from_slice(&channels);
+    if !layer_info.len().is_multiple_of(2) {
+        layer_info.push(0);
+    }
+
+    let section_len = 4 + layer_info.len() + 4;
+    writer.write_all(&(section_len as u32).to_be_bytes())?;
+    writer.write_all(&(layer_info.len() as u32).to_be_bytes())?;
+    writer.write_all(&layer_info)?;
+    writer.write_all(&0u32.to_be_bytes())?; // global layer mask
+
+    // merged image, planar and uncompressed
+    writer.write_all(&0u16.to_be_bytes())?;
+    for plane in planes(&merge(layers, width, height)) {
+        writer.write_all(&plane)?;
+    }
+
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use image::Rgba;
+
+    use super::*;
+
+    fn layers() -> Vec<ImageLayer> {
+        vec![
+            ImageLayer {
+                name: "Original".to_string(),
+                x: 0,
+                y: 0,
+                image: RgbaImage::from_pixel(8, 6, Rgba([255, 255, 255, 255])),
+            },
+            ImageLayer {
+                name: "こんにちは & <hi>".to_string(),
+                x: 5,
+                y: -1,
+                image: RgbaImage::from_pixel(3, 2, Rgba([0, 0, 0, 255])),
+            },
+        ]
+    }
+
+    #[test]
+    fn ora_starts_with_stored_mimetype() -> anyhow::Result<()> {
+        let bytes = write_ora(Cursor::new(Vec::new()), &layers(), 8, 6)?.into_inner();
+        // readers sniff the mimetype at a fixed offset, right after the 30 byte local header
+        assert_eq!(&bytes[30..38], b"mimetype");
+        assert_eq!(&bytes[38..54], b"image/openraster");
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes))?;
+        let mut stack = String::new();
+        zip.by_name("stack.xml")?.read_to_string(&mut stack)?;
+        let top = stack.find("data/layer001.png").unwrap();
+        let bottom = stack.find("data/layer000.png").unwrap();
+        assert!(top < bottom);
+        assert!(stack.contains(
+            "name=\"こんにちは &amp; &lt;hi&gt;\" src=\"data/layer001.png\" x=\"5\" y=\"-1\""
+        ));
+        assert!(zip.by_name("mergedimage.png").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn psd_sections_add_up() -> anyhow::Result<()> {
+        let bytes = write_psd(Vec::new(), &layers(), 8, 6)?;
+        let be32 = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+
+        assert_eq!(&bytes[..4], b"8BPS");
+        // header, then empty color mode data and image resources
+        let layer_section = 26 + 4 + 4;
+        let layer_section_len = be32(layer_section);
+        assert_eq!(be32(layer_section + 4) + 8, layer_section_len);
+        assert_eq!(
+            i16::from_be_bytes([bytes[layer_section + 8], bytes[layer_section + 9]]),
+            2
+        );
+
+        let merged = layer_section + 4 + layer_section_len;
+        assert_eq!(bytes.len(), merged + 2 + 4 * 8 * 6);
+        Ok(())
+    }
+}
@@ -1,9 +1,18 @@
 pub mod app;
+pub mod batch;
 pub mod command;
+pub mod export;
 pub mod image;
+pub mod import;
 pub mod khr;
+pub mod layered;
 pub mod llm;
+pub mod memory;
 pub mod ml;
+pub mod pipeline;
+pub mod project;
+pub mod prompts;
+pub mod reading_order;
 pub mod renderer;
 pub mod result;
 pub mod state;
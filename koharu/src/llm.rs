@@ -1,13 +1,25 @@
-use koharu_ml::llm::{GenerateOptions, Llm, ModelId};
-use serde::Serialize;
-use std::sync::Arc;
+use futures::future::BoxFuture;
+use koharu_ml::llm::{
+    CancellationToken, ContextTurn, GenerateOptions, Llm, ModelId, OpenAiClient, OpenAiConfig,
+    PromptContext, PromptStyle, UnsupportedLanguage,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use strum::Display;
 use tokio::sync::RwLock;
 
-use crate::state::{Document, TextBlock};
+use crate::state::{Document, TextBlock, TranslationStatus};
 
 pub use koharu_ml::llm::prefetch;
 
+/// Preceding pages replayed to the model when a call doesn't say otherwise.
+pub const DEFAULT_CONTEXT_PAGES: usize = 2;
+
+/// Listed next to the local models, loaded with an [`OpenAiConfig`].
+pub const OPENAI_COMPATIBLE_ID: &str = "openai-compatible";
+
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ModelInfo {
@@ -22,6 +34,238 @@ impl ModelInfo {
             languages: id.languages(),
         }
     }
+
+    /// A remote endpoint can translate into any language it is asked for.
+    pub fn openai_compatible() -> Self {
+        Self {
+            id: OPENAI_COMPATIBLE_ID.to_string(),
+            languages: koharu_ml::supported_locales(),
+        }
+    }
+
+    /// Custom models use a generic prompt, so any language can be asked for.
+    pub fn custom(model: &CustomModel) -> Self {
+        Self {
+            id: model.id.clone(),
+            languages: koharu_ml::supported_locales(),
+        }
+    }
+}
+
+/// A GGUF file on disk, listed next to the built-in models.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomModel {
+    pub id: String,
+    pub path: PathBuf,
+    /// System prompt, a generic translation prompt when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+/// Custom models registered by the user, saved as JSON.
+pub struct Registry {
+    path: PathBuf,
+    models: Mutex<Vec<CustomModel>>,
+}
+
+impl Registry {
+    /// Read the models saved at `path`. A missing or unreadable file is an empty registry.
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let models = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                tracing::warn!("Failed to parse {}: {err}", path.display());
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            path,
+            models: Mutex::new(models),
+        }
+    }
+
+    fn lock(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Vec<CustomModel>>> {
+        self.models
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Model registry is poisoned"))
+    }
+
+    pub fn models(&self) -> Vec<CustomModel> {
+        self.lock().map(|models| models.clone()).unwrap_or_default()
+    }
+
+    pub fn get(&self, id: &str) -> Option<CustomModel> {
+        self.models().into_iter().find(|model| model.id == id)
+    }
+
+    /// Add the GGUF file at `path`, named after the file. Registering a file again updates its prompt.
+    pub fn register(&self, path: &Path, prompt: Option<String>) -> anyhow::Result<CustomModel> {
+        anyhow::ensure!(path.is_file(), "{} is not a file", path.display());
+        let prompt = prompt.filter(|prompt| !prompt.trim().is_empty());
+
+        let mut models = self.lock()?;
+        if let Some(model) = models.iter_mut().find(|model| model.path == path) {
+            model.prompt = prompt;
+            let model = model.clone();
+            self.save(&models)?;
+            return Ok(model);
+        }
+
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| "custom".to_string());
+        let taken = |id: &str| {
+            ModelId::from_str(id).is_ok()
+                || id == OPENAI_COMPATIBLE_ID
+                || models.iter().any(|model| model.id == id)
+        };
+        let id = (1..)
+            .map(|n| match n {
+                1 => stem.clone(),
+                n => format!("{stem}-{n}"),
+            })
+            .find(|id| !taken(id))
+            .unwrap_or(stem);
+
+        let model = CustomModel {
+            id,
+            path: path.to_path_buf(),
+            prompt,
+        };
+        models.push(model.clone());
+        self.save(&models)?;
+        Ok(model)
+    }
+
+    /// Forget a custom model. The file itself is kept.
+    pub fn unregister(&self, id: &str) -> anyhow::Result<()> {
+        let mut models = self.lock()?;
+        models.retain(|model| model.id != id);
+        self.save(&models)
+    }
+
+    fn save(&self, models: &[CustomModel]) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(models)?)?;
+        Ok(())
+    }
+}
+
+/// A loaded translation backend: a local GGUF model or a remote chat completions endpoint.
+#[allow(clippy::large_enum_variant)]
+pub enum Translator {
+    Local(Llm),
+    Remote(OpenAiClient),
+}
+
+impl Translator {
+    fn target_language(&self, requested: Option<&str>) -> Result<String, UnsupportedLanguage> {
+        match self {
+            Translator::Local(llm) => llm.target_language(requested),
+            Translator::Remote(client) => client.target_language(requested),
+        }
+    }
+
+    /// Answer one prompt, passing the output to `on_token` as it arrives. Without `opts`,
+    /// each backend samples with its own defaults.
+    async fn generate(
+        &mut self,
+        prompt: &str,
+        context: &PromptContext<'_>,
+        opts: Option<&GenerateOptions>,
+        cancel: &CancellationToken,
+        on_token: impl FnMut(&str) + Send,
+    ) -> anyhow::Result<String> {
+        match self {
+            Translator::Local(llm) => llm.generate_stream(
+                prompt,
+                context,
+                opts.unwrap_or(&GenerateOptions::default()),
+                cancel,
+                on_token,
+            ),
+            Translator::Remote(client) => {
+                client
+                    .generate(prompt, context, opts, cancel, on_token)
+                    .await
+            }
+        }
+    }
+
+    /// Answer several independent prompts, decoded together by local models that support it.
+    /// `on_token` receives the index of the prompt with each piece of output.
+    async fn generate_batch(
+        &mut self,
+        prompts: &[&str],
+        context: &PromptContext<'_>,
+        opts: Option<&GenerateOptions>,
+        cancel: &CancellationToken,
+        mut on_token: impl FnMut(usize, &str) + Send,
+    ) -> anyhow::Result<Vec<String>> {
+        if let Translator::Local(llm) = self {
+            let opts = opts.cloned().unwrap_or_default();
+            return llm.generate_batch(prompts, context, &opts, cancel, on_token);
+        }
+        // requests to a remote endpoint are sent one after another
+        let mut answers = Vec::with_capacity(prompts.len());
+        for (i, prompt) in prompts.iter().enumerate() {
+            answers.push(
+                self.generate(prompt, context, opts, cancel, |text| on_token(i, text))
+                    .await?,
+            );
+        }
+        Ok(answers)
+    }
+}
+
+/// Sends prompts to a loaded [`Translator`], reporting the output of each as it grows.
+struct Session<'a, F> {
+    translator: &'a mut Translator,
+    context: &'a PromptContext<'a>,
+    opts: Option<&'a GenerateOptions>,
+    cancel: &'a CancellationToken,
+    on_output: F,
+}
+
+impl<F: Fn(&str) + Send + Sync> Prompter for Session<'_, F> {
+    fn prompt<'a>(&'a mut self, source: &'a str) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(async move {
+            let on_output = &self.on_output;
+            let mut output = String::new();
+            let response = self
+                .translator
+                .generate(source, self.context, self.opts, self.cancel, |text| {
+                    output.push_str(text);
+                    on_output(&output);
+                })
+                .await?;
+            Ok(response.trim().to_string())
+        })
+    }
+}
+
+impl<F: Fn(usize, &str) + Send + Sync> PrompterBatch for Session<'_, F> {
+    fn prompt_batch<'a>(
+        &'a mut self,
+        indices: &'a [usize],
+        sources: &'a [&'a str],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        Box::pin(async move {
+            let on_output = &self.on_output;
+            let mut outputs = vec![String::new(); sources.len()];
+            self.translator
+                .generate_batch(sources, self.context, self.opts, self.cancel, |i, text| {
+                    outputs[i].push_str(text);
+                    on_output(indices[i], &outputs[i]);
+                })
+                .await
+        })
+    }
 }
 
 /// Load state of the LLM
@@ -32,14 +276,26 @@ pub enum State {
     Empty,
     Loading,
     #[strum(serialize = "ready")]
-    Ready(Llm),
+    Ready(Translator),
     Failed(String),
 }
 
+/// Receives the model output of the current prompt so far.
+pub type OnOutput = dyn Fn(&str) + Send + Sync;
+
+/// Receives the index of a block translated on its own with its output so far.
+pub type OnBlockOutput = dyn Fn(usize, &str) + Send + Sync;
+
+/// Blocks translated on their own that are decoded together at most, bounding the memory of
+/// a batch.
+pub const MAX_BATCH: usize = 8;
+
 /// Minimal owner for the LLM with non-blocking initialization.
 pub struct Model {
     state: Arc<RwLock<State>>,
     use_cpu: bool,
+    /// Token of the running generation, kept outside `state` so it can be cancelled while the model is busy.
+    cancellation: Mutex<CancellationToken>,
 }
 
 impl Default for Model {
@@ -48,45 +304,300 @@ impl Default for Model {
     }
 }
 
-pub trait Translatable {
-    fn get_source(&self) -> anyhow::Result<String>;
-    fn set_translation(&mut self, translation: String) -> anyhow::Result<()>;
+/// Sends one prompt to the model and answers with its output.
+pub trait Prompter: Send {
+    fn prompt<'a>(&'a mut self, source: &'a str) -> BoxFuture<'a, anyhow::Result<String>>;
+}
+
+/// Answers the source texts of a batch of blocks in order, given the blocks' indices too.
+pub trait PrompterBatch: Send {
+    fn prompt_batch<'a>(
+        &'a mut self,
+        indices: &'a [usize],
+        sources: &'a [&'a str],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<String>>>;
+}
+
+/// Something the model can translate, given a [`Prompter`] that sends one prompt to it.
+pub trait Translatable: Send {
+    fn translate<'a>(
+        &'a mut self,
+        model: &'a mut dyn Prompter,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+impl Translatable for Vec<TextBlock> {
+    fn translate<'a>(
+        &'a mut self,
+        model: &'a mut dyn Prompter,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(translate_blocks(self, model))
+    }
 }
 
 impl Translatable for Document {
-    fn get_source(&self) -> anyhow::Result<String> {
-        let source = self
-            .text_blocks
-            .clone()
-            .into_iter()
-            .map(|block| block.text.unwrap_or_else(|| "<empty>".to_string()))
-            .collect::<Vec<_>>()
-            .join("\n");
-        Ok(source)
+    fn translate<'a>(
+        &'a mut self,
+        model: &'a mut dyn Prompter,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(translate_blocks(&mut self.text_blocks, model))
+    }
+}
+
+impl Translatable for TextBlock {
+    fn translate<'a>(
+        &'a mut self,
+        model: &'a mut dyn Prompter,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let source = self
+                .text
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("No source text found"))?;
+            self.translation = Some(model.prompt(&source).await?);
+            self.status = TranslationStatus::Translated;
+            Ok(())
+        })
     }
+}
 
-    fn set_translation(&mut self, translation: String) -> anyhow::Result<()> {
-        let translations = translation.split("\n").collect::<Vec<_>>();
-        for (block, translation) in self.text_blocks.iter_mut().zip(translations) {
-            block.translation = Some(translation.to_string());
+/// One line per text, prefixed with its 1-based ID so answers can be matched even when the
+/// model merges or splits lines. A single text is sent as is.
+fn numbered<'a>(texts: impl ExactSizeIterator<Item = &'a str>) -> String {
+    if texts.len() == 1 {
+        return texts.collect();
+    }
+    texts
+        .enumerate()
+        .map(|(index, text)| format!("{}. {}", index + 1, text.replace('\n', " ")))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Split `12. text` into its ID and text, accepting the full-width forms CJK output tends to use.
+fn split_id(line: &str) -> Option<(usize, &str)> {
+    let mut id = 0usize;
+    for (digits, (offset, c)) in line.char_indices().enumerate() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            '０'..='９' => c as u32 - '０' as u32,
+            '.' | '．' | ':' | '：' | ')' | '）' | '、' if digits > 0 => {
+                return Some((id, line[offset + c.len_utf8()..].trim()));
+            }
+            _ => return None,
+        };
+        id = id.checked_mul(10)?.checked_add(digit as usize)?;
+    }
+    None
+}
+
+/// Map the answer back to `count` texts by ID. Lines without an ID continue the previous one.
+///
+/// Answers without any ID are only trusted when they have exactly one line per text.
+fn parse_numbered(output: &str, count: usize) -> Vec<Option<String>> {
+    let mut translations: Vec<Option<String>> = vec![None; count];
+    let lines = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    if count == 1 {
+        // a single text is sent without an ID, so the whole answer belongs to it
+        translations[0] = Some(output.trim().to_string());
+    } else if lines.iter().all(|line| split_id(line).is_none()) {
+        if lines.len() == count {
+            for (translation, line) in translations.iter_mut().zip(lines) {
+                *translation = Some(line.to_string());
+            }
+        }
+    } else {
+        let mut current = None;
+        for line in lines {
+            match split_id(line) {
+                Some((id, text)) if (1..=count).contains(&id) && translations[id - 1].is_none() => {
+                    translations[id - 1] = Some(text.to_string());
+                    current = Some(id - 1);
+                }
+                // unknown or repeated IDs are dropped along with their continuation lines
+                Some(_) => current = None,
+                None => {
+                    if let Some(translation) =
+                        current.and_then(|index| translations[index].as_mut())
+                    {
+                        translation.push(' ');
+                        translation.push_str(line);
+                    }
+                }
+            }
+        }
+    }
+
+    for translation in &mut translations {
+        if translation
+            .as_ref()
+            .is_some_and(|text| text.trim().is_empty())
+        {
+            *translation = None;
         }
-        Ok(())
     }
+    translations
 }
 
-impl Translatable for TextBlock {
-    fn get_source(&self) -> anyhow::Result<String> {
-        let source = self
-            .text
-            .clone()
-            .ok_or_else(|| anyhow::anyhow!("No source text found"))?;
-        Ok(source)
+/// Translate every block with source text in one request, then retry the ones missing from
+/// the answer individually. Each block's [`TranslationStatus`] records which path it took.
+///
+/// A missing line has usually been merged into a neighbour, so the answered neighbours of a
+/// missing block are retried too, and keep their page answer flagged as failed if that fails.
+pub async fn translate_blocks(
+    text_blocks: &mut [TextBlock],
+    model: &mut dyn Prompter,
+) -> anyhow::Result<()> {
+    let indices = text_blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, block)| block.text.is_some())
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    if indices.is_empty() {
+        return Ok(());
     }
 
-    fn set_translation(&mut self, translation: String) -> anyhow::Result<()> {
-        self.translation = Some(translation);
-        Ok(())
+    let source = numbered(
+        indices
+            .iter()
+            .map(|&index| text_blocks[index].text.as_deref().unwrap_or_default()),
+    );
+    let translations = parse_numbered(&model.prompt(&source).await?, indices.len());
+
+    let answered = translations.iter().map(Option::is_some).collect::<Vec<_>>();
+    let mut retry = Vec::new();
+    for (position, (&index, translation)) in indices.iter().zip(translations).enumerate() {
+        let block = &mut text_blocks[index];
+        let next_to_missing = (position > 0 && !answered[position - 1])
+            || answered.get(position + 1).is_some_and(|answered| !answered);
+        match translation {
+            Some(translation) => {
+                block.translation = Some(translation);
+                block.status = TranslationStatus::Translated;
+                if next_to_missing {
+                    retry.push(index);
+                }
+            }
+            None => retry.push(index),
+        }
     }
+    if retry.is_empty() {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        "{} of {} blocks are missing from the translation or next to a missing one, retrying \
+         them one by one",
+        retry.len(),
+        indices.len()
+    );
+    for index in retry {
+        let block = &mut text_blocks[index];
+        let source = block.text.clone().unwrap_or_default();
+        match model.prompt(&source).await {
+            Ok(translation) if !translation.trim().is_empty() => {
+                block.translation = Some(translation.trim().to_string());
+                block.status = TranslationStatus::Retried;
+            }
+            Ok(_) => {
+                tracing::warn!("Empty translation for block {index}");
+                block.status = TranslationStatus::Failed;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to translate block {index}: {err:#}");
+                block.status = TranslationStatus::Failed;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Translate every block with source text on its own, at most [`MAX_BATCH`] at a time.
+pub async fn translate_each(
+    text_blocks: &mut [TextBlock],
+    model: &mut dyn PrompterBatch,
+) -> anyhow::Result<()> {
+    let indices = text_blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, block)| block.text.is_some())
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    for chunk in indices.chunks(MAX_BATCH) {
+        let sources = chunk
+            .iter()
+            .map(|&index| text_blocks[index].text.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>();
+        let translations = model.prompt_batch(chunk, &sources).await?;
+        anyhow::ensure!(
+            translations.len() == chunk.len(),
+            "Expected {} translations, got {}",
+            chunk.len(),
+            translations.len()
+        );
+        for (&index, translation) in chunk.iter().zip(translations) {
+            let block = &mut text_blocks[index];
+            let translation = translation.trim();
+            if translation.is_empty() {
+                tracing::warn!("Empty translation for block {index}");
+                block.status = TranslationStatus::Failed;
+            } else {
+                block.translation = Some(translation.to_string());
+                block.status = TranslationStatus::Translated;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Source and translation of the blocks that have both, numbered like the page prompt.
+pub fn context_turn(text_blocks: &[TextBlock]) -> Option<ContextTurn> {
+    let (sources, translations): (Vec<_>, Vec<_>) = text_blocks
+        .iter()
+        .filter_map(|block| Some((block.text.as_deref()?, block.translation.as_deref()?)))
+        .unzip();
+    if sources.is_empty() {
+        return None;
+    }
+    Some(ContextTurn {
+        source: numbered(sources.into_iter()),
+        translation: numbered(translations.into_iter()),
+    })
+}
+
+/// History for translating the page at `index`: one turn per preceding page, up to `pages`,
+/// followed by the earlier blocks of the same page when a single block is translated.
+pub fn context_turns(
+    documents: &[Document],
+    index: usize,
+    text_block_index: Option<usize>,
+    pages: usize,
+) -> Vec<ContextTurn> {
+    if pages == 0 || index >= documents.len() {
+        return Vec::new();
+    }
+
+    let mut turns = documents[index.saturating_sub(pages)..index]
+        .iter()
+        .filter_map(|document| context_turn(&document.text_blocks))
+        .collect::<Vec<_>>();
+    if let Some(text_block_index) = text_block_index {
+        turns.extend(
+            documents[index]
+                .text_blocks
+                .iter()
+                .take(text_block_index)
+                .filter_map(|block| context_turn(std::slice::from_ref(block))),
+        );
+    }
+    turns
 }
 
 impl Model {
@@ -94,6 +605,7 @@ impl Model {
         Self {
             state: Arc::new(RwLock::new(State::Empty)),
             use_cpu,
+            cancellation: Mutex::default(),
         }
     }
 
@@ -103,6 +615,27 @@ impl Model {
 
     /// Start loading the model on a blocking thread and return immediately.
     pub async fn load(&self, id: ModelId) {
+        let use_cpu = self.use_cpu;
+        self.spawn_load(async move { Llm::load(id, use_cpu).await })
+            .await;
+    }
+
+    /// Start loading a GGUF file registered by the user and return immediately.
+    pub async fn load_custom(&self, model: CustomModel) {
+        let use_cpu = self.use_cpu;
+        self.spawn_load(async move {
+            tokio::task::spawn_blocking(move || {
+                let style = PromptStyle::Generic {
+                    system: model.prompt,
+                };
+                Llm::load_file(&model.path, style, use_cpu)
+            })
+            .await?
+        })
+        .await;
+    }
+
+    async fn spawn_load(&self, load: impl Future<Output = anyhow::Result<Llm>> + Send + 'static) {
         // mark as loading
         {
             let mut guard = self.state.write().await;
@@ -110,13 +643,12 @@ impl Model {
         }
 
         let state_cloned = self.state.clone();
-        let use_cpu = self.use_cpu;
         tokio::spawn(async move {
-            let res = Llm::load(id, use_cpu).await;
+            let res = load.await;
             match res {
                 Ok(llm) => {
                     let mut guard = state_cloned.write().await;
-                    *guard = State::Ready(llm);
+                    *guard = State::Ready(Translator::Local(llm));
                 }
                 Err(e) => {
                     tracing::error!("LLM load join error: {e}");
@@ -139,6 +671,21 @@ impl Model {
         self.state.write().await
     }
 
+    /// Use a remote endpoint instead of a local model. Nothing is sent until the first translation.
+    pub async fn connect(&self, config: OpenAiConfig) -> anyhow::Result<()> {
+        let client = OpenAiClient::new(config)?;
+        *self.state.write().await = State::Ready(Translator::Remote(client));
+        Ok(())
+    }
+
+    /// Stop the running generation and the ones waiting for the model, if any. Their
+    /// translations fail instead of being kept partially.
+    pub fn cancel(&self) {
+        if let Ok(cancellation) = self.cancellation.lock() {
+            cancellation.cancel();
+        }
+    }
+
     /// Drop the loaded model from memory.
     pub async fn offload(&self) {
         *self.state.write().await = State::Empty;
@@ -149,19 +696,344 @@ impl Model {
         matches!(*self.state.read().await, State::Ready(_))
     }
 
-    /// Generate text from the loaded model.
-    pub async fn generate(&self, doc: &mut impl Translatable) -> anyhow::Result<()> {
+    /// Wait for a pending load to finish, for callers that can't poll `ready` from a UI.
+    pub async fn wait_ready(&self) -> anyhow::Result<()> {
+        loop {
+            match &*self.state.read().await {
+                State::Ready(_) => return Ok(()),
+                State::Failed(e) => return Err(anyhow::anyhow!("Model failed to load: {e}")),
+                State::Empty => return Err(anyhow::anyhow!("No model is loaded")),
+                State::Loading => {}
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        }
+    }
+
+    /// Generate text from the loaded model, with the project glossary and earlier translations in `context`.
+    ///
+    /// `opts` overrides how the model samples, and `on_output` receives the output of the
+    /// current prompt so far, every time it grows.
+    pub async fn generate(
+        &self,
+        doc: &mut impl Translatable,
+        context: &PromptContext<'_>,
+        opts: Option<&GenerateOptions>,
+        on_output: &OnOutput,
+    ) -> anyhow::Result<()> {
+        // taken before waiting for the model, so cancelling a queued generation stops it too
+        let cancel = self.cancellation_token()?;
+        let mut guard = self.state.write().await;
+        let translator = ready(&mut guard)?;
+        // checked once up front, the retries would otherwise mark every block as failed
+        let language = translator.target_language(context.language)?;
+        let context = &PromptContext {
+            language: Some(&language),
+            ..*context
+        };
+        let mut session = Session {
+            translator,
+            context,
+            opts,
+            cancel: &cancel,
+            on_output,
+        };
+        let result = doc.translate(&mut session).await;
+        // retries swallow their errors, so a cancellation has to be reported here
+        if cancel.is_cancelled() {
+            anyhow::bail!("Generation was cancelled");
+        }
+        result
+    }
+
+    /// Translate each block of `text_blocks` with its own prompt instead of the whole page in
+    /// one, decoding the prompts together where the model supports it.
+    ///
+    /// `on_output` receives the index of a block with its output so far, every time it grows.
+    pub async fn generate_blocks(
+        &self,
+        text_blocks: &mut [TextBlock],
+        context: &PromptContext<'_>,
+        opts: Option<&GenerateOptions>,
+        on_output: &(dyn Fn(usize, &str) + Send + Sync),
+    ) -> anyhow::Result<()> {
+        // taken before waiting for the model, so cancelling a queued generation stops it too
+        let cancel = self.cancellation_token()?;
         let mut guard = self.state.write().await;
-        match &mut *guard {
-            State::Ready(llm) => {
-                let text = doc.get_source()?;
-                let response = llm.generate(&text, &GenerateOptions::default())?;
-                let response = response.trim().to_string();
-                doc.set_translation(response)
+        let translator = ready(&mut guard)?;
+        let language = translator.target_language(context.language)?;
+        let context = &PromptContext {
+            language: Some(&language),
+            ..*context
+        };
+        let mut session = Session {
+            translator,
+            context,
+            opts,
+            cancel: &cancel,
+            on_output,
+        };
+        translate_each(text_blocks, &mut session).await
+    }
+
+    /// The token of the running generation, shared with the ones waiting for the model so
+    /// [`Model::cancel`] stops them all. Once cancelled, the next generation gets a fresh one.
+    fn cancellation_token(&self) -> anyhow::Result<CancellationToken> {
+        let mut cancellation = self
+            .cancellation
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Cancellation token is poisoned"))?;
+        if cancellation.is_cancelled() {
+            *cancellation = CancellationToken::new();
+        }
+        Ok(cancellation.clone())
+    }
+}
+
+/// The loaded translator, or why there is none.
+fn ready(state: &mut State) -> anyhow::Result<&mut Translator> {
+    match state {
+        State::Ready(translator) => Ok(translator),
+        State::Loading => Err(anyhow::anyhow!("Model is still loading")),
+        State::Failed(e) => Err(anyhow::anyhow!("Model failed to load: {e}")),
+        State::Empty => Err(anyhow::anyhow!("No model is loaded")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A model that answers from a script, whatever it is asked, and errors once it runs out.
+    struct Stub {
+        answers: VecDeque<&'static str>,
+        prompts: Vec<String>,
+    }
+
+    impl Stub {
+        fn new(answers: &[&'static str]) -> Self {
+            Self {
+                answers: answers.iter().copied().collect(),
+                prompts: Vec::new(),
             }
-            State::Loading => Err(anyhow::anyhow!("Model is still loading")),
-            State::Failed(e) => Err(anyhow::anyhow!("Model failed to load: {e}")),
-            State::Empty => Err(anyhow::anyhow!("No model is loaded")),
         }
     }
+
+    impl Prompter for Stub {
+        fn prompt<'a>(&'a mut self, source: &'a str) -> BoxFuture<'a, anyhow::Result<String>> {
+            self.prompts.push(source.to_string());
+            let answer = self
+                .answers
+                .pop_front()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow::anyhow!("out of answers"));
+            Box::pin(async move { answer })
+        }
+    }
+
+    /// Answers batches with a function of the blocks' indices.
+    struct BatchStub<F>(F);
+
+    impl<F> PrompterBatch for BatchStub<F>
+    where
+        F: FnMut(&[usize]) -> Vec<String> + Send,
+    {
+        fn prompt_batch<'a>(
+            &'a mut self,
+            indices: &'a [usize],
+            _sources: &'a [&'a str],
+        ) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+            let answers = (self.0)(indices);
+            Box::pin(async move { Ok(answers) })
+        }
+    }
+
+    fn blocks(texts: &[&str]) -> Vec<TextBlock> {
+        texts
+            .iter()
+            .map(|text| TextBlock {
+                text: Some(text.to_string()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn translations(blocks: &[TextBlock]) -> Vec<(Option<&str>, TranslationStatus)> {
+        blocks
+            .iter()
+            .map(|block| (block.translation.as_deref(), block.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn answers_are_matched_by_id() -> anyhow::Result<()> {
+        let mut text_blocks = blocks(&["おはよう", "元気？", "またね"]);
+        // out of order, a full-width ID, and the last line split in two
+        let mut stub = Stub::new(&["２．How are you?\n1. Morning.\n3: See\nyou."]);
+        text_blocks.translate(&mut stub).await?;
+
+        assert_eq!(stub.prompts, ["1. おはよう\n2. 元気？\n3. またね"]);
+        assert_eq!(
+            translations(&text_blocks),
+            [
+                (Some("Morning."), TranslationStatus::Translated),
+                (Some("How are you?"), TranslationStatus::Translated),
+                (Some("See you."), TranslationStatus::Translated),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merged_lines_are_retried_individually() -> anyhow::Result<()> {
+        let mut text_blocks = blocks(&["おはよう", "元気？", "またね", "うん"]);
+        text_blocks.insert(1, TextBlock::default());
+        // merges 2 and 3 into one line, and the retry of the next neighbour fails
+        let mut stub = Stub::new(&[
+            "1. Morning.\n2. How are you? See you.\n4. Yeah.",
+            "How are you?",
+            "See you.",
+            "",
+        ]);
+        text_blocks.translate(&mut stub).await?;
+
+        // the missing block and both of its neighbours are asked again
+        assert_eq!(
+            stub.prompts,
+            [
+                "1. おはよう\n2. 元気？\n3. またね\n4. うん",
+                "元気？",
+                "またね",
+                "うん"
+            ]
+        );
+        assert_eq!(
+            translations(&text_blocks),
+            [
+                (Some("Morning."), TranslationStatus::Translated),
+                (None, TranslationStatus::Pending),
+                (Some("How are you?"), TranslationStatus::Retried),
+                (Some("See you."), TranslationStatus::Retried),
+                (Some("Yeah."), TranslationStatus::Failed),
+            ]
+        );
+        let page = text_blocks
+            .iter()
+            .filter_map(|block| block.translation.as_deref())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(page.matches("See you.").count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unaligned_answers_without_ids_are_not_trusted() -> anyhow::Result<()> {
+        let mut text_blocks = blocks(&["おはよう", "元気？", "またね"]);
+        // drops the IDs and a line, then answers one retry empty and runs out on the last
+        let mut stub = Stub::new(&["Morning.\nHow are you?", "", "How are you?"]);
+        text_blocks.translate(&mut stub).await?;
+
+        assert_eq!(stub.prompts.len(), 4);
+        assert_eq!(
+            translations(&text_blocks),
+            [
+                (None, TranslationStatus::Failed),
+                (Some("How are you?"), TranslationStatus::Retried),
+                (None, TranslationStatus::Failed),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocks_are_translated_on_their_own_in_batches() -> anyhow::Result<()> {
+        let mut text_blocks = blocks(&["おはよう"; MAX_BATCH + 2]);
+        text_blocks.insert(1, TextBlock::default());
+        let mut batches = Vec::new();
+        let mut stub = BatchStub(|indices: &[usize]| {
+            batches.push(indices.to_vec());
+            // the last block gets an empty answer
+            indices
+                .iter()
+                .map(|index| match index {
+                    10 => " ".to_string(),
+                    _ => format!(" Morning {index}. "),
+                })
+                .collect()
+        });
+        translate_each(&mut text_blocks, &mut stub).await?;
+
+        assert_eq!(batches, [vec![0, 2, 3, 4, 5, 6, 7, 8], vec![9, 10]]);
+        let translations = translations(&text_blocks);
+        assert_eq!(
+            translations[..3],
+            [
+                (Some("Morning 0."), TranslationStatus::Translated),
+                (None, TranslationStatus::Pending),
+                (Some("Morning 2."), TranslationStatus::Translated),
+            ]
+        );
+        assert_eq!(translations[10], (None, TranslationStatus::Failed));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_names_models_after_files_and_persists() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("koharu-registry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let file = dir.join("My-Finetune.Q8_0.gguf");
+        std::fs::write(&file, b"GGUF")?;
+        let other = dir.join("nested");
+        std::fs::create_dir_all(&other)?;
+        let other = other.join("My-Finetune.Q8_0.gguf");
+        std::fs::write(&other, b"GGUF")?;
+
+        let path = dir.join("custom-models.json");
+        let registry = Registry::load(&path);
+        assert_eq!(registry.register(&file, None)?.id, "my-finetune.q8_0");
+        assert_eq!(registry.register(&other, None)?.id, "my-finetune.q8_0-2");
+        // registering a file again only updates its prompt
+        let updated = registry.register(&file, Some("Translate.".to_string()))?;
+        assert_eq!(updated.prompt.as_deref(), Some("Translate."));
+        assert!(registry.register(&dir.join("missing.gguf"), None).is_err());
+
+        let reloaded = Registry::load(&path);
+        assert_eq!(reloaded.models(), registry.models());
+        reloaded.unregister("my-finetune.q8_0")?;
+        assert_eq!(Registry::load(&path).models().len(), 1);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_a_generation_waiting_for_the_model() -> anyhow::Result<()> {
+        let model = Arc::new(Model::default());
+        model
+            .connect(OpenAiConfig {
+                endpoint: "http://127.0.0.1:9/v1".to_string(),
+                ..Default::default()
+            })
+            .await?;
+
+        let busy = model.get_mut().await;
+        let queued = tokio::spawn({
+            let model = model.clone();
+            async move {
+                let mut text_blocks = blocks(&["こんにちは"]);
+                model
+                    .generate(&mut text_blocks, &PromptContext::default(), None, &|_| {})
+                    .await
+            }
+        });
+        // let the generation start waiting for the model before cancelling
+        tokio::task::yield_now().await;
+        model.cancel();
+        drop(busy);
+
+        let err = queued.await?.unwrap_err();
+        assert_eq!(err.to_string(), "Generation was cancelled");
+        Ok(())
+    }
 }
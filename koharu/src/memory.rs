@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crate::state::{Document, TextBlock, TranslationStatus};
+
+/// Earlier translations, reused when the same line shows up again in the project.
+///
+/// Lookups try the exact source text first, then the text with whitespace, case, full-width
+/// forms and decorative punctuation folded away.
+#[derive(Debug, Default, Clone)]
+pub struct TranslationMemory {
+    exact: HashMap<String, String>,
+    normalized: HashMap<String, String>,
+}
+
+impl TranslationMemory {
+    pub fn from_documents<'a>(documents: impl IntoIterator<Item = &'a Document>) -> Self {
+        let mut memory = Self::default();
+        for document in documents {
+            memory.insert_blocks(&document.text_blocks);
+        }
+        memory
+    }
+
+    /// Remember every block that has both source text and a translation.
+    pub fn insert_blocks(&mut self, text_blocks: &[TextBlock]) {
+        for block in text_blocks {
+            if let (Some(text), Some(translation)) =
+                (block.text.as_deref(), block.translation.as_deref())
+            {
+                self.insert(text, translation);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, source: &str, translation: &str) {
+        let (source, translation) = (source.trim(), translation.trim());
+        if source.is_empty() || translation.is_empty() {
+            return;
+        }
+
+        let key = normalize(source);
+        if !key.is_empty() {
+            self.normalized.insert(key, translation.to_string());
+        }
+        self.exact
+            .insert(source.to_string(), translation.to_string());
+    }
+
+    pub fn lookup(&self, source: &str) -> Option<&str> {
+        let source = source.trim();
+        if let Some(translation) = self.exact.get(source) {
+            return Some(translation);
+        }
+
+        let key = normalize(source);
+        if key.is_empty() {
+            return None;
+        }
+        self.normalized.get(&key).map(String::as_str)
+    }
+
+    /// Fill in remembered translations and return the indices of the blocks that still need one.
+    pub fn apply(&self, text_blocks: &mut [TextBlock]) -> Vec<usize> {
+        let mut pending = Vec::new();
+        for (index, block) in text_blocks.iter_mut().enumerate() {
+            match block.text.as_deref().and_then(|text| self.lookup(text)) {
+                Some(translation) => {
+                    block.translation = Some(translation.to_string());
+                    block.status = TranslationStatus::Memory;
+                }
+                None => pending.push(index),
+            }
+        }
+        pending
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty()
+    }
+}
+
+/// Keep letters, digits, `!` and `?`, so `はい！！` matches `はい!` but not `はい？`.
+fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    for c in text.chars() {
+        // full-width ASCII variants
+        let c = match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        };
+        if matches!(c, '!' | '?') {
+            if !normalized.ends_with(c) {
+                normalized.push(c);
+            }
+        } else if c.is_alphanumeric() {
+            normalized.extend(c.to_lowercase());
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(text: &str, translation: Option<&str>) -> TextBlock {
+        TextBlock {
+            text: Some(text.to_string()),
+            translation: translation.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reuses_exact_and_near_exact_lines() {
+        let mut memory = TranslationMemory::default();
+        memory.insert_blocks(&[
+            block("はい！", Some("Yes!")),
+            block("ＯＫ です", Some("Okay")),
+            block("まだ", None),
+        ]);
+        assert_eq!(memory.len(), 2);
+
+        assert_eq!(memory.lookup("はい！"), Some("Yes!"));
+        assert_eq!(memory.lookup(" はい!!… "), Some("Yes!"));
+        assert_eq!(memory.lookup("okです"), Some("Okay"));
+        assert_eq!(memory.lookup("はい？"), None);
+        assert_eq!(memory.lookup("まだ"), None);
+
+        let mut blocks = vec![
+            block("はい!", None),
+            block("いいえ", None),
+            TextBlock::default(),
+        ];
+        assert_eq!(memory.apply(&mut blocks), [1, 2]);
+        assert_eq!(blocks[0].translation.as_deref(), Some("Yes!"));
+        assert_eq!(blocks[0].status, TranslationStatus::Memory);
+    }
+}
@@ -1,12 +1,12 @@
 use anyhow::Result;
 use image::DynamicImage;
-use koharu_ml::comic_text_detector::{self, ComicTextDetector};
+use koharu_ml::comic_text_detector::{self, ComicTextDetector, DetectOptions};
 use koharu_ml::font_detector::{self, FontDetector};
 use koharu_ml::lama::{self, Lama};
 use koharu_ml::manga_ocr::{self, MangaOcr};
 
 use crate::image::SerializableDynamicImage;
-use crate::state::TextBlock;
+use crate::state::{TextBlock, TextStyle};
 
 const NEAR_BLACK_THRESHOLD: u8 = 12;
 const GRAY_NEAR_BLACK_THRESHOLD: u8 = 60;
@@ -68,6 +68,34 @@ fn normalize_font_prediction(prediction: &mut font_detector::FontPrediction) {
     }
 }
 
+/// Keep only the segment pixels that fall inside a text block, so inpainting
+/// doesn't erase artwork the detector picked up outside of dialog.
+pub fn mask_text_blocks(
+    segment: &SerializableDynamicImage,
+    text_blocks: &[TextBlock],
+) -> SerializableDynamicImage {
+    let mut segment_data = segment.to_rgba8();
+    let (seg_width, seg_height) = segment_data.dimensions();
+    for y in 0..seg_height {
+        for x in 0..seg_width {
+            let pixel = segment_data.get_pixel_mut(x, y);
+            if pixel.0 != [0, 0, 0, 255] {
+                let inside_any_block = text_blocks.iter().any(|block| {
+                    x >= block.x as u32
+                        && x < (block.x + block.width) as u32
+                        && y >= block.y as u32
+                        && y < (block.y + block.height) as u32
+                });
+                if !inside_any_block {
+                    *pixel = image::Rgba([0, 0, 0, 255]);
+                }
+            }
+        }
+    }
+
+    DynamicImage::ImageRgba8(segment_data).into()
+}
+
 pub struct Model {
     dialog_detector: ComicTextDetector,
     ocr: MangaOcr,
@@ -88,10 +116,11 @@ impl Model {
     pub async fn detect_dialog(
         &self,
         image: &SerializableDynamicImage,
+        opts: &DetectOptions,
     ) -> Result<(Vec<TextBlock>, SerializableDynamicImage)> {
-        let (bboxes, segment) = self.dialog_detector.inference(image)?;
+        let (bboxes, segment) = self.dialog_detector.inference(image, opts)?;
 
-        let mut text_blocks: Vec<TextBlock> = bboxes
+        let text_blocks: Vec<TextBlock> = bboxes
             .into_iter()
             .map(|bbox| TextBlock {
                 x: bbox.xmin,
@@ -99,16 +128,11 @@ impl Model {
                 width: bbox.xmax - bbox.xmin,
                 height: bbox.ymax - bbox.ymin,
                 confidence: bbox.confidence,
+                class_index: bbox.data,
                 ..Default::default()
             })
             .collect();
 
-        text_blocks.sort_unstable_by(|a, b| {
-            (a.y + a.height / 2.0)
-                .partial_cmp(&(b.y + b.height / 2.0))
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
-
         Ok((text_blocks, DynamicImage::ImageLuma8(segment).into()))
     }
 
@@ -137,7 +161,7 @@ impl Model {
         Ok(blocks
             .iter()
             .cloned()
-            .zip(texts.into_iter())
+            .zip(texts)
             .map(|(block, text)| TextBlock {
                 text: text.into(),
                 ..block
@@ -155,6 +179,46 @@ impl Model {
         Ok(result.into())
     }
 
+    /// Predict fonts for every text block and seed its style from the prediction.
+    pub async fn detect_text_styles(
+        &self,
+        image: &SerializableDynamicImage,
+        text_blocks: &mut [TextBlock],
+    ) -> Result<()> {
+        if text_blocks.is_empty() {
+            return Ok(());
+        }
+
+        let images: Vec<DynamicImage> = text_blocks
+            .iter()
+            .map(|block| {
+                image.crop_imm(
+                    block.x as u32,
+                    block.y as u32,
+                    block.width as u32,
+                    block.height as u32,
+                )
+            })
+            .collect();
+        let font_predictions = self.detect_fonts(&images, 1).await?;
+        for (block, prediction) in text_blocks.iter_mut().zip(font_predictions) {
+            tracing::debug!("Detected font for block {:?}: {:?}", block.text, prediction);
+
+            // fill style with prediction, and use default font families for now
+            let color = prediction.text_color;
+            let font_size = (prediction.font_size_px > 0.0).then_some(prediction.font_size_px);
+
+            block.font_prediction = Some(prediction);
+            block.style = Some(TextStyle {
+                font_size,
+                color: [color[0], color[1], color[2], 255],
+                ..Default::default()
+            });
+        }
+
+        Ok(())
+    }
+
     pub async fn detect_font(
         &self,
         image: &DynamicImage,
@@ -0,0 +1,519 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{Context as _, Result};
+use futures::future::BoxFuture;
+use koharu_ml::comic_text_detector::DetectOptions;
+use koharu_ml::llm::{ContextTurn, GenerateOptions, GlossaryEntry, PromptContext, PromptProfile};
+use koharu_ml::panel_detector::{self, PanelOptions};
+use koharu_renderer::renderer::TextShaderEffect;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+
+use crate::{
+    llm,
+    memory::TranslationMemory,
+    ml,
+    reading_order::ReadingOrder,
+    renderer::Renderer,
+    state::{Document, TextBlock},
+};
+
+/// Every stage the pipeline knows about, in the order a page is processed.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Display,
+    EnumString,
+    EnumIter,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "camelCase")]
+pub enum StageKind {
+    Detect,
+    FontDetect,
+    Ocr,
+    Inpaint,
+    Translate,
+    Render,
+}
+
+impl StageKind {
+    /// The stage with its default settings.
+    pub fn stage(self) -> Box<dyn Stage> {
+        match self {
+            StageKind::Detect => Box::new(Detect::default()),
+            StageKind::FontDetect => Box::new(FontDetect),
+            StageKind::Ocr => Box::new(Ocr),
+            StageKind::Inpaint => Box::new(Inpaint),
+            StageKind::Translate => Box::new(Translate::default()),
+            StageKind::Render => Box::new(Render::default()),
+        }
+    }
+}
+
+/// Models shared by the stages. A frontend only needs to provide the ones its stages use.
+#[derive(Default, Clone)]
+pub struct Context {
+    ml: Option<Arc<ml::Model>>,
+    llm: Option<Arc<llm::Model>>,
+    renderer: Option<Arc<Renderer>>,
+}
+
+impl Context {
+    pub fn with_ml(mut self, ml: Arc<ml::Model>) -> Self {
+        self.ml = Some(ml);
+        self
+    }
+
+    pub fn with_llm(mut self, llm: Arc<llm::Model>) -> Self {
+        self.llm = Some(llm);
+        self
+    }
+
+    pub fn with_renderer(mut self, renderer: Arc<Renderer>) -> Self {
+        self.renderer = Some(renderer);
+        self
+    }
+
+    fn ml(&self) -> Result<&ml::Model> {
+        self.ml
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Vision models are not loaded"))
+    }
+
+    fn llm(&self) -> Result<&llm::Model> {
+        self.llm
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No LLM is available"))
+    }
+
+    fn renderer(&self) -> Result<&Renderer> {
+        self.renderer
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Renderer is not available"))
+    }
+}
+
+/// A single step that updates a document in place.
+pub trait Stage: Send + Sync {
+    fn kind(&self) -> StageKind;
+
+    fn run<'a>(&'a self, ctx: &'a Context, document: &'a mut Document)
+    -> BoxFuture<'a, Result<()>>;
+}
+
+/// Progress reported while a pipeline runs.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum PipelineEvent {
+    #[serde(rename_all = "camelCase")]
+    StageStarted {
+        stage: StageKind,
+        step: usize,
+        total: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    StageFinished {
+        stage: StageKind,
+        step: usize,
+        total: usize,
+        elapsed_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    StageFailed {
+        stage: StageKind,
+        step: usize,
+        total: usize,
+        error: String,
+    },
+}
+
+/// Ordered list of stages shared by the desktop app, the batch CLI and the web server.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_stages(stages: Vec<Box<dyn Stage>>) -> Self {
+        Self { stages }
+    }
+
+    pub fn from_kinds(kinds: &[StageKind]) -> Self {
+        Self::from_stages(kinds.iter().map(|kind| kind.stage()).collect())
+    }
+
+    /// Every stage with default settings, from detection to rendering.
+    pub fn full() -> Self {
+        Self::from_stages(StageKind::iter().map(StageKind::stage).collect())
+    }
+
+    pub fn with_stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Run `stage` in place of the first stage of the same kind, if there is one.
+    pub fn replace_stage(mut self, stage: impl Stage + 'static) -> Self {
+        let kind = stage.kind();
+        if let Some(existing) = self
+            .stages
+            .iter_mut()
+            .find(|existing| existing.kind() == kind)
+        {
+            *existing = Box::new(stage);
+        }
+        self
+    }
+
+    pub fn kinds(&self) -> Vec<StageKind> {
+        self.stages.iter().map(|stage| stage.kind()).collect()
+    }
+
+    /// Run every stage on `document`, stopping at the first failure.
+    pub async fn run(
+        &self,
+        ctx: &Context,
+        document: &mut Document,
+        mut on_event: impl FnMut(PipelineEvent),
+    ) -> Result<()> {
+        let total = self.stages.len();
+        for (step, stage) in self.stages.iter().enumerate() {
+            let kind = stage.kind();
+            on_event(PipelineEvent::StageStarted {
+                stage: kind,
+                step,
+                total,
+            });
+
+            let started = Instant::now();
+            if let Err(err) = stage.run(ctx, document).await {
+                on_event(PipelineEvent::StageFailed {
+                    stage: kind,
+                    step,
+                    total,
+                    error: format!("{err:#}"),
+                });
+                return Err(err).with_context(|| format!("{kind} failed for {}", document.name));
+            }
+
+            on_event(PipelineEvent::StageFinished {
+                stage: kind,
+                step,
+                total,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Detect panels and text blocks, in reading order, and the text segmentation mask.
+///
+/// Webtoon strips are read top to bottom whatever their panels, which are then only detected
+/// when `keep_panels` is set.
+#[derive(Default)]
+pub struct Detect {
+    pub options: DetectOptions,
+    pub panel_options: PanelOptions,
+    pub reading_order: ReadingOrder,
+    /// Detect the panels whatever the reading order, for pages whose panels are exported.
+    pub keep_panels: bool,
+}
+
+impl Stage for Detect {
+    fn kind(&self) -> StageKind {
+        StageKind::Detect
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a Context,
+        document: &'a mut Document,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // panel detection is plain CPU work, run next to the text detection
+            let panels =
+                (self.keep_panels || self.reading_order != ReadingOrder::Webtoon).then(|| {
+                    let image = document.image.0.clone();
+                    let options = self.panel_options.clone();
+                    tokio::task::spawn_blocking(move || {
+                        panel_detector::detect_panels(&image, &options)
+                    })
+                });
+            let (mut text_blocks, segment) = ctx
+                .ml()?
+                .detect_dialog(&document.image, &self.options)
+                .await?;
+            let mut panels = match panels {
+                Some(panels) => panels.await.context("Panel detection failed")?,
+                None => Vec::new(),
+            };
+            self.reading_order.sort_panels(&mut panels);
+            self.reading_order.sort(&mut text_blocks, &panels);
+            document.text_blocks = text_blocks;
+            document.panels = panels;
+            document.segment = Some(segment);
+            Ok(())
+        })
+    }
+}
+
+/// Predict font, size and colors of every text block.
+pub struct FontDetect;
+
+impl Stage for FontDetect {
+    fn kind(&self) -> StageKind {
+        StageKind::FontDetect
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a Context,
+        document: &'a mut Document,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            ctx.ml()?
+                .detect_text_styles(&document.image, &mut document.text_blocks)
+                .await
+        })
+    }
+}
+
+/// Recognize the source text of every text block.
+pub struct Ocr;
+
+impl Stage for Ocr {
+    fn kind(&self) -> StageKind {
+        StageKind::Ocr
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a Context,
+        document: &'a mut Document,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            document.text_blocks = ctx
+                .ml()?
+                .ocr(&document.image, &document.text_blocks)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Erase the text covered by both the segment mask and a text block.
+pub struct Inpaint;
+
+impl Stage for Inpaint {
+    fn kind(&self) -> StageKind {
+        StageKind::Inpaint
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a Context,
+        document: &'a mut Document,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let segment = document
+                .segment
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Segment image not found"))?;
+            let mask = ml::mask_text_blocks(segment, &document.text_blocks);
+            document.inpainted = Some(ctx.ml()?.inpaint(&document.image, &mask).await?);
+            Ok(())
+        })
+    }
+}
+
+/// Translate the whole page, or a single text block.
+#[derive(Default)]
+pub struct Translate {
+    pub text_block_index: Option<usize>,
+    /// Target language by name or tag, the model's default when `None`.
+    pub language: Option<String>,
+    pub glossary: Vec<GlossaryEntry>,
+    /// Replaces the prompts of the model.
+    pub profile: Option<PromptProfile>,
+    /// Sampling settings, the model's defaults when `None`.
+    pub options: Option<GenerateOptions>,
+    /// Earlier translations replayed to the model, oldest first.
+    pub context: Vec<ContextTurn>,
+    /// Reused for whole pages and updated with their new translations, so it can be shared across pages.
+    pub memory: Option<Arc<Mutex<TranslationMemory>>>,
+    /// Send each block of the page as its own prompt instead of numbering them in one, which
+    /// costs about the same on models that decode the prompts together.
+    pub per_block: bool,
+    /// Receives the model output of the current prompt while it is generated.
+    pub on_output: Option<Arc<llm::OnOutput>>,
+    /// Receives the index of a block with its output while blocks are translated on their own.
+    pub on_block_output: Option<Arc<llm::OnBlockOutput>>,
+}
+
+impl Translate {
+    fn prompt_context(&self) -> PromptContext<'_> {
+        PromptContext {
+            glossary: &self.glossary,
+            history: &self.context,
+            profile: self.profile.as_ref(),
+            language: self.language.as_deref(),
+        }
+    }
+
+    fn on_output(&self) -> &llm::OnOutput {
+        self.on_output.as_deref().unwrap_or(&|_| {})
+    }
+
+    /// Translate the blocks of a page, numbered in one prompt or each on its own, with
+    /// `block_index` mapping their position to the one reported to `on_block_output`.
+    async fn translate_page(
+        &self,
+        llm: &llm::Model,
+        text_blocks: &mut Vec<TextBlock>,
+        block_index: &(dyn Fn(usize) -> usize + Sync),
+    ) -> Result<()> {
+        if self.per_block {
+            llm.generate_blocks(
+                text_blocks,
+                &self.prompt_context(),
+                self.options.as_ref(),
+                &|index, output| {
+                    if let Some(on_block_output) = &self.on_block_output {
+                        on_block_output(block_index(index), output);
+                    }
+                },
+            )
+            .await
+        } else {
+            llm.generate(
+                text_blocks,
+                &self.prompt_context(),
+                self.options.as_ref(),
+                self.on_output(),
+            )
+            .await
+        }
+    }
+
+    /// Only send the blocks the memory has no translation for to the model.
+    async fn translate_with_memory(
+        &self,
+        llm: &llm::Model,
+        document: &mut Document,
+        memory: &Mutex<TranslationMemory>,
+    ) -> Result<()> {
+        let pending = memory
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Translation memory is poisoned"))?
+            .apply(&mut document.text_blocks);
+        let reused = document.text_blocks.len() - pending.len();
+        if reused > 0 {
+            tracing::info!(
+                "Reused {reused} translations from memory for {}",
+                document.name
+            );
+        }
+
+        if !pending.is_empty() {
+            let mut blocks = pending
+                .iter()
+                .map(|&index| TextBlock {
+                    text: document.text_blocks[index].text.clone(),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            self.translate_page(llm, &mut blocks, &|index| pending[index])
+                .await?;
+            for (index, block) in pending.into_iter().zip(blocks) {
+                document.text_blocks[index].translation = block.translation;
+                document.text_blocks[index].status = block.status;
+            }
+        }
+
+        memory
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Translation memory is poisoned"))?
+            .insert_blocks(&document.text_blocks);
+        Ok(())
+    }
+}
+
+impl Stage for Translate {
+    fn kind(&self) -> StageKind {
+        StageKind::Translate
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a Context,
+        document: &'a mut Document,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let llm = ctx.llm()?;
+
+            match self.text_block_index {
+                Some(index) => {
+                    let text_block = document
+                        .text_blocks
+                        .get_mut(index)
+                        .ok_or_else(|| anyhow::anyhow!("Text block not found"))?;
+                    llm.generate(
+                        text_block,
+                        &self.prompt_context(),
+                        self.options.as_ref(),
+                        self.on_output(),
+                    )
+                    .await
+                }
+                None if document.text_blocks.is_empty() => Ok(()),
+                None => match self.memory.as_deref() {
+                    Some(memory) => self.translate_with_memory(llm, document, memory).await,
+                    None => {
+                        self.translate_page(llm, &mut document.text_blocks, &|index| index)
+                            .await
+                    }
+                },
+            }
+        })
+    }
+}
+
+/// Render translations and compose them onto the inpainted page.
+#[derive(Default)]
+pub struct Render {
+    pub text_block_index: Option<usize>,
+    pub effect: TextShaderEffect,
+}
+
+impl Stage for Render {
+    fn kind(&self) -> StageKind {
+        StageKind::Render
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a Context,
+        document: &'a mut Document,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            ctx.renderer()?
+                .render(document, self.text_block_index, self.effect)
+        })
+    }
+}
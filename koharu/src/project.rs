@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use koharu_ml::llm::{GenerateOptions, GlossaryEntry};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    khr::{is_khr_file, read_project},
+    reading_order::ReadingOrder,
+};
+
+/// Settings shared by every page of a project, saved in the KHR index.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Project {
+    /// Terms the translator should keep consistent across pages.
+    pub glossary: Vec<GlossaryEntry>,
+    /// Name of the prompt profile used instead of the model's own prompts.
+    pub prompt_profile: Option<String>,
+    /// Sampling settings for translations that don't bring their own.
+    pub generate_options: Option<GenerateOptions>,
+    /// Order text blocks are read and translated in, manga order when `None`.
+    pub reading_order: Option<ReadingOrder>,
+}
+
+impl Project {
+    /// Settings of the KHR files among `paths`, merged in order. Images contribute nothing.
+    pub fn load(paths: &[PathBuf]) -> Self {
+        let mut project = Self::default();
+        for path in paths {
+            if !is_khr_file(path).unwrap_or(false) {
+                continue;
+            }
+            match read_project(path) {
+                Ok(other) => project.merge(other),
+                Err(err) => warn!(
+                    "Failed to read project settings of {}: {err:#}",
+                    path.display()
+                ),
+            }
+        }
+        project
+    }
+
+    /// Add the glossary entries of `other` for terms that aren't defined yet, and its
+    /// prompt profile, sampling settings and reading order where none are set yet.
+    pub fn merge(&mut self, other: Project) {
+        if self.prompt_profile.is_none() {
+            self.prompt_profile = other.prompt_profile;
+        }
+        if self.generate_options.is_none() {
+            self.generate_options = other.generate_options;
+        }
+        if self.reading_order.is_none() {
+            self.reading_order = other.reading_order;
+        }
+        for entry in other.glossary {
+            if !self
+                .glossary
+                .iter()
+                .any(|existing| existing.source == entry.source)
+            {
+                self.glossary.push(entry);
+            }
+        }
+    }
+}
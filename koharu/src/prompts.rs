@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use koharu_ml::llm::{PromptProfile, default_profiles};
+use serde::{Deserialize, Serialize};
+
+const SYSTEM_FILE: &str = "system.jinja";
+const USER_FILE: &str = "user.jinja";
+
+/// A prompt profile with the name it is selected by.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamedProfile {
+    pub name: String,
+    #[serde(flatten)]
+    pub profile: PromptProfile,
+}
+
+/// Prompt profiles kept as editable MiniJinja templates, one directory per profile holding
+/// `user.jinja` and an optional `system.jinja`.
+pub struct PromptProfiles {
+    dir: PathBuf,
+}
+
+impl PromptProfiles {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Write the built-in prompts as profiles, leaving the ones that already exist untouched.
+    pub fn install_defaults(&self) -> anyhow::Result<()> {
+        for (name, profile) in default_profiles() {
+            if !self.profile_dir(name)?.exists() {
+                self.save(name, &profile)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> anyhow::Result<Vec<NamedProfile>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut names = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(USER_FILE).is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let profile = self.load(&name)?;
+                Ok(NamedProfile { name, profile })
+            })
+            .collect()
+    }
+
+    pub fn load(&self, name: &str) -> anyhow::Result<PromptProfile> {
+        read_profile(&self.profile_dir(name)?)
+            .with_context(|| format!("Failed to load prompt profile `{name}`"))
+    }
+
+    /// Save `profile` under `name`, refusing templates that don't compile.
+    pub fn save(&self, name: &str, profile: &PromptProfile) -> anyhow::Result<()> {
+        profile
+            .validate()
+            .with_context(|| format!("Prompt profile `{name}` is invalid"))?;
+        let dir = self.profile_dir(name)?;
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(USER_FILE), &profile.user)?;
+        match &profile.system {
+            Some(system) => std::fs::write(dir.join(SYSTEM_FILE), system)?,
+            None => match std::fs::remove_file(dir.join(SYSTEM_FILE)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    fn profile_dir(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && Path::new(name).file_name() == Some(name.as_ref());
+        anyhow::ensure!(valid, "Invalid prompt profile name `{name}`");
+        Ok(self.dir.join(name))
+    }
+}
+
+/// Read the profile stored in `dir`.
+pub fn read_profile(dir: &Path) -> anyhow::Result<PromptProfile> {
+    let user = std::fs::read_to_string(dir.join(USER_FILE))
+        .with_context(|| format!("{} has no {USER_FILE}", dir.display()))?;
+    let system = match std::fs::read_to_string(dir.join(SYSTEM_FILE)) {
+        Ok(system) => Some(system),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    Ok(PromptProfile { system, user })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_installed_once_and_edits_survive() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("koharu-prompts-{}", std::process::id()));
+        let profiles = PromptProfiles::new(&dir);
+        profiles.install_defaults()?;
+
+        let names = profiles
+            .list()?
+            .into_iter()
+            .map(|profile| profile.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["generic", "hunyuan-mt", "lfm2", "sakura", "vntl"]);
+        assert_eq!(profiles.load("vntl")?.system, None);
+
+        let casual = PromptProfile::new(Some("Keep it casual."), "{{ text }}");
+        profiles.save("sakura", &casual)?;
+        profiles.install_defaults()?;
+        assert_eq!(profiles.load("sakura")?, casual);
+
+        assert!(
+            profiles
+                .save("broken", &PromptProfile::new(None, "{{"))
+                .is_err()
+        );
+        assert!(profiles.load("../sakura").is_err());
+        assert!(profiles.load("missing").is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
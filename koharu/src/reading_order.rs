@@ -0,0 +1,351 @@
+//! Order of the text blocks of a page, which is also the order they are translated in.
+//!
+//! Manga and comic pages are ordered with a recursive XY-cut: blocks are split into rows at
+//! horizontal gaps no block crosses, rows into columns at vertical gaps, and so on, which
+//! follows the panel layout as long as no block crosses a gutter. When the panels of the page are
+//! known, the panels are ordered first and the blocks are read panel by panel.
+
+use koharu_ml::panel_detector::Panel;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use crate::state::TextBlock;
+
+/// How the text blocks of a page are read.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Display,
+    EnumString,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "camelCase")]
+pub enum ReadingOrder {
+    /// Rows top to bottom, each read right to left.
+    #[default]
+    Manga,
+    /// Rows top to bottom, each read left to right.
+    Comic,
+    /// Top to bottom only, for long vertical strips.
+    Webtoon,
+}
+
+impl ReadingOrder {
+    /// Sort `blocks` in reading order, panel by panel. Blocks outside every panel are read
+    /// where they sit among the panels.
+    pub fn sort(self, blocks: &mut Vec<TextBlock>, panels: &[Panel]) {
+        let rects: Vec<Rect> = blocks.iter().map(Rect::from).collect();
+        let order = self.order_in_panels(&rects, panels);
+        reorder(blocks, order);
+    }
+
+    /// Sort `panels` in reading order.
+    pub fn sort_panels(self, panels: &mut Vec<Panel>) {
+        let rects: Vec<Rect> = panels.iter().map(Rect::from).collect();
+        let order = self.order(&rects);
+        reorder(panels, order);
+    }
+
+    /// Indices of `rects` in reading order, reading the rects of a panel one after another.
+    fn order_in_panels(self, rects: &[Rect], panels: &[Panel]) -> Vec<usize> {
+        if panels.is_empty() {
+            return self.order(rects);
+        }
+
+        // every panel with the rects centered in it is a unit, and so is every other rect
+        let mut members = vec![Vec::new(); panels.len()];
+        let mut loose = Vec::new();
+        for (i, rect) in rects.iter().enumerate() {
+            let (x, y) = (rect.center(Axis::X), rect.center(Axis::Y));
+            match panels.iter().position(|panel| panel.contains(x, y)) {
+                Some(panel) => members[panel].push(i),
+                None => loose.push(i),
+            }
+        }
+        let units: Vec<Rect> = panels
+            .iter()
+            .map(Rect::from)
+            .chain(loose.iter().map(|&i| rects[i]))
+            .collect();
+
+        let mut order = Vec::with_capacity(rects.len());
+        for unit in self.order(&units) {
+            let Some(members) = members.get(unit) else {
+                order.push(loose[unit - panels.len()]);
+                continue;
+            };
+            let inside: Vec<Rect> = members.iter().map(|&i| rects[i]).collect();
+            order.extend(self.order(&inside).into_iter().map(|i| members[i]));
+        }
+        order
+    }
+
+    /// Indices of `rects` in reading order.
+    fn order(self, rects: &[Rect]) -> Vec<usize> {
+        let indices = (0..rects.len()).collect();
+        match self {
+            ReadingOrder::Manga | ReadingOrder::Comic => {
+                let mut order = Vec::with_capacity(rects.len());
+                xy_cut(indices, rects, self == ReadingOrder::Manga, &mut order);
+                order
+            }
+            ReadingOrder::Webtoon => sorted_by_center(indices, rects, false),
+        }
+    }
+}
+
+/// Move the items of `items` to the positions given by `order`.
+fn reorder<T>(items: &mut Vec<T>, order: Vec<usize>) {
+    let mut items_by_index: Vec<Option<T>> = items.drain(..).map(Some).collect();
+    items.extend(order.into_iter().filter_map(|i| items_by_index[i].take()));
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl From<&TextBlock> for Rect {
+    fn from(block: &TextBlock) -> Self {
+        Rect {
+            x0: block.x,
+            y0: block.y,
+            x1: block.x + block.width,
+            y1: block.y + block.height,
+        }
+    }
+}
+
+impl From<&Panel> for Rect {
+    fn from(panel: &Panel) -> Self {
+        let [x0, y0, x1, y1] = panel.bbox();
+        Rect {
+            x0: x0 as f32,
+            y0: y0 as f32,
+            x1: x1 as f32,
+            y1: y1 as f32,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+}
+
+impl Rect {
+    fn span(&self, axis: Axis) -> (f32, f32) {
+        match axis {
+            Axis::X => (self.x0, self.x1),
+            Axis::Y => (self.y0, self.y1),
+        }
+    }
+
+    fn center(&self, axis: Axis) -> f32 {
+        let (start, end) = self.span(axis);
+        (start + end) / 2.0
+    }
+}
+
+/// Append `indices` to `order` row by row, reading each row right to left when `rtl`.
+fn xy_cut(indices: Vec<usize>, rects: &[Rect], rtl: bool, order: &mut Vec<usize>) {
+    if indices.len() <= 1 {
+        order.extend(indices);
+        return;
+    }
+
+    let rows = split(&indices, rects, Axis::Y);
+    if rows.len() > 1 {
+        for row in rows {
+            xy_cut(row, rects, rtl, order);
+        }
+        return;
+    }
+
+    let mut columns = split(&indices, rects, Axis::X);
+    if columns.len() > 1 {
+        if rtl {
+            columns.reverse();
+        }
+        for column in columns {
+            xy_cut(column, rects, rtl, order);
+        }
+        return;
+    }
+
+    // blocks overlapping both ways can't be cut apart
+    order.extend(sorted_by_center(indices, rects, rtl));
+}
+
+/// Groups of `indices` separated by gaps along `axis` that no rect crosses, in increasing order.
+fn split(indices: &[usize], rects: &[Rect], axis: Axis) -> Vec<Vec<usize>> {
+    let mut sorted = indices.to_vec();
+    sorted.sort_by(|&a, &b| rects[a].span(axis).0.total_cmp(&rects[b].span(axis).0));
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut end = f32::NEG_INFINITY;
+    for i in sorted {
+        let (start, stop) = rects[i].span(axis);
+        match groups.last_mut() {
+            Some(group) if start < end => group.push(i),
+            _ => groups.push(vec![i]),
+        }
+        end = end.max(stop);
+    }
+    groups
+}
+
+/// `indices` by vertical center, then horizontally.
+fn sorted_by_center(mut indices: Vec<usize>, rects: &[Rect], rtl: bool) -> Vec<usize> {
+    indices.sort_by(|&a, &b| {
+        let (a, b) = (&rects[a], &rects[b]);
+        let horizontal = a.center(Axis::X).total_cmp(&b.center(Axis::X));
+        a.center(Axis::Y)
+            .total_cmp(&b.center(Axis::Y))
+            .then(if rtl {
+                horizontal.reverse()
+            } else {
+                horizontal
+            })
+    });
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(text: &str, x: f32, y: f32, width: f32, height: f32) -> TextBlock {
+        TextBlock {
+            x,
+            y,
+            width,
+            height,
+            text: Some(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn read(order: ReadingOrder, mut blocks: Vec<TextBlock>) -> Vec<String> {
+        order.sort(&mut blocks, &[]);
+        blocks.into_iter().filter_map(|block| block.text).collect()
+    }
+
+    /// Four panels in two rows, one block each, the right column sitting a bit lower.
+    fn grid() -> Vec<TextBlock> {
+        vec![
+            block("bottom-left", 10.0, 120.0, 30.0, 40.0),
+            block("top-right", 60.0, 20.0, 30.0, 40.0),
+            block("bottom-right", 60.0, 130.0, 30.0, 40.0),
+            block("top-left", 10.0, 10.0, 30.0, 40.0),
+        ]
+    }
+
+    #[test]
+    fn manga_rows_are_read_right_to_left() {
+        assert_eq!(
+            read(ReadingOrder::Manga, grid()),
+            ["top-right", "top-left", "bottom-right", "bottom-left"]
+        );
+    }
+
+    #[test]
+    fn comic_rows_are_read_left_to_right() {
+        assert_eq!(
+            read(ReadingOrder::Comic, grid()),
+            ["top-left", "top-right", "bottom-left", "bottom-right"]
+        );
+    }
+
+    #[test]
+    fn tall_panel_comes_before_the_stack_beside_it() {
+        // a tall panel on the right, two stacked panels on the left
+        let blocks = vec![
+            block("lower-left", 10.0, 110.0, 30.0, 40.0),
+            block("upper-left", 10.0, 10.0, 30.0, 40.0),
+            block("tall-right", 60.0, 30.0, 30.0, 100.0),
+        ];
+        assert_eq!(
+            read(ReadingOrder::Manga, blocks.clone()),
+            ["tall-right", "upper-left", "lower-left"]
+        );
+        assert_eq!(
+            read(ReadingOrder::Comic, blocks),
+            ["upper-left", "lower-left", "tall-right"]
+        );
+    }
+
+    #[test]
+    fn webtoon_is_read_top_to_bottom() {
+        let blocks = vec![
+            block("third", 0.0, 400.0, 50.0, 20.0),
+            block("first", 60.0, 10.0, 50.0, 20.0),
+            block("second", 0.0, 200.0, 200.0, 20.0),
+        ];
+        assert_eq!(
+            read(ReadingOrder::Webtoon, blocks),
+            ["first", "second", "third"]
+        );
+    }
+
+    fn panel(x0: u32, y0: u32, x1: u32, y1: u32) -> Panel {
+        Panel {
+            polygon: vec![[x0, y0], [x1, y0], [x1, y1], [x0, y1]],
+        }
+    }
+
+    #[test]
+    fn blocks_are_read_panel_by_panel() {
+        // a tall panel on the right whose only block sits low, two stacked panels on the left,
+        // and a caption below them outside every panel
+        let panels = vec![
+            panel(0, 0, 90, 90),
+            panel(0, 100, 90, 200),
+            panel(100, 0, 200, 200),
+        ];
+        let blocks = vec![
+            block("caption", 50.0, 210.0, 100.0, 20.0),
+            block("lower-left", 20.0, 120.0, 40.0, 40.0),
+            block("tall-right", 130.0, 140.0, 40.0, 40.0),
+            block("upper-left", 20.0, 10.0, 40.0, 40.0),
+        ];
+
+        // on their own, the low block on the right is read with the lower left one
+        assert_eq!(
+            read(ReadingOrder::Manga, blocks.clone()),
+            ["upper-left", "tall-right", "lower-left", "caption"]
+        );
+
+        let mut in_panels = blocks;
+        ReadingOrder::Manga.sort(&mut in_panels, &panels);
+        let texts: Vec<_> = in_panels.into_iter().filter_map(|b| b.text).collect();
+        assert_eq!(texts, ["tall-right", "upper-left", "lower-left", "caption"]);
+
+        let mut panels = panels;
+        ReadingOrder::Manga.sort_panels(&mut panels);
+        let tops: Vec<_> = panels.iter().map(|p| p.bbox()[..2].to_vec()).collect();
+        assert_eq!(tops, [[100, 0], [0, 0], [0, 100]]);
+    }
+
+    #[test]
+    fn overlapping_blocks_fall_back_to_their_centers() {
+        // each overlaps the other both ways, so no cut separates them
+        let blocks = vec![
+            block("left", 0.0, 0.0, 60.0, 60.0),
+            block("right", 40.0, 0.0, 60.0, 60.0),
+        ];
+        assert_eq!(read(ReadingOrder::Manga, blocks.clone()), ["right", "left"]);
+        assert_eq!(read(ReadingOrder::Comic, blocks), ["left", "right"]);
+    }
+}
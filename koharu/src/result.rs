@@ -1,3 +1,6 @@
+use koharu_ml::llm::UnsupportedLanguage;
+use serde::ser::SerializeStruct;
+
 #[derive(Debug, thiserror::Error)]
 pub enum CommandError {
     #[error(transparent)]
@@ -7,19 +10,83 @@ pub enum CommandError {
     Parse(#[from] strum::ParseError),
 
     #[error(transparent)]
-    Anyhow(#[from] anyhow::Error),
+    UnsupportedLanguage(#[from] UnsupportedLanguage),
+
+    #[error(transparent)]
+    Anyhow(anyhow::Error),
 
     #[error(transparent)]
     Window(#[from] tauri::Error),
 }
 
+impl From<anyhow::Error> for CommandError {
+    fn from(err: anyhow::Error) -> Self {
+        // the stages wrap their errors with context, which would hide the ones the UI handles
+        match err.downcast_ref::<UnsupportedLanguage>() {
+            Some(unsupported) => CommandError::UnsupportedLanguage(unsupported.clone()),
+            None => CommandError::Anyhow(err),
+        }
+    }
+}
+
+/// Errors are sent to the UI as their message, except an unsupported language, which is an
+/// object with `kind: "unsupportedLanguage"`, the `message` and the `model`, `language` and
+/// `supported` languages.
 impl serde::Serialize for CommandError {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        match self {
+            CommandError::UnsupportedLanguage(err) => {
+                let mut state = serializer.serialize_struct("UnsupportedLanguage", 5)?;
+                state.serialize_field("kind", "unsupportedLanguage")?;
+                state.serialize_field("message", &err.to_string())?;
+                state.serialize_field("model", &err.model)?;
+                state.serialize_field("language", &err.language)?;
+                state.serialize_field("supported", &err.supported)?;
+                state.end()
+            }
+            _ => serializer.serialize_str(&self.to_string()),
+        }
     }
 }
 
 pub type Result<T> = std::result::Result<T, CommandError>;
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn unsupported_language_survives_context() {
+        let unsupported = UnsupportedLanguage {
+            model: "sakura".to_string(),
+            language: "Klingon".to_string(),
+            supported: vec!["Chinese".to_string()],
+        };
+        let err = Err::<(), _>(unsupported.clone())
+            .context("Translate failed for page")
+            .unwrap_err();
+
+        let err = CommandError::from(err);
+        assert!(matches!(&err, CommandError::UnsupportedLanguage(e) if *e == unsupported));
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({
+                "kind": "unsupportedLanguage",
+                "message": unsupported.to_string(),
+                "model": "sakura",
+                "language": "Klingon",
+                "supported": ["Chinese"],
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(CommandError::from(anyhow::anyhow!("No model is loaded")))
+                .unwrap(),
+            "No model is loaded"
+        );
+    }
+}
@@ -1,15 +1,19 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
-use anyhow::anyhow;
-use image::GenericImageView;
-use koharu_ml::font_detector::FontPrediction;
+use anyhow::{Context, anyhow};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage, imageops};
+use koharu_ml::{font_detector::FontPrediction, panel_detector::Panel};
 use koharu_renderer::renderer::TextShaderEffect;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use tracing::warn;
 
 use crate::{
     image::SerializableDynamicImage,
-    khr::{deserialize_khr, has_khr_magic},
+    import::{self, natural_cmp},
+    khr::{KhrReader, is_khr_file},
+    project::Project,
 };
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +29,28 @@ pub struct TextBlock {
     pub style: Option<TextStyle>,
     pub font_prediction: Option<FontPrediction>,
     pub rendered: Option<SerializableDynamicImage>,
+    #[serde(default)]
+    pub status: TranslationStatus,
+    /// Class the text detector assigned to the block.
+    #[serde(default)]
+    pub class_index: usize,
+}
+
+/// How the translation of a [`TextBlock`] came about.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TranslationStatus {
+    /// Not translated by the model yet.
+    #[default]
+    Pending,
+    /// Answered in the page translation.
+    Translated,
+    /// Missing from the page translation, or next to a missing block, then translated on its own.
+    Retried,
+    /// Copied from an earlier translation of the same text.
+    Memory,
+    /// Still missing after the retry, or next to a missing block with only its page answer.
+    Failed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,6 +97,9 @@ pub struct Document {
     pub width: u32,
     pub height: u32,
     pub text_blocks: Vec<TextBlock>,
+    /// Panels of the page, in reading order.
+    #[serde(default)]
+    pub panels: Vec<Panel>,
     pub segment: Option<SerializableDynamicImage>,
     pub inpainted: Option<SerializableDynamicImage>,
     pub rendered: Option<SerializableDynamicImage>,
@@ -78,26 +107,42 @@ pub struct Document {
 }
 
 impl Document {
-    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
-        let bytes = std::fs::read(&path)?;
+    /// Open an image, every page of a KHR project, or every image of an archive or PDF.
+    pub fn open(path: PathBuf) -> anyhow::Result<Vec<Self>> {
+        let is_khr = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("khr"));
+        if is_khr || is_khr_file(&path)? {
+            return KhrReader::open(&path)?.documents();
+        }
 
-        if has_khr_magic(&bytes) {
-            return Self::khr(path, bytes);
+        if import::is_importable(&path) {
+            return import::import(&path);
         }
 
-        Self::image(path, bytes)
+        Ok(vec![Self::open_image(path)?])
     }
 
-    fn image(path: PathBuf, bytes: Vec<u8>) -> anyhow::Result<Self> {
-        let img = image::load_from_memory(&bytes)?;
-        let (width, height) = img.dimensions();
-        let id = blake3::hash(&bytes).to_hex().to_string();
+    pub fn open_image(path: PathBuf) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(&path)?;
         let name = path
             .file_stem()
             .unwrap_or_default()
             .to_string_lossy()
             .to_string();
-        Ok(Document {
+        Self::from_image_bytes(path, name, &bytes)
+    }
+
+    pub fn from_image_bytes(path: PathBuf, name: String, bytes: &[u8]) -> anyhow::Result<Self> {
+        let img = image::load_from_memory(bytes)?;
+        Ok(Self::from_image(path, name, bytes, img))
+    }
+
+    /// `bytes` are only hashed for the document id.
+    pub fn from_image(path: PathBuf, name: String, bytes: &[u8], img: DynamicImage) -> Self {
+        let (width, height) = img.dimensions();
+        let id = blake3::hash(bytes).to_hex().to_string();
+        Document {
             id,
             path,
             name,
@@ -105,20 +150,544 @@ impl Document {
             width,
             height,
             ..Default::default()
+        }
+    }
+}
+
+/// Open every path in natural file name order. Files that fail to load are skipped unless none load.
+pub fn load_documents(mut paths: Vec<PathBuf>) -> anyhow::Result<Vec<Document>> {
+    paths.sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name().unwrap_or_default().to_string_lossy(),
+            &b.file_name().unwrap_or_default().to_string_lossy(),
+        )
+    });
+
+    let results = paths
+        .into_par_iter()
+        .map(|path| {
+            Document::open(path.clone())
+                .with_context(|| format!("Failed to open {}", path.display()))
+        })
+        .collect::<Vec<_>>();
+
+    let mut documents = Vec::new();
+    let mut last_error = None;
+    for result in results {
+        match result {
+            Ok(opened) => documents.extend(opened),
+            Err(err) => {
+                warn!("{err:#}");
+                last_error = Some(err);
+            }
+        }
+    }
+
+    match last_error {
+        Some(err) if documents.is_empty() => Err(err),
+        _ => Ok(documents),
+    }
+}
+
+/// Maximum number of edits kept per document.
+const HISTORY_LIMIT: usize = 50;
+/// Memory the edits of a document may take. A full-page edit of a 4K page is about 66 MB.
+const HISTORY_BYTES: usize = 256 * 1024 * 1024;
+
+/// Image layers of a [`Document`] tracked by the edit history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Segment,
+    BrushLayer,
+    Inpainted,
+    Rendered,
+}
+
+impl Layer {
+    fn get(self, document: &Document) -> Option<&SerializableDynamicImage> {
+        match self {
+            Layer::Segment => document.segment.as_ref(),
+            Layer::BrushLayer => document.brush_layer.as_ref(),
+            Layer::Inpainted => document.inpainted.as_ref(),
+            Layer::Rendered => document.rendered.as_ref(),
+        }
+    }
+
+    fn slot(self, document: &mut Document) -> &mut Option<SerializableDynamicImage> {
+        match self {
+            Layer::Segment => &mut document.segment,
+            Layer::BrushLayer => &mut document.brush_layer,
+            Layer::Inpainted => &mut document.inpainted,
+            Layer::Rendered => &mut document.rendered,
+        }
+    }
+
+    /// What the layer looks like while it does not exist, matching what the commands start from.
+    fn base(self, document: &Document, (x, y, width, height): Rect) -> RgbaImage {
+        match self {
+            Layer::Segment => RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255])),
+            Layer::BrushLayer | Layer::Rendered => {
+                RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 0]))
+            }
+            Layer::Inpainted => document.image.crop_imm(x, y, width, height).to_rgba8(),
+        }
+    }
+
+    fn crop(self, document: &Document, (x, y, width, height): Rect) -> RgbaImage {
+        match self.get(document) {
+            Some(image) => image.crop_imm(x, y, width, height).to_rgba8(),
+            None => self.base(document, (x, y, width, height)),
+        }
+    }
+}
+
+/// `(x, y, width, height)` in document pixels.
+pub type Rect = (u32, u32, u32, u32);
+
+/// Changed pixels of one layer, cropped to the bounding box of the change.
+#[derive(Debug, Clone)]
+struct LayerDiff {
+    layer: Layer,
+    x: u32,
+    y: u32,
+    existed_before: bool,
+    exists_after: bool,
+    before: RgbaImage,
+    after: RgbaImage,
+}
+
+impl LayerDiff {
+    fn bytes(&self) -> usize {
+        self.before.as_raw().len() + self.after.as_raw().len()
+    }
+
+    fn apply(&self, document: &mut Document, forward: bool) {
+        let (exists, pixels) = match forward {
+            true => (self.exists_after, &self.after),
+            false => (self.existed_before, &self.before),
+        };
+        if !exists {
+            *self.layer.slot(document) = None;
+            return;
+        }
+
+        let (width, height) = (document.width, document.height);
+        let mut image = match self.layer.get(document) {
+            Some(image) => image.to_rgba8(),
+            None => self.layer.base(document, (0, 0, width, height)),
+        };
+        imageops::replace(&mut image, pixels, self.x as i64, self.y as i64);
+        *self.layer.slot(document) = Some(DynamicImage::ImageRgba8(image).into());
+    }
+}
+
+/// Text blocks kept by the history. Their rendered bitmaps are left out, since they are large
+/// and can be rendered again; `rendered` marks the blocks that had one.
+#[derive(Debug, Clone)]
+struct TextBlocks {
+    blocks: Vec<TextBlock>,
+    rendered: Vec<bool>,
+}
+
+impl TextBlocks {
+    fn new(blocks: &[TextBlock]) -> Self {
+        Self {
+            blocks: blocks
+                .iter()
+                .map(|block| TextBlock {
+                    rendered: None,
+                    ..block.clone()
+                })
+                .collect(),
+            rendered: blocks
+                .iter()
+                .map(|block| block.rendered.is_some())
+                .collect(),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|block| {
+                let text = |text: &Option<String>| text.as_ref().map_or(0, String::len);
+                size_of::<TextBlock>() + text(&block.text) + text(&block.translation)
+            })
+            .sum()
+    }
+
+    /// Put the blocks back into `document`. Returns the indices of the blocks to render again.
+    fn restore(&self, document: &mut Document) -> Vec<usize> {
+        document.text_blocks = self.blocks.clone();
+        (0..self.blocks.len())
+            .filter(|&index| self.rendered[index])
+            .collect()
+    }
+}
+
+/// A single undoable change to a document.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    text_blocks: Option<(TextBlocks, TextBlocks)>,
+    layers: Vec<LayerDiff>,
+    /// Memory taken by the edit.
+    bytes: usize,
+}
+
+impl Edit {
+    /// Returns the indices of the text blocks to render again.
+    fn apply(&self, document: &mut Document, forward: bool) -> Vec<usize> {
+        let stale = match &self.text_blocks {
+            Some((before, after)) => match forward {
+                true => after.restore(document),
+                false => before.restore(document),
+            },
+            None => Vec::new(),
+        };
+        for diff in &self.layers {
+            diff.apply(document, forward);
+        }
+        stale
+    }
+}
+
+/// State of a document captured right before a command mutates it.
+#[derive(Default)]
+pub struct Snapshot {
+    text_blocks: Option<TextBlocks>,
+    layers: Vec<(Layer, Rect, bool, RgbaImage)>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text_blocks(mut self, document: &Document) -> Self {
+        self.text_blocks = Some(TextBlocks::new(&document.text_blocks));
+        self
+    }
+
+    /// Remember `region` of `layer`, or the whole layer when the command may touch all of it.
+    pub fn layer(mut self, document: &Document, layer: Layer, region: Option<Rect>) -> Self {
+        let rect = region.unwrap_or((0, 0, document.width, document.height));
+        let existed = layer.get(document).is_some();
+        self.layers
+            .push((layer, rect, existed, layer.crop(document, rect)));
+        self
+    }
+
+    /// Diff against the mutated document. Returns `None` when nothing changed.
+    pub fn finish(self, document: &Document) -> Option<Edit> {
+        let text_blocks = self
+            .text_blocks
+            .map(|before| (before, TextBlocks::new(&document.text_blocks)));
+
+        let layers = self
+            .layers
+            .into_iter()
+            .filter_map(|(layer, rect, existed_before, before)| {
+                let exists_after = layer.get(document).is_some();
+                let after = layer.crop(document, rect);
+                let (x, y, width, height) = match changed_bounds(&before, &after) {
+                    Some(bounds) => bounds,
+                    None if existed_before == exists_after => return None,
+                    None => (0, 0, 0, 0),
+                };
+                Some(LayerDiff {
+                    layer,
+                    x: rect.0 + x,
+                    y: rect.1 + y,
+                    existed_before,
+                    exists_after,
+                    before: before.view(x, y, width, height).to_image(),
+                    after: after.view(x, y, width, height).to_image(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if text_blocks.is_none() && layers.is_empty() {
+            return None;
+        }
+
+        let bytes = text_blocks
+            .as_ref()
+            .map_or(0, |(before, after)| before.bytes() + after.bytes())
+            + layers.iter().map(LayerDiff::bytes).sum::<usize>();
+        Some(Edit {
+            text_blocks,
+            layers,
+            bytes,
         })
     }
+}
+
+/// Bounding box of the pixels that differ between two images of the same size.
+fn changed_bounds(before: &RgbaImage, after: &RgbaImage) -> Option<Rect> {
+    let (mut x0, mut y0, mut x1, mut y1) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in after.enumerate_pixels() {
+        if before.get_pixel(x, y) != pixel {
+            x0 = x0.min(x);
+            y0 = y0.min(y);
+            x1 = x1.max(x + 1);
+            y1 = y1.max(y + 1);
+        }
+    }
+    (x0 < x1).then(|| (x0, y0, x1 - x0, y1 - y0))
+}
+
+/// Undo/redo stacks of a single document, holding at most [`HISTORY_LIMIT`] edits and
+/// [`HISTORY_BYTES`] of memory. The latest edit is kept whatever its size.
+#[derive(Debug, Clone)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    /// Memory taken by the edits of both stacks.
+    bytes: usize,
+    budget: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            bytes: 0,
+            budget: HISTORY_BYTES,
+        }
+    }
+}
 
-    fn khr(_path: PathBuf, bytes: Vec<u8>) -> anyhow::Result<Self> {
-        let docs = deserialize_khr(&bytes)?;
-        docs.into_iter()
-            .next()
-            .ok_or_else(|| anyhow!("No document found in KHR file"))
+impl History {
+    pub fn record(&mut self, edit: Option<Edit>) {
+        let Some(edit) = edit else {
+            return;
+        };
+        for undone in self.redo.drain(..) {
+            self.bytes -= undone.bytes;
+        }
+        self.bytes += edit.bytes;
+        self.undo.push(edit);
+        while self.undo.len() > HISTORY_LIMIT || (self.bytes > self.budget && self.undo.len() > 1) {
+            self.bytes -= self.undo.remove(0).bytes;
+        }
+    }
+
+    /// Revert the latest edit. Returns the indices of the text blocks to render again, or `None`
+    /// when there is nothing to undo.
+    pub fn undo(&mut self, document: &mut Document) -> Option<Vec<usize>> {
+        let edit = self.undo.pop()?;
+        let stale = edit.apply(document, false);
+        self.redo.push(edit);
+        Some(stale)
+    }
+
+    /// Reapply the latest undone edit. Returns the indices of the text blocks to render again,
+    /// or `None` when there is nothing to redo.
+    pub fn redo(&mut self, document: &mut Document) -> Option<Vec<usize>> {
+        let edit = self.redo.pop()?;
+        let stale = edit.apply(document, true);
+        self.undo.push(edit);
+        Some(stale)
     }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     pub documents: Vec<Document>,
+    pub project: Project,
+    /// Edit history keyed by the index of the document. Documents are only ever appended, and
+    /// the history is cleared when they are replaced, so identical pages keep their own.
+    #[serde(skip)]
+    pub history: HashMap<usize, History>,
+}
+
+impl State {
+    pub fn document_with_history(
+        &mut self,
+        index: usize,
+    ) -> anyhow::Result<(&mut Document, &mut History)> {
+        let document = self
+            .documents
+            .get_mut(index)
+            .ok_or_else(|| anyhow!("Document not found"))?;
+        let history = self.history.entry(index).or_default();
+        Ok((document, history))
+    }
 }
 
 pub type AppState = Arc<RwLock<State>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> Document {
+        let image = RgbaImage::from_pixel(16, 12, Rgba([200, 200, 200, 255]));
+        Document {
+            id: "page".to_string(),
+            image: DynamicImage::ImageRgba8(image).into(),
+            width: 16,
+            height: 12,
+            ..Default::default()
+        }
+    }
+
+    fn block(text: &str) -> TextBlock {
+        TextBlock {
+            text: Some(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Paint a `width` x `height` square of `color` at `x`, `y` on the brush layer.
+    fn paint(document: &mut Document, x: u32, y: u32, width: u32, height: u32, color: u8) {
+        let mut layer = match &document.brush_layer {
+            Some(layer) => layer.to_rgba8(),
+            None => RgbaImage::new(document.width, document.height),
+        };
+        for y in y..y + height {
+            for x in x..x + width {
+                layer.put_pixel(x, y, Rgba([color, color, color, 255]));
+            }
+        }
+        document.brush_layer = Some(DynamicImage::ImageRgba8(layer).into());
+    }
+
+    fn brush_pixel(document: &Document, x: u32, y: u32) -> Option<[u8; 4]> {
+        document
+            .brush_layer
+            .as_ref()
+            .map(|layer| layer.to_rgba8().get_pixel(x, y).0)
+    }
+
+    #[test]
+    fn changed_bounds_cover_only_the_differing_pixels() {
+        let before = RgbaImage::new(10, 8);
+        assert_eq!(changed_bounds(&before, &before), None);
+
+        let mut after = before.clone();
+        after.put_pixel(2, 3, Rgba([1, 0, 0, 0]));
+        after.put_pixel(6, 5, Rgba([0, 1, 0, 0]));
+        assert_eq!(changed_bounds(&before, &after), Some((2, 3, 5, 3)));
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip_text_blocks_and_layers() {
+        let mut document = document();
+        document.text_blocks = vec![block("before")];
+        let mut history = History::default();
+
+        let snapshot = Snapshot::new().text_blocks(&document).layer(
+            &document,
+            Layer::BrushLayer,
+            Some((4, 4, 4, 4)),
+        );
+        document.text_blocks = vec![block("after")];
+        paint(&mut document, 5, 5, 2, 2, 90);
+        let edit = snapshot.finish(&document);
+
+        // only the painted square is kept
+        let diff = &edit.as_ref().unwrap().layers[0];
+        assert_eq!((diff.x, diff.y), (5, 5));
+        assert_eq!(diff.after.dimensions(), (2, 2));
+        assert!(!diff.existed_before && diff.exists_after);
+        history.record(edit);
+
+        assert!(history.undo(&mut document).is_some());
+        assert_eq!(document.text_blocks[0].text.as_deref(), Some("before"));
+        assert!(document.brush_layer.is_none());
+        assert!(history.undo(&mut document).is_none());
+
+        assert!(history.redo(&mut document).is_some());
+        assert_eq!(document.text_blocks[0].text.as_deref(), Some("after"));
+        assert_eq!(brush_pixel(&document, 5, 5), Some([90, 90, 90, 255]));
+        assert_eq!(brush_pixel(&document, 4, 4), Some([0, 0, 0, 0]));
+        assert!(history.redo(&mut document).is_none());
+    }
+
+    #[test]
+    fn unchanged_snapshots_record_nothing() {
+        let document = document();
+        let snapshot = Snapshot::new().layer(&document, Layer::Inpainted, None);
+        assert!(snapshot.finish(&document).is_none());
+    }
+
+    #[test]
+    fn identical_pages_keep_their_own_history() -> anyhow::Result<()> {
+        let mut state = State {
+            documents: vec![document(), document()],
+            ..Default::default()
+        };
+        let (page, history) = state.document_with_history(1)?;
+        let snapshot = Snapshot::new().layer(page, Layer::BrushLayer, None);
+        paint(page, 0, 0, 1, 1, 90);
+        history.record(snapshot.finish(page));
+
+        let (page, history) = state.document_with_history(0)?;
+        assert!(history.undo(page).is_none());
+        assert!(page.brush_layer.is_none());
+
+        let (page, history) = state.document_with_history(1)?;
+        assert!(history.undo(page).is_some());
+        assert!(page.brush_layer.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn new_edits_clear_redo_and_old_ones_are_trimmed() {
+        let mut document = document();
+        let mut history = History::default();
+        for shade in 0..HISTORY_LIMIT as u8 + 5 {
+            let snapshot = Snapshot::new().layer(&document, Layer::BrushLayer, None);
+            paint(&mut document, 0, 0, 1, 1, shade + 1);
+            history.record(snapshot.finish(&document));
+        }
+        assert_eq!(history.undo.len(), HISTORY_LIMIT);
+
+        while history.undo(&mut document).is_some() {}
+        // the first five edits were dropped, so undoing stops after the fifth
+        assert_eq!(brush_pixel(&document, 0, 0), Some([5, 5, 5, 255]));
+
+        assert!(history.redo(&mut document).is_some());
+        let snapshot = Snapshot::new().layer(&document, Layer::BrushLayer, None);
+        paint(&mut document, 1, 1, 1, 1, 200);
+        history.record(snapshot.finish(&document));
+        assert!(history.redo(&mut document).is_none());
+    }
+
+    #[test]
+    fn edits_are_trimmed_to_the_byte_budget_without_rendered_bitmaps() {
+        let mut document = document();
+        let mut history = History {
+            budget: 3 * 16 * 12 * 4 * 2,
+            ..Default::default()
+        };
+        for shade in 1..=5 {
+            let snapshot = Snapshot::new().layer(&document, Layer::BrushLayer, None);
+            paint(&mut document, 0, 0, 16, 12, shade);
+            history.record(snapshot.finish(&document));
+        }
+        // each full-page edit keeps a before and an after, so three fit
+        assert_eq!(history.undo.len(), 3);
+        assert_eq!(history.bytes, history.budget);
+
+        let mut rendered = block("after");
+        rendered.rendered = Some(DynamicImage::new_rgba8(4, 4).into());
+        document.text_blocks = vec![block("before"), rendered];
+        let snapshot = Snapshot::new().text_blocks(&document);
+        document.text_blocks[0].translation = Some("changed".to_string());
+        let edit = snapshot.finish(&document).unwrap();
+        let (before, after) = edit.text_blocks.as_ref().unwrap();
+        assert!(before.blocks.iter().all(|block| block.rendered.is_none()));
+        assert_eq!(after.rendered, [false, true]);
+        history.record(Some(edit));
+        // the new edit is small, so only one full-page edit had to go
+        assert_eq!(history.undo.len(), 3);
+        assert!(history.bytes <= history.budget);
+
+        assert_eq!(history.undo(&mut document), Some(vec![1]));
+        assert_eq!(document.text_blocks[0].translation, None);
+        assert!(document.text_blocks[1].rendered.is_none());
+    }
+}
@@ -161,6 +161,7 @@ export function LlmControls() {
             </div>
             <textarea
               value={llmOpenAIPrompt}
+              placeholder={t('llm.openaiPromptPlaceholder')}
               rows={3}
               onChange={(event) => setLlmOpenAIPrompt(event.target.value)}
               className='w-full rounded border border-neutral-200 bg-white px-2 py-2 text-sm text-neutral-800 outline-none focus:border-rose-400'
@@ -4,6 +4,7 @@ import { create } from 'zustand'
 import { invoke } from '@tauri-apps/api/core'
 import { getCurrentWindow, ProgressBarStatus } from '@tauri-apps/api/window'
 import {
+  DetectOptions,
   Document,
   InpaintRegion,
   RenderEffect,
@@ -12,14 +13,12 @@ import {
 } from '@/types'
 import { createOperationSlice, type OperationSlice } from '@/lib/operations'
 import {
-  callOpenAICompletion,
   isOpenAIConfigured,
   isOpenAIModel,
-  OPENAI_COMPATIBLE_MODEL,
+  OPENAI_COMPATIBLE_MODEL_ID,
   OPENAI_DEFAULT_MODEL,
   type LlmModelInfo,
 } from '@/lib/openai'
-import { t } from 'i18next'
 
 type ProcessAction = 'detect' | 'ocr' | 'inpaint' | 'llmGenerate' | 'render'
 
@@ -204,7 +203,7 @@ type AppState = OperationSlice & {
   setProgress: (progress?: number, status?: ProgressBarStatus) => Promise<void>
   clearProgress: () => Promise<void>
   // Processing actions
-  detect: (_?: any, index?: number) => Promise<void>
+  detect: (_?: any, index?: number, options?: DetectOptions) => Promise<void>
   ocr: (_?: any, index?: number) => Promise<void>
   inpaint: (_?: any, index?: number) => Promise<void>
   inpaintPartial: (
@@ -254,49 +253,24 @@ export const useAppStore = create<AppState>((set, get) => {
     return true
   }
 
-  const generateWithOpenAI = async (
-    index: number,
-    textBlockIndex?: number,
-  ): Promise<Document | void> => {
+  // the backend keeps no endpoint settings, so they are sent before every translation
+  const connectOpenAI = async () => {
     const {
-      documents,
       llmOpenAIEndpoint,
       llmOpenAIApiKey,
       llmOpenAIPrompt,
       llmOpenAIModel,
     } = get()
-    const doc = documents[index]
-    if (!doc) return
-    await textBlockSyncer.flush()
-
-    const blocks = doc.textBlocks ?? []
-    const hasSingle = typeof textBlockIndex === 'number' && textBlockIndex >= 0
-    const sourceText = hasSingle
-      ? (blocks[textBlockIndex!]?.text ?? '')
-      : blocks.map((block) => block.text ?? '').join('\n')
-    if (!sourceText.trim()) return
-
-    const completion = await callOpenAICompletion({
-      endpoint: llmOpenAIEndpoint,
-      apiKey: llmOpenAIApiKey,
-      prompt: llmOpenAIPrompt.trim(),
-      content: sourceText,
-      model: llmOpenAIModel,
-    })
-
-    const translatedLines = hasSingle ? null : completion.split(/\r?\n/)
-    const updatedBlocks = blocks.map((block, i) => {
-      if (hasSingle) {
-        return i === textBlockIndex
-          ? { ...block, translation: completion }
-          : block
-      }
-      const translated = translatedLines?.[i]
-      if (translated === undefined) return block
-      return { ...block, translation: translated }
+    await invoke('llm_load', {
+      id: OPENAI_COMPATIBLE_MODEL_ID,
+      openai: {
+        endpoint: llmOpenAIEndpoint,
+        apiKey: llmOpenAIApiKey,
+        // the backend falls back to its generic prompt unless the user wrote one
+        prompt: llmOpenAIPrompt.trim() || undefined,
+        model: llmOpenAIModel,
+      },
     })
-
-    return { ...doc, textBlocks: updatedBlocks }
   }
 
   return {
@@ -321,7 +295,7 @@ export const useAppStore = create<AppState>((set, get) => {
     llmLoading: false,
     llmOpenAIEndpoint: '',
     llmOpenAIApiKey: '',
-    llmOpenAIPrompt: t('llm.openaiPromptPlaceholder'),
+    llmOpenAIPrompt: '',
     llmOpenAIModel: OPENAI_DEFAULT_MODEL,
     operation: undefined,
     hydrateDocuments: (docs: Document[]) => {
@@ -473,10 +447,11 @@ export const useAppStore = create<AppState>((set, get) => {
     flushMaskSync: async () => {
       await maskSyncer.flush()
     },
-    detect: async (_, index) => {
+    detect: async (_, index, options) => {
       index = index ?? get().currentDocumentIndex
       const doc: Document = await invoke<Document>('detect', {
         index,
+        options,
       })
       set((state) => ({
         documents: replaceDocument(state.documents, index, doc),
@@ -544,10 +519,7 @@ export const useAppStore = create<AppState>((set, get) => {
     },
     llmList: async () => {
       try {
-        const models = [
-          ...(await invoke<LlmModelInfo[]>('llm_list')),
-          OPENAI_COMPATIBLE_MODEL,
-        ]
+        const models = await invoke<LlmModelInfo[]>('llm_list')
         set({ llmModels: models })
         const currentModel = get().llmSelectedModel
         const currentLanguage = get().llmSelectedLanguage
@@ -658,23 +630,21 @@ export const useAppStore = create<AppState>((set, get) => {
             : languages[0]
           : undefined
 
-      let doc: Document | void = undefined
       if (isOpenAICompatible()) {
-        if (!syncOpenAIReady()) {
+        if (!get().llmReady) {
           throw new Error(
             'Provide an OpenAI compatible endpoint and API key to generate translations.',
           )
         }
-        doc = await generateWithOpenAI(index, textBlockIndex)
+        await connectOpenAI()
       }
 
-      if (doc === undefined) {
-        doc = await invoke<Document>('llm_generate', {
-          index,
-          textBlockIndex,
-          language,
-        })
-      }
+      await textBlockSyncer.flush()
+      const doc = await invoke<Document>('llm_generate', {
+        index,
+        textBlockIndex,
+        request: { language },
+      })
       set((state) => ({
         documents: replaceDocument(state.documents, index, doc),
         showTextBlocksOverlay: true,
@@ -26,6 +26,13 @@ export type TextStyle = {
   effect?: RenderEffect
 }
 
+export type TranslationStatus =
+  | 'pending'
+  | 'translated'
+  | 'retried'
+  | 'memory'
+  | 'failed'
+
 export type TextBlock = {
   x: number
   y: number
@@ -37,6 +44,8 @@ export type TextBlock = {
   style?: TextStyle
   fontPrediction?: FontPrediction
   rendered?: number[]
+  status?: TranslationStatus
+  classIndex?: number
 }
 
 export type ToolMode = 'select' | 'block' | 'brush' | 'repairBrush' | 'eraser'
@@ -48,6 +57,10 @@ export type InpaintRegion = {
   height: number
 }
 
+export type Panel = {
+  polygon: [number, number][]
+}
+
 export type Document = {
   id: string
   path: string
@@ -56,8 +69,59 @@ export type Document = {
   width: number
   height: number
   textBlocks: TextBlock[]
+  panels?: Panel[]
   segment?: number[]
   inpainted?: number[]
   brushLayer?: number[]
   rendered?: number[]
 }
+
+export type LlmToken = {
+  index: number
+  textBlockIndex?: number
+  output: string
+}
+
+/** Rejection of a translation command whose target language the model can't translate into. */
+export type UnsupportedLanguageError = {
+  kind: 'unsupportedLanguage'
+  message: string
+  model: string
+  language: string
+  supported: string[]
+}
+
+export type PromptProfile = {
+  name: string
+  system?: string
+  user: string
+}
+
+export type ReadingOrder = 'manga' | 'comic' | 'webtoon'
+
+export type PanelLayout = 'separate' | 'strip'
+
+export type DetectOptions = {
+  confidenceThreshold?: number
+  nmsThreshold?: number
+  binaryThreshold?: number
+  dilationRadius?: number
+  holeCloseRadius?: number
+  bboxDilation?: number
+  inputSize?: number
+  tileAspectRatio?: number | null
+  tileOverlap?: number
+  groupBubbles?: number | null
+}
+
+export type GenerateOptions = {
+  maxTokens?: number
+  temperature?: number
+  topK?: number
+  topP?: number
+  seed?: number
+  splitPrompt?: boolean
+  repeatPenalty?: number
+  repeatLastN?: number
+  greedy?: boolean
+}